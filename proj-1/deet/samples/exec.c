@@ -0,0 +1,11 @@
+#include <stdio.h>
+#include <unistd.h>
+
+int main(int argc, char *argv[]) {
+    const char *program = argc > 1 ? argv[1] : "samples/hello";
+    printf("About to exec %s\n", program);
+    fflush(stdout);
+    execl(program, program, NULL);
+    perror("execl");
+    return 1;
+}
@@ -0,0 +1,9 @@
+#include <stdio.h>
+
+int add(int a, int b);
+
+int main() {
+    int total = add(2, 3);
+    printf("total = %d\n", total);
+    return 0;
+}
@@ -0,0 +1,14 @@
+#include <stdio.h>
+
+int factorial(int n) {
+    if (n <= 1) {
+        return 1;
+    }
+    int rest = factorial(n - 1);
+    return n * rest;
+}
+
+int main() {
+    printf("5! = %d\n", factorial(5));
+    return 0;
+}
@@ -0,0 +1,13 @@
+#include <signal.h>
+#include <stdio.h>
+
+void handler(int signum) {
+    printf("Caught signal %d\n", signum);
+}
+
+int main() {
+    signal(SIGUSR1, handler);
+    printf("Handler installed\n");
+    raise(SIGUSR1);
+    return 0;
+}
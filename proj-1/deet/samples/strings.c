@@ -0,0 +1,12 @@
+#include <stdio.h>
+
+const char *greeting = "Hello, deet!";
+char *mutable_greeting = "Hello again!";
+
+int main() {
+    const char *name = "world";
+    const int answer = 42;
+    const char **where = &name;
+    printf("%s %s %s %d %p\n", greeting, mutable_greeting, name, answer, (void *)where);
+    return 0;
+}
@@ -0,0 +1,21 @@
+#include <pthread.h>
+#include <stdio.h>
+
+void *worker(void *arg) {
+    int id = *(int *)arg;
+    printf("worker %d running\n", id);
+    return NULL;
+}
+
+int main() {
+    pthread_t threads[2];
+    int ids[2] = {1, 2};
+    for (int i = 0; i < 2; i++) {
+        pthread_create(&threads[i], NULL, worker, &ids[i]);
+    }
+    for (int i = 0; i < 2; i++) {
+        pthread_join(threads[i], NULL);
+    }
+    printf("all workers done\n");
+    return 0;
+}
@@ -1,37 +1,140 @@
-use crate::debugger_command::DebuggerCommand;
-use crate::dwarf_data::{DwarfData, Error as DwarfError};
-use crate::inferior::{Inferior, Status};
+use crate::debugger_command::{self, Condition, DebuggerCommand};
+use crate::dwarf_data::{DwarfData, Error as DwarfError, Location, Type, Variable};
+use crate::inferior::{Frame, Inferior, Status};
 use nix::sys::ptrace;
-use nix::sys::wait::waitpid;
+use nix::sys::signal::Signal;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+
+/// Number of source lines shown on each side of the current line by the list command.
+const LIST_CONTEXT_LINES: usize = 5;
+/// Number of words printed on each line by the examine command.
+const EXAMINE_WORDS_PER_LINE: usize = 4;
+/// The longest an x86-64 instruction can be.
+const MAX_INSTRUCTION_LEN: usize = 15;
 
 #[derive(Clone)]
 struct Breakpoint {
     id: i64,
     addr: usize,
     orig_byte: u8,
+    condition: Option<Condition>,
+}
+
+/// Interprets the raw (little-endian) bytes of an integer variable, sign-extending unless the
+/// DWARF type is unsigned. Returns None if the type isn't a plain integer size.
+fn value_as_i64(bytes: &[u8], entity_type: &Type) -> Option<i64> {
+    let size = entity_type.size;
+    if size == 0 || size > 8 || bytes.len() < size {
+        return None;
+    }
+    let mut raw = [0_u8; 8];
+    raw[..size].copy_from_slice(&bytes[..size]);
+    let value = u64::from_le_bytes(raw);
+    if entity_type.name.contains("unsigned") || entity_type.name.ends_with('*') {
+        Some(value as i64)
+    } else {
+        let shift = 64 - 8 * size;
+        Some(((value << shift) as i64) >> shift)
+    }
+}
+
+/// Decodes the raw (little-endian) bytes of a variable according to its DWARF type.
+fn format_value(bytes: &[u8], entity_type: &Type) -> String {
+    let size = entity_type.size;
+    if size == 0 || size > 8 || bytes.len() < size {
+        return format!("{:x?}", bytes);
+    }
+    let mut raw = [0_u8; 8];
+    raw[..size].copy_from_slice(&bytes[..size]);
+    let value = u64::from_le_bytes(raw);
+    let name = entity_type.name.as_str();
+
+    if name.ends_with('*') {
+        format!("{:#x}", value)
+    } else if name == "float" && size == 4 {
+        f32::from_bits(value as u32).to_string()
+    } else if name == "double" && size == 8 {
+        f64::from_bits(value).to_string()
+    } else if name == "_Bool" || name == "bool" {
+        (value != 0).to_string()
+    } else if name.contains("char") && size == 1 {
+        format!(
+            "{} '{}'",
+            value as u8 as i8,
+            std::ascii::escape_default(value as u8)
+        )
+    } else if name.contains("unsigned") {
+        value.to_string()
+    } else {
+        value_as_i64(bytes, entity_type).unwrap().to_string()
+    }
+}
+
+/// Formats a signal as its name followed by its number, e.g. "SIGSEGV (11)".
+fn describe_signal(signal: Signal) -> String {
+    format!("{} ({})", signal, signal as i32)
+}
+
+/// x86-64 has four debug address registers (DR0-DR3)
+const MAX_WATCHPOINTS: usize = 4;
+
+struct Watchpoint {
+    expr: String,
+    addr: usize,
+    len: usize,
+    entity_type: Type,
+    value: Vec<u8>,
 }
 
 pub struct Debugger {
     target: String,
     history_path: String,
+    /// File that breakpoints are saved to on quit and restored from on startup
+    breakpoints_path: String,
     readline: Editor<()>,
     debug_data: Option<DwarfData>,
     inferior: Option<Inferior>,
-    breakpoints_list: Vec<(i64, usize)>,
+    /// (id, address, condition, location as the user gave it)
+    breakpoints_list: Vec<(i64, usize, Option<Condition>, String)>,
     breakpoints_map: HashMap<usize, Breakpoint>,
     breakpoint_count: i64,
     current_result: Result<Status, nix::Error>,
+    source_cache: HashMap<String, Vec<String>>,
+    watchpoints: Vec<Watchpoint>,
+    /// Whether the inferior last stopped because a watched value changed
+    watchpoint_triggered: bool,
+    /// Index of the stack frame that print/list operate on (0 is the innermost frame)
+    current_frame: usize,
+    /// Commands given with --ex that haven't been run yet
+    startup_commands: VecDeque<String>,
 }
 
 impl Debugger {
-    /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    /// Initializes the debugger. Command history is kept in `history_file` if given, otherwise in
+    /// ~/.deet_history (or the temp directory if HOME isn't set). Breakpoints are likewise kept in
+    /// `breakpoints_file` or ~/.deet_breakpoints, and any saved for this target are restored.
+    /// `startup_commands` are run in order before the first prompt.
+    pub fn new(
+        target: &str,
+        history_file: Option<String>,
+        breakpoints_file: Option<String>,
+        startup_commands: Vec<String>,
+    ) -> Debugger {
         // TODO (milestone 3): initialize the DwarfData
         let debug_data = match DwarfData::from_file(target) {
-            Ok(val) => val,
+            Ok(val) if val.has_line_info() => Some(val),
+            Ok(_) => {
+                println!(
+                    "Warning: {} has no debugging symbols, so only breakpoints on addresses \
+                     (e.g. break 0x401136) will work",
+                    target
+                );
+                None
+            }
             Err(DwarfError::ErrorOpeningFile) => {
                 println!("Could not open file {}", target);
                 std::process::exit(1);
@@ -42,53 +145,259 @@ impl Debugger {
             }
         };
 
-        debug_data.print();
-
-        let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
+        let history_path =
+            history_file.unwrap_or_else(|| Self::default_dotfile_path(".deet_history"));
+        let breakpoints_path =
+            breakpoints_file.unwrap_or_else(|| Self::default_dotfile_path(".deet_breakpoints"));
         let mut readline = Editor::<()>::new();
-        // Attempt to load history from ~/.deet_history if it exists
+        // Attempt to load history from the history file if it exists
         let _ = readline.load_history(&history_path);
 
-        Debugger {
+        let mut debugger = Debugger {
             target: target.to_string(),
             history_path,
+            breakpoints_path,
             readline,
-            debug_data: Some(debug_data),
+            debug_data,
             inferior: None,
             breakpoints_list: Vec::new(),
             breakpoints_map: HashMap::new(),
             breakpoint_count: 0,
             current_result: Ok(Status::Exited(0)),
+            source_cache: HashMap::new(),
+            watchpoints: Vec::new(),
+            watchpoint_triggered: false,
+            current_frame: 0,
+            startup_commands: startup_commands.into_iter().collect(),
+        };
+        debugger.load_breakpoints();
+        debugger
+    }
+
+    /// Path of a file in the home directory (or the temp directory if HOME isn't set).
+    fn default_dotfile_path(name: &str) -> String {
+        let dir = match std::env::var("HOME") {
+            Ok(home) => PathBuf::from(home),
+            Err(_) => std::env::temp_dir(),
+        };
+        dir.join(name).to_string_lossy().to_string()
+    }
+
+    /// Key identifying the target in the breakpoints file, so that each program gets back only
+    /// its own breakpoints.
+    fn target_key(&self) -> String {
+        fs::canonicalize(&self.target)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| self.target.clone())
+    }
+
+    /// Restores the breakpoints saved for this target. Each line of the breakpoints file is the
+    /// target, a tab, and the arguments of the break command that set the breakpoint, so that
+    /// locations are resolved again against the current binary.
+    fn load_breakpoints(&mut self) {
+        let contents = match fs::read_to_string(&self.breakpoints_path) {
+            Ok(contents) => contents,
+            Err(_) => return,
+        };
+        let target_key = self.target_key();
+        for line in contents.lines() {
+            let mut fields = line.splitn(2, '\t');
+            let args = match (fields.next(), fields.next()) {
+                (Some(target), Some(args)) if target == target_key && !args.trim().is_empty() => {
+                    args
+                }
+                _ => continue,
+            };
+            let mut tokens = vec!["break"];
+            tokens.extend(args.split_whitespace());
+            match DebuggerCommand::from_tokens(&tokens) {
+                Some(DebuggerCommand::BreakPoint(location, condition)) => {
+                    if !self.add_breakpoint(&location, condition) {
+                        println!("Dropping saved breakpoint at \"{}\"", location);
+                    }
+                }
+                _ => println!("Ignoring malformed saved breakpoint \"{}\"", args),
+            }
         }
     }
 
+    /// Writes this target's breakpoints to the breakpoints file, keeping those saved for other
+    /// targets.
+    fn save_breakpoints(&self) -> std::io::Result<()> {
+        let target_key = self.target_key();
+        let mut lines: Vec<String> = match fs::read_to_string(&self.breakpoints_path) {
+            Ok(contents) => contents
+                .lines()
+                .filter(|line| line.splitn(2, '\t').next() != Some(target_key.as_str()))
+                .map(|line| line.to_string())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        for (_, _, condition, location) in &self.breakpoints_list {
+            match condition {
+                Some(condition) => {
+                    lines.push(format!("{}\t{} if {}", target_key, location, condition))
+                }
+                None => lines.push(format!("{}\t{}", target_key, location)),
+            }
+        }
+        if lines.is_empty() {
+            let _ = fs::remove_file(&self.breakpoints_path);
+            return Ok(());
+        }
+        fs::write(&self.breakpoints_path, lines.join("\n") + "\n")
+    }
+
+    /// Adds a breakpoint at `location`, installing it right away if the inferior is running.
+    /// Returns false if the location doesn't exist in the target.
+    fn add_breakpoint(&mut self, location: &str, condition: Option<Condition>) -> bool {
+        let addr = match self.resolve_breakpoint_addr(location) {
+            Some(addr) => addr,
+            None if self.debug_data.is_none() => {
+                println!(
+                    "No debugging symbols, so \"{}\" can't be resolved; use an address instead",
+                    location
+                );
+                return false;
+            }
+            None => {
+                println!("Could not resolve breakpoint location \"{}\"", location);
+                return false;
+            }
+        };
+        println!("Set breakpoint {} at {:#x}", self.breakpoint_count, addr);
+
+        if let Some(condition) = condition.as_ref() {
+            println!(
+                "Breakpoint {} condition: {}",
+                self.breakpoint_count, condition
+            );
+        }
+        self.breakpoints_list.push((
+            self.breakpoint_count,
+            addr,
+            condition.clone(),
+            location.to_string(),
+        ));
+        // Once the inferior has exited there's nothing to write to; the breakpoint is installed
+        // by the next run
+        if self.inferior.is_some() && !self.inferior_exited() {
+            match self.set_breakpoint(self.breakpoint_count, addr, condition) {
+                Some(breakpoint) => {
+                    self.breakpoints_map.insert(addr, breakpoint);
+                }
+                None => println!("Warning: could not write breakpoint at {:#x}", addr),
+            }
+        }
+        self.breakpoint_count += 1;
+        true
+    }
+
     fn parse_address(addr: &str) -> Option<usize> {
-        let addr_without_0x = if addr.to_lowercase().starts_with("0x") {
-            &addr[2..]
+        if addr.to_lowercase().starts_with("0x") {
+            usize::from_str_radix(&addr[2..], 16).ok()
         } else {
-            &addr
-        };
-        usize::from_str_radix(addr_without_0x, 16).ok()
+            addr.parse::<usize>().ok()
+        }
+    }
+
+    /// Resolves a breakpoint location given as a raw address (`0x...`), a line number, or a
+    /// function name, optionally qualified with a source file (`file.c:42`, `file.c:func`).
+    /// Breakpoints on a function go just past its prologue, where its frame is set up and its
+    /// parameters can be read. Returns None if the location doesn't exist in the target.
+    fn resolve_breakpoint_addr(&self, location: &str) -> Option<usize> {
+        if location.to_lowercase().starts_with("0x") {
+            Self::parse_address(location)
+        } else if let Some(colon) = location.rfind(':') {
+            let (file, target) = (&location[..colon], &location[colon + 1..]);
+            let debug_data = self.debug_data.as_ref()?;
+            if !target.is_empty() && target.chars().all(|char| char.is_ascii_digit()) {
+                debug_data.get_addr_for_line(Some(file), target.parse::<usize>().ok()?)
+            } else {
+                debug_data
+                    .get_addr_for_function(Some(file), target)
+                    .map(|addr| debug_data.get_prologue_end(addr))
+            }
+        } else if location.chars().all(|char| char.is_ascii_digit()) {
+            let line_number = location.parse::<usize>().ok()?;
+            self.debug_data
+                .as_ref()?
+                .get_addr_for_line(None, line_number)
+        } else {
+            let debug_data = self.debug_data.as_ref()?;
+            debug_data
+                .get_addr_for_function(None, location)
+                .map(|addr| debug_data.get_prologue_end(addr))
+        }
+    }
+
+    /// Returns whether the last thing we saw from the inferior was it exiting or being killed.
+    fn inferior_exited(&self) -> bool {
+        match self.current_result {
+            Ok(Status::Exited(_)) | Ok(Status::Signaled(_)) => true,
+            _ => false,
+        }
     }
 
-    fn deal_status(&self, result: &Result<Status, nix::Error>) {
+    /// Reports why the inferior stopped. `expected_trap` says that a SIGTRAP is one we caused
+    /// ourselves (a step finishing, a watched value changing), so only the location is printed.
+    fn deal_status(&self, result: &Result<Status, nix::Error>, expected_trap: bool) {
         match result {
             Ok(status) => match status {
-                crate::inferior::Status::Stopped(_, signal, mut rip) => {
-                    println!("Child stopped (signal {})", signal);
-                    if let Some(data) = self.debug_data.as_ref() {
-                        let func_name = data.get_function_from_addr(rip).expect("invalid addr");
-                        let func_line = data.get_line_from_addr(rip).expect("invalid addr");
-                        println!("Stopped at {} ({})", func_name, func_line);
+                crate::inferior::Status::Stopped(tid, signal, mut rip) => {
+                    // rip is just past the int3 when we stop on a breakpoint, so back it up to
+                    // report where the breakpoint is
+                    let breakpoint = if *signal == Signal::SIGTRAP {
+                        self.breakpoints_map.get(&(rip - 1))
+                    } else {
+                        None
+                    };
+                    if breakpoint.is_some() {
+                        rip -= 1;
+                    }
+                    // Once there's more than one thread, say which one stopped
+                    let multithreaded = self
+                        .inferior
+                        .as_ref()
+                        .map_or(false, |inferior| inferior.is_multithreaded());
+                    let (who, hit) = if multithreaded {
+                        (format!("Thread {}", tid), format!("Thread {} hit", tid))
                     } else {
-                        eprintln!("invalid debug data!");
+                        ("Child".to_string(), "Hit".to_string())
+                    };
+                    if breakpoint.is_none() && !(expected_trap && *signal == Signal::SIGTRAP) {
+                        println!("{} stopped by {}", who, describe_signal(*signal));
+                    }
+                    if *signal == Signal::SIGSEGV {
+                        self.print_fault(rip);
+                    }
+                    // Without line info (no debugging symbols, or stopped in a library) all we
+                    // can say is the address
+                    let location = self.debug_data.as_ref().and_then(|data| {
+                        let func_name = data.get_function_from_addr(rip)?;
+                        let func_line = data.get_line_from_addr(rip)?;
+                        Some(format!("{} ({})", func_name, func_line))
+                    });
+                    let location = location.unwrap_or_else(|| format!("{:#x}", rip));
+                    match breakpoint {
+                        Some(breakpoint) => {
+                            println!("{} breakpoint {} at {}", hit, breakpoint.id, location);
+                            self.print_arguments(rip);
+                        }
+                        None => println!("Stopped at {}", location),
                     }
                 }
-                crate::inferior::Status::Exited(_) => {
-                    println!("Child exited (status 0)");
+                crate::inferior::Status::Exited(exit_code) => {
+                    println!("Child exited (status {})", exit_code);
                 }
                 crate::inferior::Status::Signaled(signal) => {
-                    println!("Child Signaled (signal {})", signal);
+                    println!("Child exited due to {}", describe_signal(*signal));
+                }
+                crate::inferior::Status::Exec(pid, rip) => {
+                    println!(
+                        "Process {} exec'd a new program, stopped at its entry ({:#x})",
+                        pid, rip
+                    );
                 }
             },
             Err(err) => {
@@ -97,27 +406,896 @@ impl Debugger {
         }
     }
 
-    fn set_breakpoint(&mut self, point_id: i64, addr: usize) -> Option<Breakpoint> {
-        let orig_byte = self
-            .inferior
-            .as_mut()
-            .unwrap()
-            .write_byte(addr, 0xcc)
-            .ok()?;
+    /// After a SIGSEGV, prints the address the inferior tried to access and the function the
+    /// faulting instruction is in. Even without line info for rip (say, the crash is in a library
+    /// called with a bad pointer), the nearest function below it usually points at the culprit.
+    fn print_fault(&self, rip: usize) {
+        match self.inferior.as_ref().unwrap().get_fault_address() {
+            Ok(addr) => println!("Invalid memory access at address {:#x}", addr),
+            Err(err) => eprintln!("{}", err),
+        }
+        let func = self
+            .debug_data
+            .as_ref()
+            .and_then(|data| data.get_nearest_function(rip));
+        if let Some(func) = func {
+            println!(
+                "Faulting instruction {:#x} is in {}+{:#x}",
+                rip,
+                func.name,
+                rip - func.address
+            );
+        }
+    }
+
+    /// When stopped at the start of a function, prints its arguments. Past the prologue (where
+    /// breakpoints on a function go) they have been stored in the frame like any other variable.
+    fn print_arguments(&self, addr: usize) {
+        let data = match self.debug_data.as_ref() {
+            Some(data) => data,
+            None => return,
+        };
+        let func_addr = match data.get_function_containing(addr) {
+            Some(func) => func.address,
+            None => return,
+        };
+        if addr == func_addr {
+            self.print_register_arguments(data.get_parameters(func_addr));
+        } else if addr == data.get_prologue_end(func_addr) {
+            for param in data.get_parameters(func_addr) {
+                match self.read_variable(&param.name) {
+                    Ok((var, bytes)) => {
+                        println!(
+                            "{} = {}",
+                            param.name,
+                            format_value(&bytes, &var.entity_type)
+                        )
+                    }
+                    Err(msg) => println!("{}", msg),
+                }
+            }
+        }
+    }
+
+    /// At the very first instruction of a function, prints its integer and pointer arguments,
+    /// which the System V AMD64 calling convention passes in registers. Floating point arguments
+    /// are passed in the SSE registers instead, so they are skipped without using up a register.
+    fn print_register_arguments(&self, params: Vec<&Variable>) {
+        if params.is_empty() {
+            return;
+        }
+        let regs = match self.inferior.as_ref().unwrap().get_registers() {
+            Ok(regs) => regs,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+        let arg_registers = [regs.rdi, regs.rsi, regs.rdx, regs.rcx, regs.r8, regs.r9];
+        let integer_params = params.iter().filter(|param| {
+            let type_name = param.entity_type.name.as_str();
+            param.entity_type.size <= 8 && type_name != "float" && type_name != "double"
+        });
+        // Anything past the sixth integer argument is on the stack
+        for (param, value) in integer_params.zip(arg_registers.iter()) {
+            let bytes = value.to_le_bytes();
+            println!(
+                "{} = {}",
+                param.name,
+                format_value(&bytes, &param.entity_type)
+            );
+        }
+    }
+
+    fn set_breakpoint(
+        &mut self,
+        point_id: i64,
+        addr: usize,
+        condition: Option<Condition>,
+    ) -> Option<Breakpoint> {
+        // If another breakpoint already trapped this address, the byte there now is its 0xcc, so
+        // the original byte has to come from that breakpoint
+        let orig_byte = match self.breakpoints_map.get(&addr) {
+            Some(existing) => existing.orig_byte,
+            None => self
+                .inferior
+                .as_mut()
+                .unwrap()
+                .write_byte(addr, 0xcc)
+                .ok()?,
+        };
         Some(Breakpoint {
             id: point_id,
             addr: addr,
             orig_byte: orig_byte,
+            condition: condition,
         })
     }
 
+    /// Returns the source file and line number for an address, if the address has line info.
+    fn line_at(&self, addr: usize) -> Option<(String, usize)> {
+        let line = self.debug_data.as_ref()?.get_line_from_addr(addr)?;
+        Some((line.file, line.number))
+    }
+
+    /// Executes a single instruction in the inferior. If a breakpoint is installed on the
+    /// instruction about to run, the original byte is restored for the step and the breakpoint is
+    /// put back afterwards.
+    fn step_instruction(&mut self) -> Result<Status, nix::Error> {
+        let inferior = self.inferior.as_mut().unwrap();
+        let rip = inferior.get_rip()?;
+        let status = match self.breakpoints_map.get(&rip) {
+            Some(breakpoint) => {
+                inferior.write_byte(rip, breakpoint.orig_byte)?;
+                let status = inferior.step();
+                // After an exec, the memory the breakpoint was in belongs to the old program
+                if !matches!(status, Ok(Status::Exec(..))) {
+                    let _ = inferior.write_byte(rip, 0xcc);
+                }
+                status
+            }
+            None => inferior.step(),
+        }?;
+        if let Status::Exec(..) = status {
+            self.handle_exec();
+        }
+        Ok(status)
+    }
+
+    /// Writes every breakpoint in breakpoints_list into the inferior.
+    fn install_breakpoints(&mut self) {
+        for idx in 0..self.breakpoints_list.len() {
+            let (point_id, addr, condition, _) = self.breakpoints_list[idx].clone();
+            match self.set_breakpoint(point_id, addr, condition) {
+                Some(breakpoint) => {
+                    self.breakpoints_map.insert(addr, breakpoint);
+                }
+                None => println!(
+                    "Warning: could not set breakpoint {} at {:#x}",
+                    point_id, addr
+                ),
+            }
+        }
+    }
+
+    /// Takes a deleted breakpoint's trap out of the inferior. Another breakpoint may share the
+    /// address, in which case the trap stays and reports that breakpoint from now on.
+    fn uninstall_breakpoint(&mut self, addr: usize) {
+        let remaining = self
+            .breakpoints_list
+            .iter()
+            .find(|(_, a, _, _)| *a == addr)
+            .cloned();
+        if let Some((point_id, _, condition, _)) = remaining {
+            if let Some(breakpoint) = self.breakpoints_map.get_mut(&addr) {
+                breakpoint.id = point_id;
+                breakpoint.condition = condition;
+            }
+            return;
+        }
+        let breakpoint = match self.breakpoints_map.remove(&addr) {
+            Some(breakpoint) => breakpoint,
+            None => return,
+        };
+        let inferior = match self.inferior.as_mut() {
+            Some(inferior) => inferior,
+            None => return,
+        };
+        let _ = inferior.write_byte(addr, breakpoint.orig_byte);
+        // If we're stopped on this breakpoint, rip is past the trap we just took out, and with
+        // the breakpoint gone nothing would rewind it
+        if let Ok(Status::Stopped(tid, Signal::SIGTRAP, rip)) = self.current_result {
+            if rip == addr + 1 && inferior.set_rip(addr).is_ok() {
+                self.current_result = Ok(Status::Stopped(tid, Signal::SIGTRAP, addr));
+            }
+        }
+    }
+
+    /// Gets rid of the current inferior before starting over or quitting. A process we started is
+    /// killed, but one we attached to is left running as we found it: the breakpoints and
+    /// watchpoints are taken out of it and it is detached.
+    fn release_inferior(&mut self) {
+        let attached = match self.inferior.as_ref() {
+            Some(inferior) => inferior.is_attached(),
+            None => return,
+        };
+        if attached && !self.inferior_exited() {
+            let _ = self.rewind_to_breakpoint();
+            let inferior = self.inferior.as_mut().unwrap();
+            for (addr, breakpoint) in self.breakpoints_map.iter() {
+                let _ = inferior.write_byte(*addr, breakpoint.orig_byte);
+            }
+            let _ = inferior.set_hw_watchpoints(&[]);
+            match inferior.detach() {
+                Ok(()) => println!("Detached from process {}", inferior.pid()),
+                Err(err) => println!("Could not detach from process {}: {}", inferior.pid(), err),
+            }
+        } else if let Some(inferior) = self.inferior.as_mut() {
+            let _ = inferior.kill();
+        }
+        self.inferior = None;
+        self.breakpoints_map.clear();
+        self.watchpoints.clear();
+    }
+
+    /// Called when the inferior has exec'd a new program, which wipes out the breakpoints and
+    /// watchpoints we had set. If it exec'd our target again they are put back; otherwise the
+    /// user is warned that they no longer apply.
+    fn handle_exec(&mut self) {
+        self.breakpoints_map.clear();
+        self.watchpoints.clear();
+        let exe_path = self.inferior.as_ref().unwrap().exe_path();
+        let same_target = match (&exe_path, fs::canonicalize(&self.target)) {
+            (Ok(exe_path), Ok(target_path)) => *exe_path == target_path,
+            _ => false,
+        };
+        if same_target {
+            self.install_breakpoints();
+        } else if !self.breakpoints_list.is_empty() {
+            let new_program = exe_path
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_else(|_| "another program".to_string());
+            println!(
+                "Warning: the inferior exec'd {}; breakpoints are not set in it",
+                new_program
+            );
+        }
+    }
+
+    /// If the inferior last stopped because it trapped on one of our breakpoints, rip is one byte
+    /// past the breakpoint. Back it up so that the original instruction gets executed next.
+    fn rewind_to_breakpoint(&mut self) -> Result<(), nix::Error> {
+        if let Ok(Status::Stopped(_, Signal::SIGTRAP, rip)) = self.current_result {
+            if self.breakpoints_map.get(&(rip - 1)).is_some() {
+                self.inferior.as_ref().unwrap().set_rip(rip - 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Continues the inferior until it reaches `addr`, using a temporary breakpoint that is
+    /// removed again once the inferior stops. If the inferior stops somewhere else first (another
+    /// breakpoint, a signal, or exit), that status is returned instead.
+    fn run_to_address(&mut self, addr: usize) -> Result<Status, nix::Error> {
+        let inferior = self.inferior.as_mut().unwrap();
+        let temp_orig_byte = if self.breakpoints_map.contains_key(&addr) {
+            None
+        } else {
+            Some(inferior.write_byte(addr, 0xcc)?)
+        };
+        let status = inferior.continue_run(None)?;
+        if let Status::Exec(..) = status {
+            self.handle_exec();
+            return Ok(status);
+        }
+        if let Some(orig_byte) = temp_orig_byte {
+            let _ = inferior.write_byte(addr, orig_byte);
+            if let Status::Stopped(pid, Signal::SIGTRAP, rip) = status {
+                if rip == addr + 1 {
+                    inferior.set_rip(addr)?;
+                    return Ok(Status::Stopped(pid, Signal::SIGTRAP, addr));
+                }
+            }
+        }
+        Ok(status)
+    }
+
+    /// Runs until a function that was just called returns to `return_addr`. `call_rsp` is where
+    /// the return address sits on the stack; if the function is recursive, the calls it makes
+    /// return to the same address further down the stack first, and are run past.
+    fn run_to_return(&mut self, return_addr: usize, call_rsp: usize) -> Result<Status, nix::Error> {
+        loop {
+            let status = self.run_to_address(return_addr)?;
+            if let Status::Stopped(_, Signal::SIGTRAP, rip) = status {
+                let rsp = self.inferior.as_ref().unwrap().get_registers()?.rsp as usize;
+                if rip == return_addr && rsp <= call_rsp {
+                    match self.step_instruction()? {
+                        Status::Stopped(_, Signal::SIGTRAP, _) => continue,
+                        other => return Ok(other),
+                    }
+                }
+            }
+            return Ok(status);
+        }
+    }
+
+    /// Steps the inferior until it reaches a different source line. If `step_over_calls` is set,
+    /// function calls are run to completion instead of being stepped into. Calls into functions
+    /// without debug info (e.g. libc) are always run to completion.
+    fn step_line(&mut self, step_over_calls: bool) -> Result<Status, nix::Error> {
+        let pid = self.inferior.as_ref().unwrap().tid();
+        self.rewind_to_breakpoint()?;
+
+        let regs = ptrace::getregs(pid)?;
+        let start_line = self.line_at(regs.rip as usize);
+        let start_rsp = regs.rsp as usize;
+
+        loop {
+            let before = ptrace::getregs(pid)?;
+            let mut rip = match self.step_instruction()? {
+                Status::Stopped(_, Signal::SIGTRAP, rip) => rip,
+                other => return Ok(other),
+            };
+
+            if step_over_calls {
+                // A call pushes the address of the instruction after it, which tells it apart
+                // from a push, and from a jump within the function. Comparing functions
+                // instead would step into recursive calls.
+                let regs = ptrace::getregs(pid)?;
+                let top = ptrace::read(pid, regs.rsp as ptrace::AddressType)? as usize;
+                let called = regs.rsp == before.rsp - 8
+                    && top > before.rip as usize
+                    && top <= before.rip as usize + MAX_INSTRUCTION_LEN;
+                if called {
+                    match self.run_to_return(top, regs.rsp as usize)? {
+                        Status::Stopped(_, Signal::SIGTRAP, stopped_rip) if stopped_rip == top => {
+                            rip = stopped_rip
+                        }
+                        other => return Ok(other),
+                    }
+                }
+            }
+
+            // Code without line information, such as a PLT stub or the library function behind
+            // it, is never a place to stop. Single-stepping through it would take ages (the
+            // dynamic linker may run the first time a function is called), so get back to our
+            // own code in one go.
+            if self.line_at(rip).is_none() {
+                let regs = ptrace::getregs(pid)?;
+                if (regs.rsp as usize) < start_rsp {
+                    // We were just called, so the return address is on top of the stack
+                    let return_addr = ptrace::read(pid, regs.rsp as ptrace::AddressType)? as usize;
+                    match self.run_to_return(return_addr, regs.rsp as usize)? {
+                        Status::Stopped(_, Signal::SIGTRAP, stopped_rip)
+                            if stopped_rip == return_addr =>
+                        {
+                            rip = return_addr
+                        }
+                        other => return Ok(other),
+                    }
+                } else {
+                    // We returned into code we know nothing about (e.g. out of main), so there
+                    // is no next line; run until something else stops us
+                    return self.continue_inferior(None);
+                }
+            }
+
+            // Like a breakpoint on a function, stepping into one stops once its prologue has run
+            let prologue_end = self.debug_data.as_ref().unwrap().get_prologue_end(rip);
+            if prologue_end != rip {
+                match self.run_to_address(prologue_end)? {
+                    Status::Stopped(_, Signal::SIGTRAP, stopped_rip)
+                        if stopped_rip == prologue_end =>
+                    {
+                        rip = prologue_end
+                    }
+                    other => return Ok(other),
+                }
+            }
+
+            let line = self.line_at(rip);
+            if line.is_some() && line != start_line {
+                return Ok(Status::Stopped(pid, Signal::SIGTRAP, rip));
+            }
+        }
+    }
+
+    /// Finds the address the selected frame will return to. In the first two instructions of a
+    /// function (`push %rbp; mov %rsp,%rbp`) rbp still belongs to the caller, so the return
+    /// address is read relative to rsp instead.
+    fn return_address(&self, frame: &Frame) -> Result<usize, nix::Error> {
+        let pid = self.inferior.as_ref().unwrap().tid();
+        let entry = self
+            .debug_data
+            .as_ref()
+            .and_then(|data| data.get_function_containing(frame.rip))
+            .map(|func| func.address);
+        let slot = match entry {
+            Some(entry) if self.current_frame == 0 && frame.rip - entry <= 1 => {
+                let rsp = ptrace::getregs(pid)?.rsp as usize;
+                rsp + 8 * (frame.rip - entry)
+            }
+            _ => frame.rbp + 8,
+        };
+        Ok(ptrace::read(pid, slot as ptrace::AddressType)? as usize)
+    }
+
+    /// Runs until the selected frame returns to its caller. Like `resume`, a breakpoint at the
+    /// current instruction is stepped over first. Expects rip to have been rewound already, so
+    /// that `frame` reflects the real instruction pointer.
+    fn finish(&mut self, frame: Frame) -> Result<Status, nix::Error> {
+        let return_addr = self.return_address(&frame)?;
+        self.advance_to(return_addr)
+    }
+
+    /// Runs until the inferior reaches `addr`, first stepping over a breakpoint at the current
+    /// instruction so that we don't immediately trap on it again. Expects rip to have been
+    /// rewound already.
+    fn advance_to(&mut self, addr: usize) -> Result<Status, nix::Error> {
+        let rip = self.inferior.as_ref().unwrap().get_rip()?;
+        if self.breakpoints_map.get(&rip).is_some() {
+            match self.step_instruction()? {
+                Status::Stopped(_, Signal::SIGTRAP, _) => {}
+                other => return Ok(other),
+            }
+        }
+        self.run_to_address(addr)
+    }
+
+    /// Finds the address of `line` for `until`. Returns an error message if the line has no
+    /// code, or if it isn't in the function the inferior is currently stopped in.
+    fn until_address(&self, line: usize) -> Result<usize, String> {
+        let debug_data = self
+            .debug_data
+            .as_ref()
+            .ok_or_else(|| "No debugging symbols loaded".to_string())?;
+        let addr = debug_data
+            .get_addr_for_line(None, line)
+            .ok_or_else(|| format!("No code at line {}", line))?;
+        let rip = self
+            .inferior
+            .as_ref()
+            .unwrap()
+            .get_rip()
+            .map_err(|err| err.to_string())?;
+        let current_func = debug_data.get_function_from_addr(rip);
+        if current_func.is_none() || debug_data.get_function_from_addr(addr) != current_func {
+            return Err(format!("Line {} is not in the current function", line));
+        }
+        Ok(addr)
+    }
+
+    /// Returns the stack frame currently selected with frame/up/down.
+    fn selected_frame(&self) -> Result<Frame, String> {
+        let data = self
+            .debug_data
+            .as_ref()
+            .ok_or_else(|| "No debugging symbols loaded".to_string())?;
+        let frames = self
+            .inferior
+            .as_ref()
+            .unwrap()
+            .unwind_frames(data)
+            .map_err(|err| err.to_string())?;
+        frames
+            .get(self.current_frame)
+            .copied()
+            .ok_or_else(|| format!("No frame {}", self.current_frame))
+    }
+
+    /// Finds a variable visible from the selected frame and computes its address.
+    fn locate_variable(&self, var_name: &str) -> Result<(&Variable, usize), String> {
+        let frame = self.selected_frame()?;
+        let var = self
+            .debug_data
+            .as_ref()
+            .and_then(|data| data.get_variable(frame.rip, var_name))
+            .ok_or_else(|| format!("No symbol \"{}\" in current context.", var_name))?;
+        let addr = match var.location {
+            Location::Address(addr) => addr,
+            // gcc describes the frame base as DW_OP_call_frame_cfa, which is rbp + 16 once the
+            // function prologue has run.
+            Location::FramePointerOffset(offset) => (frame.rbp as isize + 16 + offset) as usize,
+        };
+        Ok((var, addr))
+    }
+
+    /// Finds a variable visible from the inferior's current location and reads its bytes.
+    /// Returns a printable error message if the variable is out of scope or can't be read.
+    fn read_variable(&self, var_name: &str) -> Result<(&Variable, Vec<u8>), String> {
+        let (var, addr) = self.locate_variable(var_name)?;
+        let bytes = self
+            .inferior
+            .as_ref()
+            .unwrap()
+            .read_memory(addr, var.entity_type.size)
+            .map_err(|err| {
+                format!(
+                    "Cannot access memory for {} at {:#x}: {}",
+                    var_name, addr, err
+                )
+            })?;
+        Ok((var, bytes))
+    }
+
+    /// Prints the value of a variable visible from the inferior's current location.
+    fn print_variable(&self, var_name: &str) {
+        match self.read_variable(var_name) {
+            Ok((var, bytes)) => {
+                let mut value = format_value(&bytes, &var.entity_type);
+                // Show what a char pointer points to, like gdb does
+                if var.entity_type.name.ends_with("char *") {
+                    let inferior = self.inferior.as_ref().unwrap();
+                    if let Some(ptr) = value_as_i64(&bytes, &var.entity_type) {
+                        if let Ok(string) = inferior.read_cstring(ptr as usize) {
+                            value = format!("{} {:?}", value, string);
+                        }
+                    }
+                }
+                println!("{} = {}", var_name, value)
+            }
+            Err(msg) => println!("{}", msg),
+        }
+    }
+
+    /// Stores an integer in a variable visible from the inferior's current location. The value
+    /// has to fit in the variable's DWARF type; anything other than an integer (or pointer) is
+    /// refused.
+    fn set_variable(&mut self, var_name: &str, value: i64) -> Result<(), String> {
+        let (var, addr) = self.locate_variable(var_name)?;
+        let type_name = var.entity_type.name.clone();
+        let size = var.entity_type.size;
+        if size == 0 || size > 8 || type_name == "float" || type_name == "double" {
+            return Err(format!("Cannot set {} of type {}", var_name, type_name));
+        }
+        let bits = 8 * size as u32;
+        let fits = if type_name.contains("unsigned") || type_name.ends_with('*') {
+            value >= 0 && (bits == 64 || value < 1_i64 << bits)
+        } else {
+            bits == 64 || (value >= -(1_i64 << (bits - 1)) && value < 1_i64 << (bits - 1))
+        };
+        if !fits {
+            return Err(format!(
+                "{} does not fit in {} ({})",
+                value, var_name, type_name
+            ));
+        }
+        self.inferior
+            .as_mut()
+            .unwrap()
+            .write_memory(addr, &value.to_le_bytes()[..size])
+            .map_err(|err| {
+                format!(
+                    "Cannot access memory for {} at {:#x}: {}",
+                    var_name, addr, err
+                )
+            })
+    }
+
+    /// Evaluates a breakpoint condition against the inferior's current state. A condition that
+    /// can't be evaluated counts as true, so the user gets to see why.
+    fn condition_holds(&self, condition: &Condition) -> bool {
+        match self.read_variable(&condition.var_name) {
+            Ok((var, bytes)) => match value_as_i64(&bytes, &var.entity_type) {
+                Some(value) => condition.holds(value),
+                None => {
+                    println!("Cannot compare {} as an integer", condition.var_name);
+                    true
+                }
+            },
+            Err(msg) => {
+                println!("Error evaluating condition \"{}\": {}", condition, msg);
+                true
+            }
+        }
+    }
+
+    /// Resumes the inferior from wherever it is stopped, optionally delivering a signal. If it is
+    /// sitting on a breakpoint, the breakpoint is stepped over first so that it doesn't
+    /// immediately trap again.
+    fn resume(&mut self, signal: Option<Signal>) -> Result<Status, nix::Error> {
+        self.rewind_to_breakpoint()?;
+        let rip = self.inferior.as_ref().unwrap().get_rip()?;
+        if self.breakpoints_map.get(&rip).is_some() {
+            match self.step_instruction()? {
+                Status::Stopped(_, Signal::SIGTRAP, _) => {}
+                other => return Ok(other),
+            }
+        }
+        self.continue_inferior(signal)
+    }
+
+    /// Continues the inferior until it stops for a reason worth reporting. Conditional
+    /// breakpoints whose condition doesn't hold are stepped over and execution carries on.
+    fn continue_inferior(&mut self, signal: Option<Signal>) -> Result<Status, nix::Error> {
+        let mut signal = signal;
+        self.watchpoint_triggered = false;
+        loop {
+            let status = self
+                .inferior
+                .as_ref()
+                .unwrap()
+                .continue_run(signal.take())?;
+            if let Status::Stopped(_, Signal::SIGTRAP, rip) = status {
+                let should_stop = match self
+                    .breakpoints_map
+                    .get(&(rip - 1))
+                    .and_then(|breakpoint| breakpoint.condition.as_ref())
+                {
+                    Some(condition) => self.condition_holds(condition),
+                    None => true,
+                };
+                if !should_stop {
+                    self.inferior.as_ref().unwrap().set_rip(rip - 1)?;
+                    match self.step_instruction()? {
+                        Status::Stopped(_, Signal::SIGTRAP, _) => continue,
+                        other => return Ok(other),
+                    }
+                }
+                if !self.breakpoints_map.contains_key(&(rip - 1)) {
+                    self.watchpoint_triggered = self.report_watchpoints();
+                }
+            }
+            if let Status::Exec(..) = status {
+                self.handle_exec();
+            }
+            return Ok(status);
+        }
+    }
+
+    /// Prints `count` words of inferior memory starting at `addr`, a few per line, like gdb's x
+    /// command. Words that can't be read are shown as such rather than aborting the whole dump.
+    /// Our breakpoints are hidden, showing the original bytes instead of their traps.
+    fn examine_memory(&self, addr: usize, count: usize) {
+        let inferior = self.inferior.as_ref().unwrap();
+        let word_size = std::mem::size_of::<usize>();
+        for line_start in (0..count).step_by(EXAMINE_WORDS_PER_LINE) {
+            let mut line = format!("{:#x}:", addr + line_start * word_size);
+            for idx in line_start..count.min(line_start + EXAMINE_WORDS_PER_LINE) {
+                let word_addr = addr + idx * word_size;
+                match inferior.read_memory(word_addr, word_size) {
+                    Ok(mut bytes) => {
+                        for (offset, byte) in bytes.iter_mut().enumerate() {
+                            if let Some(breakpoint) =
+                                self.breakpoints_map.get(&(word_addr + offset))
+                            {
+                                *byte = breakpoint.orig_byte;
+                            }
+                        }
+                        let word = bytes
+                            .iter()
+                            .rev()
+                            .fold(0_u64, |word, byte| (word << 8) | *byte as u64);
+                        line.push_str(&format!(" {:#018x}", word));
+                    }
+                    Err(_) => line.push_str(" <unreadable>"),
+                }
+            }
+            println!("{}", line);
+        }
+    }
+
+    /// Sets a hardware watchpoint on an address (`0x...`) or on a variable visible from the
+    /// current location. Watching a variable uses its DWARF type for size and formatting.
+    fn add_watchpoint(&mut self, location: &str) {
+        if self.watchpoints.len() >= MAX_WATCHPOINTS {
+            println!("All {} hardware watchpoints are in use", MAX_WATCHPOINTS);
+            return;
+        }
+        let (addr, entity_type) = if location.to_lowercase().starts_with("0x") {
+            match Self::parse_address(location) {
+                Some(addr) => (addr, Type::new("unsigned long".to_string(), 8)),
+                None => {
+                    println!("Invalid address {}", location);
+                    return;
+                }
+            }
+        } else {
+            match self.locate_variable(location) {
+                Ok((var, addr)) => (addr, var.entity_type.clone()),
+                Err(msg) => {
+                    println!("{}", msg);
+                    return;
+                }
+            }
+        };
+        // The debug registers can only watch 1, 2, 4 or 8 naturally-aligned bytes
+        let len = entity_type.size;
+        if ![1, 2, 4, 8].contains(&len) || addr % len != 0 {
+            println!("Cannot watch {} bytes at {:#x}", len, addr);
+            return;
+        }
+
+        let inferior = self.inferior.as_ref().unwrap();
+        let value = match inferior.read_memory(addr, len) {
+            Ok(value) => value,
+            Err(err) => {
+                println!("Cannot access memory at {:#x}: {}", addr, err);
+                return;
+            }
+        };
+        let mut regions: Vec<(usize, usize)> =
+            self.watchpoints.iter().map(|w| (w.addr, w.len)).collect();
+        regions.push((addr, len));
+        if let Err(err) = inferior.set_hw_watchpoints(&regions) {
+            println!("Failed to set hardware watchpoint: {}", err);
+            return;
+        }
+        println!(
+            "Watchpoint {}: {} ({:#x})",
+            self.watchpoints.len(),
+            location,
+            addr
+        );
+        self.watchpoints.push(Watchpoint {
+            expr: location.to_string(),
+            addr,
+            len,
+            entity_type,
+            value,
+        });
+    }
+
+    /// Compares every watched location against its last known value, reporting and recording
+    /// any that changed. Returns whether there were any.
+    fn report_watchpoints(&mut self) -> bool {
+        let inferior = self.inferior.as_ref().unwrap();
+        let mut changed = false;
+        for (idx, watchpoint) in self.watchpoints.iter_mut().enumerate() {
+            if let Ok(new_value) = inferior.read_memory(watchpoint.addr, watchpoint.len) {
+                if new_value != watchpoint.value {
+                    println!("Watchpoint {}: {}", idx, watchpoint.expr);
+                    println!(
+                        "Old value = {}",
+                        format_value(&watchpoint.value, &watchpoint.entity_type)
+                    );
+                    println!(
+                        "New value = {}",
+                        format_value(&new_value, &watchpoint.entity_type)
+                    );
+                    watchpoint.value = new_value;
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Selects stack frame `frame_idx` for subsequent print/list commands and describes it.
+    fn select_frame(&mut self, frame_idx: usize) {
+        let data = match self.debug_data.as_ref() {
+            Some(data) => data,
+            None => {
+                println!("No debugging symbols loaded");
+                return;
+            }
+        };
+        let frames = match self.inferior.as_ref().unwrap().unwind_frames(data) {
+            Ok(frames) => frames,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+        let frame = match frames.get(frame_idx) {
+            Some(frame) => frame,
+            None => {
+                println!(
+                    "No frame {} (the stack has {} frames)",
+                    frame_idx,
+                    frames.len()
+                );
+                return;
+            }
+        };
+        println!(
+            "#{} {} ({})",
+            frame_idx,
+            data.get_function_from_addr(frame.rip)
+                .unwrap_or_else(|| "??".to_string()),
+            data.get_line_from_addr(frame.rip)
+                .map(|line| line.to_string())
+                .unwrap_or_else(|| "??".to_string())
+        );
+        self.current_frame = frame_idx;
+    }
+
+    /// Prints the source lines surrounding the line that contains `addr`, marking that line.
+    fn list_source(&mut self, addr: usize) {
+        let line = match self
+            .debug_data
+            .as_ref()
+            .and_then(|data| data.get_line_from_addr(addr))
+        {
+            Some(line) => line,
+            None => {
+                println!("No line information for address {:#x}", addr);
+                return;
+            }
+        };
+        if !self.source_cache.contains_key(&line.file) {
+            match fs::read_to_string(&line.file) {
+                Ok(contents) => {
+                    let lines = contents.lines().map(|l| l.to_string()).collect();
+                    self.source_cache.insert(line.file.clone(), lines);
+                }
+                Err(err) => {
+                    println!("Could not read source file {}: {}", line.file, err);
+                    return;
+                }
+            }
+        }
+
+        let source_lines = &self.source_cache[&line.file];
+        let first = line.number.saturating_sub(LIST_CONTEXT_LINES).max(1);
+        let last = (line.number + LIST_CONTEXT_LINES).min(source_lines.len());
+        for number in first..=last {
+            let marker = if number == line.number { "->" } else { "  " };
+            println!("{} {:>4}\t{}", marker, number, source_lines[number - 1]);
+        }
+    }
+
+    /// Prints every breakpoint along with where it resolves to and whether it is currently
+    /// installed in a running inferior.
+    fn print_breakpoints(&self) {
+        if self.breakpoints_list.is_empty() {
+            println!("No breakpoints.");
+            return;
+        }
+        println!("Num\tAddress\t\t\tActive\tWhere");
+        for (point_id, addr, condition, _) in &self.breakpoints_list {
+            let location = match self.debug_data.as_ref() {
+                Some(data) => format!(
+                    "{} ({})",
+                    data.get_function_from_addr(*addr)
+                        .unwrap_or_else(|| "??".to_string()),
+                    data.get_line_from_addr(*addr)
+                        .map(|line| line.to_string())
+                        .unwrap_or_else(|| "??".to_string())
+                ),
+                None => "??".to_string(),
+            };
+            let active = self.inferior.is_some()
+                && !self.inferior_exited()
+                && self.breakpoints_map.contains_key(addr);
+            println!(
+                "{}\t{:#018x}\t{}\t{}",
+                point_id,
+                addr,
+                if active { "yes" } else { "no" },
+                location
+            );
+            if let Some(condition) = condition {
+                println!("\tstop only if {}", condition);
+            }
+        }
+    }
+
+    /// Prints the inferior's general-purpose registers in hex. When it's stopped at one of our
+    /// breakpoints, rip is shown as the breakpoint's address rather than one past the trap.
+    fn print_registers(&self) {
+        let mut regs = match self.inferior.as_ref().unwrap().get_registers() {
+            Ok(regs) => regs,
+            Err(err) => {
+                eprintln!("{}", err);
+                return;
+            }
+        };
+        if let Ok(Status::Stopped(_, Signal::SIGTRAP, rip)) = self.current_result {
+            if self.breakpoints_map.contains_key(&(rip - 1)) {
+                regs.rip -= 1;
+            }
+        }
+        let named_regs = [
+            ("rip", regs.rip),
+            ("rsp", regs.rsp),
+            ("rbp", regs.rbp),
+            ("rax", regs.rax),
+            ("rbx", regs.rbx),
+            ("rcx", regs.rcx),
+            ("rdx", regs.rdx),
+            ("rsi", regs.rsi),
+            ("rdi", regs.rdi),
+            ("r8", regs.r8),
+            ("r9", regs.r9),
+            ("r10", regs.r10),
+            ("r11", regs.r11),
+            ("r12", regs.r12),
+            ("r13", regs.r13),
+            ("r14", regs.r14),
+            ("r15", regs.r15),
+            ("eflags", regs.eflags),
+        ];
+        for (name, value) in named_regs.iter() {
+            println!("{:<8}{:#018x}", name, value);
+        }
+    }
+
     pub fn run(&mut self) {
         loop {
             match self.get_next_command() {
                 DebuggerCommand::Run(args) => {
-                    if let Some(mut inferior) = self.inferior.take() {
-                        let _ = inferior.kill();
-                    }
+                    self.release_inferior();
 
                     if let Some(inferior) = Inferior::new(&self.target, &args) {
                         // Create the inferior
@@ -125,105 +1303,329 @@ impl Debugger {
                         // TODO (milestone 1): make the inferior run
                         // You may use self.inferior.as_mut().unwrap() to get a mutable reference
                         // to the Inferior object
-                        for idx in 0..self.breakpoints_list.len() {
-                            let (point_id, addr) = self.breakpoints_list[idx];
-                            let breakpoint = self
-                                .set_breakpoint(point_id, addr)
-                                .expect("set breakpoint failed!");
-                            self.breakpoints_map.insert(addr, breakpoint);
-                        }
+                        // Saved original bytes belong to the previous process; start fresh.
+                        self.breakpoints_map.clear();
+                        // Debug registers are per-process, and watched stack addresses are
+                        // meaningless in a new process anyway.
+                        self.watchpoints.clear();
+                        self.install_breakpoints();
 
-                        self.current_result = self.inferior.as_mut().unwrap().continue_run(None);
-                        self.deal_status(&self.current_result);
+                        self.current_frame = 0;
+                        self.current_result = self.continue_inferior(None);
+                        self.deal_status(&self.current_result, false);
                     } else {
                         println!("Error starting subprocess");
                     }
                 }
 
-                DebuggerCommand::Continue => {
+                DebuggerCommand::Attach(pid) => {
+                    self.release_inferior();
+                    let pid = nix::unistd::Pid::from_raw(pid);
+                    match Inferior::attach(pid) {
+                        Ok(inferior) => {
+                            self.inferior = Some(inferior);
+                            println!("Attached to process {}", pid);
+                            let exe_path = self.inferior.as_ref().unwrap().exe_path();
+                            let same_target = match (&exe_path, fs::canonicalize(&self.target)) {
+                                (Ok(exe_path), Ok(target_path)) => *exe_path == target_path,
+                                _ => false,
+                            };
+                            if !same_target {
+                                println!(
+                                    "Warning: process {} is not running {}; symbols and \
+                                     breakpoints may not match it",
+                                    pid, self.target
+                                );
+                            }
+                            self.breakpoints_map.clear();
+                            self.watchpoints.clear();
+                            self.install_breakpoints();
+                            self.current_frame = 0;
+                            let rip = self.inferior.as_ref().unwrap().get_rip().unwrap_or(0);
+                            self.current_result = Ok(Status::Stopped(pid, Signal::SIGSTOP, rip));
+                            // A process we attach to is usually blocked somewhere in libc, which
+                            // we have no line information for
+                            match self.line_at(rip) {
+                                Some((file, line)) => println!("Stopped at {}:{}", file, line),
+                                None => println!("Stopped at {:#x}", rip),
+                            }
+                        }
+                        Err(err) => println!("Could not attach to process {}: {}", pid, err),
+                    }
+                }
+
+                DebuggerCommand::Continue(signal) => {
                     if self.inferior.is_none() {
                         eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("Child is not running");
+                    } else {
+                        self.current_frame = 0;
+                        self.current_result = self.resume(signal);
+                        self.deal_status(&self.current_result, self.watchpoint_triggered);
                     }
-                    if self.current_result.is_ok() {
-                        let status = self
-                            .current_result
-                            .as_ref()
-                            .ok()
-                            .expect("get current result failed!");
-                        if let Status::Stopped(pid, _signal, rip) = status {
-                            let stopped_rip = rip - 1;
-                            let breakpoint = &self.breakpoints_map[&stopped_rip];
-                            // restore old value
-                            let _ = self
-                                .inferior
-                                .as_mut()
-                                .unwrap()
-                                .write_byte(stopped_rip, breakpoint.orig_byte);
-
-                            let _ = ptrace::step(*pid, None);
-                            let _ = waitpid(*pid, None);
-
-                            let _ = self
-                                .inferior
-                                .as_mut()
-                                .unwrap()
-                                .write_byte(stopped_rip, 0xcc);
+                }
+
+                DebuggerCommand::Backtrace => match self.inferior.as_ref() {
+                    Some(_) if self.inferior_exited() => println!("Child is not running"),
+                    Some(inferior) => {
+                        if let Err(err) = inferior.print_backtrace(&self.debug_data) {
+                            eprintln!("{}", err);
                         }
                     }
-                    self.current_result = self.inferior.as_mut().unwrap().continue_run(None);
-                    self.deal_status(&self.current_result);
+                    None => eprintln!("Error no subprocess is running!"),
+                },
+
+                DebuggerCommand::BreakPoint(point_addr, condition) => {
+                    self.add_breakpoint(&point_addr, condition);
                 }
 
-                DebuggerCommand::Backtrace => {
-                    let _ = self
-                        .inferior
-                        .as_mut()
-                        .unwrap()
-                        .print_backtrace(&self.debug_data);
-                }
-
-                DebuggerCommand::BreakPoint(point_addr) => {
-                    let mut addr: usize = 0;
-                    if point_addr.to_lowercase().starts_with("0x") {
-                        addr = Self::parse_address(&point_addr).expect("invalied address");
-                        println!("Set breakpoint {} at {}", self.breakpoint_count, point_addr);
-                    } else if point_addr.chars().all(|char| char.is_ascii_digit()) {
-                        let line_number = point_addr
-                            .parse::<usize>()
-                            .expect("failed to parse addr to line number");
-                        addr = self
-                            .debug_data
-                            .as_ref()
-                            .unwrap()
-                            .get_addr_for_line(None, line_number)
-                            .expect("failed to get addr for line");
-
-                        println!("Set breakpoint {} at {:x}", self.breakpoint_count, addr);
+                DebuggerCommand::Next => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("Child is not running");
+                    } else if self.debug_data.is_none() {
+                        println!("No line information, so there are no lines to step through");
+                    } else {
+                        self.current_frame = 0;
+                        self.current_result = self.step_line(true);
+                        self.deal_status(&self.current_result, true);
+                    }
+                }
+
+                DebuggerCommand::Step => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("Child is not running");
+                    } else if self.debug_data.is_none() {
+                        println!("No line information, so there are no lines to step through");
+                    } else {
+                        self.current_frame = 0;
+                        self.current_result = self.step_line(false);
+                        self.deal_status(&self.current_result, true);
+                    }
+                }
+
+                DebuggerCommand::Finish => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("Child is not running");
+                    } else if let Err(err) = self.rewind_to_breakpoint() {
+                        eprintln!("{}", err);
+                    } else {
+                        match self.selected_frame() {
+                            Ok(frame) => {
+                                let func_name = self
+                                    .debug_data
+                                    .as_ref()
+                                    .and_then(|data| data.get_function_from_addr(frame.rip));
+                                if func_name.as_deref() == Some("main") {
+                                    println!("\"finish\" not meaningful in the outermost frame.");
+                                } else {
+                                    println!(
+                                        "Run till exit from {}",
+                                        func_name.unwrap_or_else(|| "??".to_string())
+                                    );
+                                    self.current_result = self.finish(frame);
+                                    self.current_frame = 0;
+                                    self.deal_status(&self.current_result, true);
+                                }
+                            }
+                            Err(err) => eprintln!("{}", err),
+                        }
+                    }
+                }
+
+                DebuggerCommand::Until(line) => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("Child is not running");
+                    } else if let Err(err) = self.rewind_to_breakpoint() {
+                        eprintln!("{}", err);
                     } else {
-                        addr = self
-                            .debug_data
-                            .as_ref()
-                            .unwrap()
-                            .get_addr_for_function(None, &point_addr)
-                            .expect("faile to get addr for cuntion");
+                        match self.until_address(line) {
+                            Ok(addr) => {
+                                self.current_frame = 0;
+                                self.current_result = self.advance_to(addr);
+                                self.deal_status(&self.current_result, true);
+                            }
+                            Err(err) => eprintln!("{}", err),
+                        }
+                    }
+                }
 
-                        println!("Set breakpoint {} at {:x}", self.breakpoint_count, addr);
+                DebuggerCommand::DeleteBreakpoint(point_id) => {
+                    match self
+                        .breakpoints_list
+                        .iter()
+                        .position(|(id, _addr, _condition, _location)| *id == point_id)
+                    {
+                        Some(idx) => {
+                            let (_, addr, _, _) = self.breakpoints_list.remove(idx);
+                            self.uninstall_breakpoint(addr);
+                            println!("Deleted breakpoint {}", point_id);
+                        }
+                        None => {
+                            println!("No breakpoint number {}", point_id);
+                        }
                     }
+                }
 
-                    self.breakpoints_list.push((self.breakpoint_count, addr));
-                    if self.inferior.is_some() {
-                        let breakpoint = self
-                            .set_breakpoint(self.breakpoint_count, addr)
-                            .expect("set_breakpoint failed!");
-                        self.breakpoints_map.insert(addr, breakpoint);
+                DebuggerCommand::Print(var_name) => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("The program is not being run.");
+                    } else {
+                        self.print_variable(&var_name);
+                    }
+                }
+
+                DebuggerCommand::Set(var_name, value) => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("Child is not running");
+                    } else if let Err(msg) = self.set_variable(&var_name, value) {
+                        println!("{}", msg);
+                    } else {
+                        self.print_variable(&var_name);
+                    }
+                }
+
+                DebuggerCommand::List => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else {
+                        match self.selected_frame() {
+                            Ok(frame) => self.list_source(frame.rip),
+                            Err(msg) => println!("{}", msg),
+                        }
+                    }
+                }
+
+                DebuggerCommand::InfoBreakpoints => {
+                    self.print_breakpoints();
+                }
+
+                DebuggerCommand::Registers => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else {
+                        self.print_registers();
+                    }
+                }
+
+                DebuggerCommand::InfoFunctions => {
+                    if let Some(data) = self.debug_data.as_ref() {
+                        data.print_functions();
+                    }
+                }
+
+                DebuggerCommand::Whereis(func_name) => match self.debug_data.as_ref() {
+                    Some(data) => {
+                        let funcs = data.find_functions(&func_name);
+                        if funcs.is_empty() {
+                            println!("No function named \"{}\"", func_name);
+                        }
+                        for (file, func) in funcs {
+                            println!(
+                                "{} is defined at {}:{}, starting at {:#x}",
+                                func.name, file, func.line_number, func.address
+                            );
+                        }
+                    }
+                    None => println!("No debugging symbols loaded"),
+                },
+
+                DebuggerCommand::Watch(location) => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else {
+                        self.add_watchpoint(&location);
+                    }
+                }
+
+                DebuggerCommand::Frame(frame_idx) => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else {
+                        self.select_frame(frame_idx);
+                    }
+                }
+
+                DebuggerCommand::Up => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else {
+                        self.select_frame(self.current_frame + 1);
+                    }
+                }
+
+                DebuggerCommand::Down => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.current_frame == 0 {
+                        println!("Bottom (innermost) frame selected; you cannot go down.");
+                    } else {
+                        self.select_frame(self.current_frame - 1);
+                    }
+                }
+
+                DebuggerCommand::Kill => match self.inferior.as_ref() {
+                    // Left in place so breakpoints are still installed by the next run
+                    Some(_) if self.inferior_exited() => println!("Child is not running"),
+                    Some(_) => {
+                        let mut inferior = self.inferior.take().unwrap();
+                        let pid = inferior.pid();
+                        if let Err(err) = inferior.kill() {
+                            eprintln!("Could not kill inferior: {}", err);
+                        }
+                        // Saved bytes and debug registers belonged to that process
+                        self.breakpoints_map.clear();
+                        self.watchpoints.clear();
+                        self.current_frame = 0;
+                        println!("Killed inferior (pid {})", pid);
+                    }
+                    None => eprintln!("Error no subprocess is running!"),
+                },
+
+                DebuggerCommand::Examine(location, count) => {
+                    if self.inferior.is_none() {
+                        eprintln!("Error no subprocess is running!");
+                    } else if self.inferior_exited() {
+                        println!("Child is not running");
+                    } else {
+                        match Self::parse_address(&location) {
+                            Some(addr) => self.examine_memory(addr, count),
+                            None => println!("Invalid address {}", location),
+                        }
+                    }
+                }
+
+                DebuggerCommand::Help => {
+                    let width = debugger_command::HELP_ENTRIES
+                        .iter()
+                        .map(|(syntax, _)| syntax.len())
+                        .max()
+                        .unwrap_or(0);
+                    for (syntax, description) in debugger_command::HELP_ENTRIES {
+                        println!("{:width$}  {}", syntax, description, width = width);
                     }
-                    self.breakpoint_count += 1;
                 }
 
                 DebuggerCommand::Quit => {
-                    if let Some(mut inferior) = self.inferior.take() {
-                        let _ = inferior.kill();
+                    if let Err(err) = self.save_breakpoints() {
+                        println!(
+                            "Warning: failed to save breakpoints to {}: {}",
+                            self.breakpoints_path, err
+                        );
                     }
+                    self.release_inferior();
                     return;
                 }
             }
@@ -231,10 +1633,15 @@ impl Debugger {
     }
 
     /// This function prompts the user to enter a command, and continues re-prompting until the user
-    /// enters a valid command. It uses DebuggerCommand::from_tokens to do the command parsing.
-    ///
-    /// You don't need to read, understand, or modify this function.
+    /// enters a valid command. Commands given with --ex are used up first, as if they had been
+    /// typed at the prompt.
     fn get_next_command(&mut self) -> DebuggerCommand {
+        while let Some(line) = self.startup_commands.pop_front() {
+            println!("(deet) {}", line);
+            if let Some(cmd) = Self::parse_command(&line) {
+                return cmd;
+            }
+        }
         loop {
             // Print prompt and get next line of user input
             match self.readline.readline("(deet) ") {
@@ -260,14 +1667,34 @@ impl Debugger {
                             self.history_path, err
                         );
                     }
-                    let tokens: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+                    if let Some(cmd) = Self::parse_command(&line) {
                         return cmd;
-                    } else {
-                        println!("Unrecognized command.");
                     }
                 }
             }
         }
     }
+
+    /// Parses a command line with DebuggerCommand::from_tokens, explaining what's wrong with it
+    /// if it isn't a valid command.
+    fn parse_command(line: &str) -> Option<DebuggerCommand> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            return None;
+        }
+        if let Some(cmd) = DebuggerCommand::from_tokens(&tokens) {
+            return Some(cmd);
+        }
+        let candidates = debugger_command::commands_matching(tokens[0]);
+        if candidates.len() > 1 {
+            println!(
+                "Ambiguous command \"{}\": {}.",
+                tokens[0],
+                candidates.join(", ")
+            );
+        } else {
+            println!("Unrecognized command. Type \"help\" for a list of commands.");
+        }
+        None
+    }
 }
@@ -1,29 +1,246 @@
+use nix::sys::signal::Signal;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A breakpoint condition of the form `variable OP integer`, e.g. `count == 5`.
+#[derive(Clone)]
+pub struct Condition {
+    pub var_name: String,
+    pub op: String,
+    pub value: i64,
+}
+
+impl Condition {
+    /// Parses the tokens following `if` in a breakpoint command. Spaces around the operator are
+    /// optional, so both `count == 5` and `count==5` are accepted.
+    fn from_tokens(tokens: &[&str]) -> Option<Condition> {
+        let expr = tokens.concat();
+        // Two-character operators must be tried first so that "<=" isn't read as "<"
+        for op in &["==", "!=", "<=", ">=", "<", ">"] {
+            if let Some(idx) = expr.find(op) {
+                let var_name = expr[..idx].to_string();
+                let value = expr[idx + op.len()..].parse::<i64>().ok()?;
+                if var_name.is_empty() {
+                    return None;
+                }
+                return Some(Condition {
+                    var_name,
+                    op: op.to_string(),
+                    value,
+                });
+            }
+        }
+        None
+    }
+
+    /// Returns whether the condition holds when the variable has the given value.
+    pub fn holds(&self, var_value: i64) -> bool {
+        match self.op.as_str() {
+            "==" => var_value == self.value,
+            "!=" => var_value != self.value,
+            "<=" => var_value <= self.value,
+            ">=" => var_value >= self.value,
+            "<" => var_value < self.value,
+            ">" => var_value > self.value,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.var_name, self.op, self.value)
+    }
+}
+
+/// Parses a signal given either by number (`10`) or by name, with or without the `SIG` prefix
+/// (`SIGUSR1`, `usr1`).
+fn parse_signal(token: &str) -> Option<Signal> {
+    if let Ok(signum) = token.parse::<i32>() {
+        return Signal::try_from(signum).ok();
+    }
+    let name = token.to_uppercase();
+    if name.starts_with("SIG") {
+        Signal::from_str(&name).ok()
+    } else {
+        Signal::from_str(&format!("SIG{}", name)).ok()
+    }
+}
+
+/// Full name of every command, used to expand abbreviations such as `cont` or `ba`.
+const COMMAND_NAMES: &[&str] = &[
+    "attach",
+    "backtrace",
+    "break",
+    "continue",
+    "delete",
+    "down",
+    "examine",
+    "finish",
+    "frame",
+    "help",
+    "info",
+    "kill",
+    "list",
+    "next",
+    "print",
+    "quit",
+    "registers",
+    "run",
+    "set",
+    "step",
+    "until",
+    "up",
+    "watch",
+    "whereis",
+];
+
+/// Syntax and a short description of each command, as printed by `help`.
+pub const HELP_ENTRIES: &[(&str, &str)] = &[
+    ("run [args...]", "Start (or restart) the program"),
+    ("attach <pid>", "Debug a process that is already running"),
+    ("continue [signal]", "Resume, optionally sending a signal"),
+    ("next", "Run to the next source line, stepping over calls"),
+    ("step", "Run to the next source line, stepping into calls"),
+    ("finish", "Run until the selected function returns"),
+    ("until <line>", "Run to a line in the current function"),
+    ("break <location> [if <cond>]", "Set a breakpoint"),
+    ("delete <id>", "Delete a breakpoint"),
+    ("info breakpoints", "List breakpoints"),
+    ("info functions", "List functions with debug info"),
+    ("whereis <function>", "Show where a function is defined"),
+    ("info registers", "Show the registers"),
+    ("watch <variable>", "Report whenever a variable changes"),
+    ("print <variable>", "Print a variable in the selected frame"),
+    ("set var <variable> = <value>", "Change an integer variable"),
+    ("examine <address> [count]", "Dump words of memory"),
+    ("backtrace", "Show the call stack"),
+    ("frame <n>", "Select a stack frame"),
+    ("up / down", "Select the caller / callee frame"),
+    ("list", "Show the source around the current line"),
+    ("kill", "Kill the running program"),
+    ("help", "Show this list"),
+    ("quit", "Exit deet"),
+];
+
+/// Returns the full names of the commands starting with `prefix`.
+pub fn commands_matching(prefix: &str) -> Vec<&'static str> {
+    COMMAND_NAMES
+        .iter()
+        .filter(|name| name.starts_with(prefix))
+        .copied()
+        .collect()
+}
+
 pub enum DebuggerCommand {
     Quit,
     Run(Vec<String>),
-    Continue,
+    Attach(i32),
+    Continue(Option<Signal>),
     Backtrace,
-    BreakPoint(String),
+    BreakPoint(String, Option<Condition>),
+    Next,
+    Step,
+    Finish,
+    Until(usize),
+    DeleteBreakpoint(i64),
+    Print(String),
+    Set(String, i64),
+    List,
+    InfoBreakpoints,
+    Registers,
+    InfoFunctions,
+    Whereis(String),
+    Watch(String),
+    Frame(usize),
+    Up,
+    Down,
+    Kill,
+    Examine(String, usize),
+    Help,
 }
 
 impl DebuggerCommand {
     pub fn from_tokens(tokens: &Vec<&str>) -> Option<DebuggerCommand> {
         match tokens[0] {
             "q" | "quit" => Some(DebuggerCommand::Quit),
+            "h" | "help" => Some(DebuggerCommand::Help),
+            "k" | "kill" => Some(DebuggerCommand::Kill),
             "r" | "run" => {
                 let args = tokens[1..].to_vec();
                 Some(DebuggerCommand::Run(
                     args.iter().map(|s| s.to_string()).collect(),
                 ))
             }
-            "c" | "cont" | "continue" => Some(DebuggerCommand::Continue),
+            "attach" => Some(DebuggerCommand::Attach(tokens.get(1)?.parse().ok()?)),
+            "c" | "cont" | "continue" => match tokens.get(1) {
+                Some(signal) => Some(DebuggerCommand::Continue(Some(parse_signal(signal)?))),
+                None => Some(DebuggerCommand::Continue(None)),
+            },
             "bt" | "back" | "backtrace" => Some(DebuggerCommand::Backtrace),
             "b" | "break" | "breakpoint"=> {
-                let args = tokens[1].to_string();
-                Some(DebuggerCommand::BreakPoint(args))
+                let args = tokens.get(1)?.to_string();
+                let condition = match tokens.get(2) {
+                    Some(&"if") => Some(Condition::from_tokens(&tokens[3..])?),
+                    Some(_) => return None,
+                    None => None,
+                };
+                Some(DebuggerCommand::BreakPoint(args, condition))
+            }
+            "n" | "next" => Some(DebuggerCommand::Next),
+            "s" | "step" => Some(DebuggerCommand::Step),
+            "fin" | "finish" => Some(DebuggerCommand::Finish),
+            "u" | "until" => Some(DebuggerCommand::Until(tokens.get(1)?.parse().ok()?)),
+            "d" | "delete" => {
+                let point_id = tokens.get(1)?.parse::<i64>().ok()?;
+                Some(DebuggerCommand::DeleteBreakpoint(point_id))
+            }
+            "p" | "print" => Some(DebuggerCommand::Print(tokens.get(1)?.to_string())),
+            "set" => {
+                // `set var count = 5`, like gdb; "var" and the spaces around "=" are optional
+                let args = match tokens.get(1) {
+                    Some(&"var") | Some(&"variable") => &tokens[2..],
+                    _ => &tokens[1..],
+                };
+                let assignment = args.concat();
+                let equals = assignment.find('=')?;
+                let var_name = assignment[..equals].to_string();
+                let value = assignment[equals + 1..].parse::<i64>().ok()?;
+                if var_name.is_empty() {
+                    return None;
+                }
+                Some(DebuggerCommand::Set(var_name, value))
+            }
+            "l" | "list" => Some(DebuggerCommand::List),
+            "f" | "frame" => Some(DebuggerCommand::Frame(tokens.get(1)?.parse().ok()?)),
+            "up" => Some(DebuggerCommand::Up),
+            "down" => Some(DebuggerCommand::Down),
+            "x" | "examine" => {
+                let count = match tokens.get(2) {
+                    Some(count) => count.parse().ok()?,
+                    None => 4,
+                };
+                Some(DebuggerCommand::Examine(tokens.get(1)?.to_string(), count))
             }
-            // Default case:
-            _ => None,
+            "w" | "watch" => Some(DebuggerCommand::Watch(tokens.get(1)?.to_string())),
+            "whereis" => Some(DebuggerCommand::Whereis(tokens.get(1)?.to_string())),
+            "reg" | "registers" => Some(DebuggerCommand::Registers),
+            "i" | "info" => match *tokens.get(1)? {
+                "b" | "break" | "breakpoints" => Some(DebuggerCommand::InfoBreakpoints),
+                "r" | "registers" => Some(DebuggerCommand::Registers),
+                "func" | "functions" => Some(DebuggerCommand::InfoFunctions),
+                _ => None,
+            },
+            // Otherwise accept any unambiguous abbreviation of a command name
+            _ => match commands_matching(tokens[0]).as_slice() {
+                [name] => {
+                    let mut expanded = tokens.clone();
+                    expanded[0] = *name;
+                    DebuggerCommand::from_tokens(&expanded)
+                }
+                _ => None,
+            },
         }
     }
 }
@@ -44,6 +44,12 @@ impl DwarfData {
         })
     }
 
+    /// Whether the binary has any line number information, which is missing when it was built
+    /// without -g or stripped.
+    pub fn has_line_info(&self) -> bool {
+        self.files.iter().any(|file| !file.lines.is_empty())
+    }
+
     #[allow(dead_code)]
     fn get_target_file(&self, file: &str) -> Option<&File> {
         self.files.iter().find(|f| {
@@ -73,12 +79,15 @@ impl DwarfData {
                 self.get_target_file(filename)?
                     .functions
                     .iter()
-                    .find(|func| func.name == func_name)?
+                    .find(|func| func.name == func_name && func.is_defined())?
                     .address,
             ),
             None => {
                 for file in &self.files {
-                    if let Some(func) = file.functions.iter().find(|func| func.name == func_name) {
+                    let mut functions = file.functions.iter();
+                    if let Some(func) =
+                        functions.find(|func| func.name == func_name && func.is_defined())
+                    {
                         return Some(func.address);
                     }
                 }
@@ -111,6 +120,120 @@ impl DwarfData {
         Some(frame.function?.raw_name().ok()?.to_string())
     }
 
+    /// Returns the function whose code contains the given address.
+    pub fn get_function_containing(&self, curr_addr: usize) -> Option<&Function> {
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address <= curr_addr && curr_addr < func.address + func.text_length)
+    }
+
+    /// Returns the function containing the given address or, for an address in the gaps between
+    /// functions, the one starting closest below it. Addresses past the end of the last function
+    /// (e.g. in a shared library) have no nearest function.
+    pub fn get_nearest_function(&self, curr_addr: usize) -> Option<&Function> {
+        let functions = || {
+            self.files
+                .iter()
+                .flat_map(|file| file.functions.iter())
+                .filter(|func| func.is_defined())
+        };
+        if let Some(func) = self.get_function_containing(curr_addr) {
+            return Some(func);
+        }
+        if !functions().any(|func| func.address > curr_addr) {
+            return None;
+        }
+        functions()
+            .filter(|func| func.address <= curr_addr)
+            .max_by_key(|func| func.address)
+    }
+
+    /// Looks up a variable by name as seen from the given address: locals and parameters of the
+    /// enclosing function take precedence over globals.
+    pub fn get_variable(&self, curr_addr: usize, var_name: &str) -> Option<&Variable> {
+        if let Some(func) = self.get_function_containing(curr_addr) {
+            if let Some(var) = func.variables.iter().find(|var| var.name == var_name) {
+                return Some(var);
+            }
+        }
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| var.name == var_name)
+    }
+
+    /// Returns where the function starting at `func_addr` has set up its stack frame and stored
+    /// its parameters: like gdb, the start of the function's second line table row. Returns
+    /// `func_addr` itself if it isn't the start of a function or there is no second row.
+    pub fn get_prologue_end(&self, func_addr: usize) -> usize {
+        let func = match self.get_function_containing(func_addr) {
+            Some(func) if func.address == func_addr => func,
+            _ => return func_addr,
+        };
+        self.files
+            .iter()
+            .flat_map(|file| file.lines.iter())
+            .map(|line| line.address)
+            .filter(|addr| func.address < *addr && *addr < func.address + func.text_length)
+            .min()
+            .unwrap_or(func_addr)
+    }
+
+    /// Returns the formal parameters of the function starting at the given address, in the order
+    /// they are declared.
+    pub fn get_parameters(&self, func_addr: usize) -> Vec<&Variable> {
+        self.files
+            .iter()
+            .flat_map(|file| file.functions.iter())
+            .find(|func| func.address == func_addr)
+            .map(|func| {
+                func.variables
+                    .iter()
+                    .filter(|var| var.is_parameter)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Finds every function called `func_name`, along with the name of the file it's defined in.
+    /// There can be more than one if several files have a static function of that name.
+    pub fn find_functions(&self, func_name: &str) -> Vec<(&str, &Function)> {
+        self.files
+            .iter()
+            .flat_map(|file| {
+                file.functions
+                    .iter()
+                    .filter(move |func| func.name == func_name && func.is_defined())
+                    .map(move |func| (file.name.as_str(), func))
+            })
+            .collect()
+    }
+
+    /// Prints every known function with the file it lives in and the range of source lines its
+    /// code covers.
+    pub fn print_functions(&self) {
+        for file in &self.files {
+            for func in file.functions.iter().filter(|func| func.is_defined()) {
+                let func_lines = file.lines.iter().filter(|line| {
+                    func.address <= line.address && line.address < func.address + func.text_length
+                });
+                let first = func_lines.clone().map(|line| line.number).min();
+                let last = func_lines.map(|line| line.number).max();
+                match (first, last) {
+                    (Some(first), Some(last)) => println!(
+                        "{} ({}, lines {}-{}, at {:#x})",
+                        func.name, file.name, first, last, func.address
+                    ),
+                    _ => println!(
+                        "{} ({}:{}, at {:#x})",
+                        func.name, file.name, func.line_number, func.address
+                    ),
+                }
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn print(&self) {
         for file in &self.files {
@@ -191,6 +314,7 @@ pub struct Variable {
     pub entity_type: Type,
     pub location: Location,
     pub line_number: usize, // Line number in source file
+    pub is_parameter: bool,
 }
 
 #[derive(Debug, Default, Clone)]
@@ -202,6 +326,14 @@ pub struct Function {
     pub variables: Vec<Variable>,
 }
 
+impl Function {
+    /// Returns whether the function has code here. A function that is only declared, like
+    /// printf from stdio.h, shows up with an address and length of 0.
+    pub fn is_defined(&self) -> bool {
+        self.address != 0 && self.text_length != 0
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct File {
     pub name: String,
@@ -48,6 +48,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     let mut iter = dwarf.units();
     while let Some(header) = iter.next()? {
         let unit = dwarf.unit(header)?;
+        read_types(&unit, &dwarf, &mut offset_to_type)?;
 
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
@@ -74,33 +75,6 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         lines: Vec::new(),
                     });
                 }
-                gimli::DW_TAG_base_type => {
-                    let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
-                        if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, &unit, &dwarf) {
-                            name
-                        } else {
-                            "<unknown>".to_string()
-                        }
-                    } else {
-                        "<unknown>".to_string()
-                    };
-                    let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
-                        if let Ok(DebugValue::Uint(byte_size)) =
-                            get_attr_value(&attr, &unit, &dwarf)
-                        {
-                            byte_size
-                        } else {
-                            // TODO: report error?
-                            0
-                        }
-                    } else {
-                        // TODO: report error?
-                        0
-                    };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
-                }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
                     let mut attrs = entry.attrs();
@@ -175,6 +149,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             entity_type: entity_type.unwrap(),
                             location: location.unwrap(),
                             line_number: line_number.try_into().unwrap(),
+                            is_parameter: entry.tag() == gimli::DW_TAG_formal_parameter,
                         };
                         if depth == 1 {
                             compilation_units
@@ -243,6 +218,107 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     Ok(compilation_units)
 }
 
+/// Reads the base, pointer and const/volatile types of a unit into `offset_to_type`. A pointer can
+/// come before the type it points to, so pointers and qualifiers are only named once every entry
+/// in the unit has been seen. Qualifiers are dropped, so a `const char *` is just a `char *`.
+fn read_types<R: Reader>(
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+    offset_to_type: &mut HashMap<usize, Type>,
+) -> Result<(), Error> {
+    // Offset of the type each pointer or qualifier refers to (None for void), and pointer sizes
+    let mut pointers: HashMap<usize, (Option<usize>, usize)> = HashMap::new();
+    let mut qualifiers: HashMap<usize, Option<usize>> = HashMap::new();
+    let mut entries = unit.entries();
+    while let Some((_, entry)) = entries.next_dfs()? {
+        // Other entries refer to types by their offset in the section, not in the unit
+        let type_offset = match entry.offset().to_unit_section_offset(unit) {
+            UnitSectionOffset::DebugInfoOffset(offset) => offset.0,
+            UnitSectionOffset::DebugTypesOffset(offset) => offset.0,
+        };
+        let referenced = match entry.attr(gimli::DW_AT_type) {
+            Ok(Some(attr)) => match get_attr_value(&attr, unit, dwarf) {
+                Ok(DebugValue::Size(offset)) => Some(offset),
+                _ => None,
+            },
+            _ => None,
+        };
+        match entry.tag() {
+            gimli::DW_TAG_base_type => {
+                let name = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_name) {
+                    if let Ok(DebugValue::Str(name)) = get_attr_value(&attr, unit, dwarf) {
+                        name
+                    } else {
+                        "<unknown>".to_string()
+                    }
+                } else {
+                    "<unknown>".to_string()
+                };
+                let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
+                    if let Ok(DebugValue::Uint(byte_size)) = get_attr_value(&attr, unit, dwarf) {
+                        byte_size
+                    } else {
+                        // TODO: report error?
+                        0
+                    }
+                } else {
+                    // TODO: report error?
+                    0
+                };
+                offset_to_type.insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
+            }
+            gimli::DW_TAG_pointer_type => {
+                let byte_size = if let Ok(Some(attr)) = entry.attr(gimli::DW_AT_byte_size) {
+                    if let Ok(DebugValue::Uint(byte_size)) = get_attr_value(&attr, unit, dwarf) {
+                        byte_size
+                    } else {
+                        8
+                    }
+                } else {
+                    8
+                };
+                pointers.insert(type_offset, (referenced, byte_size.try_into().unwrap()));
+            }
+            gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+                qualifiers.insert(type_offset, referenced);
+            }
+            _ => {}
+        }
+    }
+    let offsets: Vec<usize> = pointers.keys().chain(qualifiers.keys()).copied().collect();
+    for offset in offsets {
+        if let Some(resolved) = resolve_type(Some(offset), &pointers, &qualifiers, offset_to_type) {
+            offset_to_type.insert(offset, resolved);
+        }
+    }
+    Ok(())
+}
+
+/// Works out the type at `offset` (void if None) for read_types, following qualifiers through to
+/// the type they qualify. Returns None for types we don't understand, except that a pointer to
+/// one is still treated as a `void *`.
+fn resolve_type(
+    offset: Option<usize>,
+    pointers: &HashMap<usize, (Option<usize>, usize)>,
+    qualifiers: &HashMap<usize, Option<usize>>,
+    offset_to_type: &HashMap<usize, Type>,
+) -> Option<Type> {
+    let offset = match offset {
+        Some(offset) => offset,
+        None => return Some(Type::new("void".to_string(), 0)),
+    };
+    if let Some(&(pointee, byte_size)) = pointers.get(&offset) {
+        let pointee_name = resolve_type(pointee, pointers, qualifiers, offset_to_type)
+            .map(|pointee| pointee.name)
+            .unwrap_or_else(|| "void".to_string());
+        Some(Type::new(format!("{} *", pointee_name), byte_size))
+    } else if let Some(&qualified) = qualifiers.get(&offset) {
+        resolve_type(qualified, pointers, qualifiers, offset_to_type)
+    } else {
+        offset_to_type.get(&offset).cloned()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum DebugValue {
     Str(String),
@@ -1,12 +1,15 @@
 use addr2line::gimli::Register;
+use libc::user_regs_struct;
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::signal::Signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
-use std::convert::TryInto;
+use std::cell::{Cell, RefCell};
+use std::fs;
 use std::io;
 use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
 use std::process::Child;
 use std::process::Command;
 
@@ -23,6 +26,10 @@ pub enum Status {
     /// Indicates the inferior exited due to a signal. Contains the signal that killed the
     /// process.
     Signaled(signal::Signal),
+
+    /// Indicates the inferior called exec and is stopped at the start of the new program.
+    /// Contains the process ID and the new instruction pointer.
+    Exec(nix::unistd::Pid, usize),
 }
 
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
@@ -34,12 +41,42 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// Returns the offset of debug register `index` within `struct user`, for use with
+/// PTRACE_POKEUSER.
+fn debug_register_offset(index: usize) -> usize {
+    let user: libc::user = unsafe { std::mem::zeroed() };
+    let base = &user as *const libc::user as usize;
+    let debugreg = &user.u_debugreg as *const _ as usize;
+    debugreg - base + index * size_of::<libc::c_ulonglong>()
+}
+
+/// Upper bound on how many bytes read_cstring will read, in case the pointer is bad and there's
+/// no NUL byte in sight.
+const MAX_CSTRING_LEN: usize = 4096;
+
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
 
+/// A stack frame found by unwinding: an address inside the frame's function and the frame's
+/// base pointer.
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub rip: usize,
+    pub rbp: usize,
+}
+
 pub struct Inferior {
-    child: Child,
+    /// The process we started, or None if we attached to a process that was already running
+    child: Option<Child>,
+    pid: Pid,
+    /// Every thread we're tracing, starting with the main one
+    threads: RefCell<Vec<Pid>>,
+    /// Threads announced by a clone event whose initial SIGSTOP we haven't seen yet
+    unstarted_threads: RefCell<Vec<Pid>>,
+    /// The thread that last stopped, which registers and memory are accessed through. The other
+    /// threads keep running while it is stopped.
+    current_tid: Cell<Pid>,
 }
 
 impl Inferior {
@@ -52,83 +89,343 @@ impl Inferior {
             proc_cmd.pre_exec(child_traceme);
         }
         let child = proc_cmd.args(args.iter()).spawn().ok()?;
-        Some(Inferior { child: child })
+        let pid = Pid::from_raw(child.id() as i32);
+        let mut inferior = Inferior::with_pid(Some(child), pid);
+        // The child stops with SIGTRAP once it has exec'd the target. From then on, ask to hear
+        // about any further exec, since that replaces the memory our breakpoints live in, and
+        // about any new threads, which are traced automatically once we ask for clone events.
+        let options = ptrace::Options::PTRACE_O_TRACEEXEC | ptrace::Options::PTRACE_O_TRACECLONE;
+        let traced = match inferior.wait(None) {
+            Ok(Status::Stopped(_, Signal::SIGTRAP, _)) => {
+                ptrace::setoptions(inferior.pid(), options).is_ok()
+            }
+            _ => false,
+        };
+        if !traced {
+            let _ = inferior.child.as_mut().unwrap().kill();
+            return None;
+        }
+        Some(inferior)
+    }
+
+    /// Attaches to a process that is already running, leaving it stopped just like a freshly
+    /// started inferior.
+    pub fn attach(pid: Pid) -> Result<Inferior, nix::Error> {
+        ptrace::attach(pid)?;
+        let inferior = Inferior::with_pid(None, pid);
+        // PTRACE_ATTACH sends the process a SIGSTOP, which we have to wait for
+        match inferior.wait(None)? {
+            Status::Stopped(_, Signal::SIGSTOP, _) => {}
+            _ => return Err(nix::Error::Sys(nix::errno::Errno::ESRCH)),
+        }
+        ptrace::setoptions(pid, ptrace::Options::PTRACE_O_TRACEEXEC)?;
+        Ok(inferior)
+    }
+
+    fn with_pid(child: Option<Child>, pid: Pid) -> Inferior {
+        Inferior {
+            child,
+            pid,
+            threads: RefCell::new(vec![pid]),
+            unstarted_threads: RefCell::new(Vec::new()),
+            current_tid: Cell::new(pid),
+        }
+    }
+
+    /// Returns whether we attached to this process rather than starting it ourselves.
+    pub fn is_attached(&self) -> bool {
+        self.child.is_none()
+    }
+
+    /// Stops tracing the inferior and lets it carry on running without us.
+    pub fn detach(&mut self) -> Result<(), nix::Error> {
+        ptrace::detach(self.pid, None)
+    }
+
+    /// Returns the path of the program the inferior is currently running, which changes if it
+    /// calls exec.
+    pub fn exe_path(&self) -> io::Result<PathBuf> {
+        fs::read_link(format!("/proc/{}/exe", self.pid()))
     }
 
     /// Returns the pid of this inferior.
     pub fn pid(&self) -> Pid {
-        nix::unistd::Pid::from_raw(self.child.id() as i32)
+        self.pid
+    }
+
+    /// Returns the id of the thread that last stopped.
+    pub fn tid(&self) -> Pid {
+        self.current_tid.get()
+    }
+
+    /// Returns whether the inferior has started any threads besides the main one.
+    pub fn is_multithreaded(&self) -> bool {
+        self.threads.borrow().len() > 1
     }
 
     pub fn continue_run(&self, signal: Option<Signal>) -> Result<Status, nix::Error> {
-        let _ = nix::sys::ptrace::cont(self.pid(), signal);
+        let _ = nix::sys::ptrace::cont(self.tid(), signal);
         self.wait(None)
     }
 
+    /// Executes a single machine instruction in the current thread and waits for it to stop
+    /// again. Whatever other threads do in the meantime is left queued up for later waits, so
+    /// that e.g. a thread being started doesn't run past the breakpoint we're stepping over.
+    pub fn step(&self) -> Result<Status, nix::Error> {
+        ptrace::step(self.tid(), None)?;
+        self.wait_for(None, Some(self.tid()))
+    }
+
+    /// Returns the inferior's general-purpose registers.
+    pub fn get_registers(&self) -> Result<user_regs_struct, nix::Error> {
+        ptrace::getregs(self.tid())
+    }
+
+    /// Returns the inferior's instruction pointer. After a breakpoint trap this is one byte past
+    /// the breakpoint's 0xcc.
+    pub fn get_rip(&self) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(self.tid())?.rip as usize)
+    }
+
+    /// Moves the inferior's instruction pointer to the given address.
+    pub fn set_rip(&self, rip: usize) -> Result<(), nix::Error> {
+        let mut regs = ptrace::getregs(self.tid())?;
+        regs.rip = rip as u64;
+        ptrace::setregs(self.tid(), regs)
+    }
+
+    /// Returns the address the inferior was accessing when it got the signal it is stopped with,
+    /// e.g. the bad pointer behind a SIGSEGV.
+    pub fn get_fault_address(&self) -> Result<usize, nix::Error> {
+        let siginfo = ptrace::getsiginfo(self.tid())?;
+        Ok(unsafe { siginfo.si_addr() } as usize)
+    }
+
+    /// Kills the inferior and reaps all of its threads, so that nothing about it is left for
+    /// whoever waits next (e.g. a new inferior seeing the old threads exit).
     pub fn kill(&mut self) -> io::Result<()> {
         println!("Killing running inferior (pid {})", self.pid());
-        self.child.kill()
+        let result = match self.child.as_mut() {
+            Some(child) => child.kill(),
+            None => signal::kill(self.pid, Signal::SIGKILL)
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
+        };
+        // The main thread is only reaped once the others are, including any we haven't heard
+        // about yet, so take whatever comes until it's the main thread's turn
+        while let Ok(status) = waitpid(None, Some(WaitPidFlag::__WALL)) {
+            match status {
+                WaitStatus::Exited(pid, _) | WaitStatus::Signaled(pid, _, _) if pid == self.pid => {
+                    break
+                }
+                _ => {}
+            }
+        }
+        result
     }
 
     pub fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
         let aligned_addr = align_addr_to_word(addr);
         let byte_offset = addr - aligned_addr;
-        let word = ptrace::read(self.pid(), aligned_addr as ptrace::AddressType)? as u64;
+        let word = ptrace::read(self.tid(), aligned_addr as ptrace::AddressType)? as u64;
         let orig_byte = (word >> 8 * byte_offset) & 0xff;
         let masked_word = word & !(0xff << 8 * byte_offset);
         let updated_word = masked_word | ((val as u64) << 8 * byte_offset);
         ptrace::write(
-            self.pid(),
+            self.tid(),
             aligned_addr as ptrace::AddressType,
             updated_word as *mut std::ffi::c_void,
         )?;
         Ok(orig_byte as u8)
     }
 
-    pub fn print_backtrace(&self, debug_data: &Option<DwarfData>) -> Result<(), nix::Error> {
-        match ptrace::getregs(self.pid()) {
-            Ok(reg) => match debug_data.as_ref() {
-                Some(data) => {
-                    let mut rip = reg.rip.try_into().unwrap();
-                    let mut rbp = reg.rbp.try_into().unwrap();
-
-                    loop {
-                        let func_line = data.get_line_from_addr(rip);
-                        let func_name = data.get_function_from_addr(rip);
-                        println!(
-                            "{} ({})",
-                            func_name.as_ref().unwrap(),
-                            func_line.as_ref().unwrap()
-                        );
-                        if func_name.as_ref().unwrap() == "main" {
-                            break;
-                        }
-                        rip = ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType)? as usize;
-                        rbp = ptrace::read(self.pid(), rbp as ptrace::AddressType)? as usize;
-                    }
-                }
-                None => {
-                    return Err(nix::Error::UnsupportedOperation);
+    /// Writes `bytes` into the inferior's memory starting at `addr`.
+    pub fn write_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), nix::Error> {
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.write_byte(addr + offset, *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Reads `len` bytes of the inferior's memory starting at `addr`.
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut curr_addr = addr;
+        while bytes.len() < len {
+            let word = ptrace::read(self.tid(), curr_addr as ptrace::AddressType)? as u64;
+            let remaining = len - bytes.len();
+            bytes.extend_from_slice(&word.to_le_bytes()[..remaining.min(size_of::<usize>())]);
+            curr_addr += size_of::<usize>();
+        }
+        Ok(bytes)
+    }
+
+    fn write_debug_register(&self, index: usize, value: u64) -> Result<(), nix::Error> {
+        let ret = unsafe {
+            libc::ptrace(
+                libc::PTRACE_POKEUSER,
+                self.tid().as_raw(),
+                debug_register_offset(index) as *mut libc::c_void,
+                value as *mut libc::c_void,
+            )
+        };
+        if ret == -1 {
+            Err(nix::Error::last())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Programs the hardware debug registers so that the inferior traps after writing to any of
+    /// the given `(address, length)` regions. Lengths must be 1, 2, 4 or 8, and at most four
+    /// regions are supported.
+    pub fn set_hw_watchpoints(&self, regions: &[(usize, usize)]) -> Result<(), nix::Error> {
+        let mut dr7: u64 = 0;
+        for (slot, (addr, len)) in regions.iter().enumerate().take(4) {
+            self.write_debug_register(slot, *addr as u64)?;
+            let len_bits: u64 = match len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                _ => 0b11,
+            };
+            // Local enable bit, break on data writes (RW = 01), and the watched length
+            dr7 |= 1 << (slot * 2);
+            dr7 |= 0b01 << (16 + slot * 4);
+            dr7 |= len_bits << (18 + slot * 4);
+        }
+        self.write_debug_register(7, dr7)
+    }
+
+    /// Reads a NUL-terminated C string starting at `addr`. Invalid UTF-8 is replaced rather than
+    /// rejected, and strings longer than MAX_CSTRING_LEN are truncated.
+    pub fn read_cstring(&self, addr: usize) -> Result<String, nix::Error> {
+        let mut bytes = Vec::new();
+        let mut curr_addr = addr;
+        'words: while bytes.len() < MAX_CSTRING_LEN {
+            let word = ptrace::read(self.tid(), curr_addr as ptrace::AddressType)? as u64;
+            for byte in word.to_le_bytes().iter() {
+                if *byte == 0 || bytes.len() == MAX_CSTRING_LEN {
+                    break 'words;
                 }
-            },
-            Err(err) => {
-                eprintln!("{}", err);
+                bytes.push(*byte);
+            }
+            curr_addr += size_of::<usize>();
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Walks the chain of saved frame pointers starting from the current stack frame, stopping
+    /// at main (or at the first frame we don't have debugging symbols for). For every frame
+    /// after the first, `rip` points one byte before the return address, i.e. into the call
+    /// instruction, so that it resolves to the caller's line.
+    pub fn unwind_frames(&self, debug_data: &DwarfData) -> Result<Vec<Frame>, nix::Error> {
+        let regs = ptrace::getregs(self.tid())?;
+        let mut rip = regs.rip as usize;
+        let mut rbp = regs.rbp as usize;
+        let mut frames = Vec::new();
+        loop {
+            frames.push(Frame { rip, rbp });
+            let func_name = debug_data.get_function_from_addr(rip);
+            if func_name.is_none() || func_name.as_deref() == Some("main") || rbp == 0 {
+                break;
             }
+            rip = ptrace::read(self.tid(), (rbp + 8) as ptrace::AddressType)? as usize - 1;
+            rbp = ptrace::read(self.tid(), rbp as ptrace::AddressType)? as usize;
+        }
+        Ok(frames)
+    }
+
+    pub fn print_backtrace(&self, debug_data: &Option<DwarfData>) -> Result<(), nix::Error> {
+        let data = debug_data
+            .as_ref()
+            .ok_or(nix::Error::UnsupportedOperation)?;
+        for frame in self.unwind_frames(data)? {
+            println!(
+                "{} ({})",
+                data.get_function_from_addr(frame.rip)
+                    .unwrap_or_else(|| "??".to_string()),
+                data.get_line_from_addr(frame.rip)
+                    .map(|line| line.to_string())
+                    .unwrap_or_else(|| "??".to_string())
+            );
         }
         Ok(())
     }
 
-    /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
-    /// after the waitpid call.
+    /// Waits for the inferior to stop or exit and returns a Status to indicate the state of the
+    /// process afterwards. Threads starting and exiting are reported and handled here without
+    /// returning; a thread that stops for any other reason becomes the current thread.
     pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
-            WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
-            WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
-            WaitStatus::Stopped(pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
-                Status::Stopped(pid, signal, regs.rip as usize)
+        self.wait_for(options, None)
+    }
+
+    /// Like wait, but if `thread` is given, only that thread is waited for (unless it exits).
+    fn wait_for(
+        &self,
+        options: Option<WaitPidFlag>,
+        mut thread: Option<Pid>,
+    ) -> Result<Status, nix::Error> {
+        // __WALL is needed to hear from threads, which aren't children in the usual sense
+        let flags = options.unwrap_or_else(WaitPidFlag::empty) | WaitPidFlag::__WALL;
+        loop {
+            match waitpid(thread, Some(flags))? {
+                WaitStatus::Exited(pid, exit_code) if pid == self.pid => {
+                    return Ok(Status::Exited(exit_code))
+                }
+                WaitStatus::Signaled(pid, signal, _core_dumped) if pid == self.pid => {
+                    return Ok(Status::Signaled(signal))
+                }
+                WaitStatus::Exited(tid, _) | WaitStatus::Signaled(tid, _, _) => {
+                    self.threads.borrow_mut().retain(|&t| t != tid);
+                    println!("[Thread {} exited]", tid);
+                    // If it was the thread we were waiting for, nothing more will come from it
+                    thread = None;
+                }
+                WaitStatus::PtraceEvent(tid, _signal, event)
+                    if event == libc::PTRACE_EVENT_CLONE =>
+                {
+                    let new_tid = Pid::from_raw(ptrace::getevent(tid)? as i32);
+                    // The new thread's initial stop can be reported before or after this event
+                    if !self.threads.borrow().contains(&new_tid) {
+                        self.add_thread(new_tid);
+                        self.unstarted_threads.borrow_mut().push(new_tid);
+                    }
+                    ptrace::cont(tid, None)?;
+                }
+                WaitStatus::Stopped(tid, Signal::SIGSTOP) if tid != self.pid => {
+                    let announced = self.threads.borrow().contains(&tid);
+                    let unstarted = self.unstarted_threads.borrow().contains(&tid);
+                    if announced && !unstarted {
+                        // A real SIGSTOP sent to a thread that's already running
+                        self.current_tid.set(tid);
+                        return Ok(Status::Stopped(tid, Signal::SIGSTOP, self.get_rip()?));
+                    }
+                    if !announced {
+                        self.add_thread(tid);
+                    }
+                    self.unstarted_threads.borrow_mut().retain(|&t| t != tid);
+                    ptrace::cont(tid, None)?;
+                }
+                WaitStatus::Stopped(tid, signal) => {
+                    self.current_tid.set(tid);
+                    return Ok(Status::Stopped(tid, signal, self.get_rip()?));
+                }
+                WaitStatus::PtraceEvent(pid, _signal, event)
+                    if event == libc::PTRACE_EVENT_EXEC =>
+                {
+                    // exec kills every other thread and carries on in the main one
+                    *self.threads.borrow_mut() = vec![self.pid];
+                    self.unstarted_threads.borrow_mut().clear();
+                    self.current_tid.set(self.pid);
+                    return Ok(Status::Exec(pid, self.get_rip()?));
+                }
+                other => panic!("waitpid returned unexpected status: {:?}", other),
             }
-            other => panic!("waitpid returned unexpected status: {:?}", other),
-        })
+        }
+    }
+
+    fn add_thread(&self, tid: Pid) {
+        self.threads.borrow_mut().push(tid);
+        println!("[New thread {}]", tid);
     }
 }
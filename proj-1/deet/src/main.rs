@@ -8,17 +8,48 @@ use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use std::env;
 
+fn usage(program: &str) -> ! {
+    println!(
+        "Usage: {} [--history-file <path>] [--breakpoints-file <path>] [--ex <command>]... \
+         <target program>",
+        program
+    );
+    std::process::exit(1);
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
-        std::process::exit(1);
+    let mut target: Option<&String> = None;
+    let mut history_file: Option<String> = None;
+    let mut breakpoints_file: Option<String> = None;
+    let mut startup_commands: Vec<String> = Vec::new();
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--history-file" => match arg_iter.next() {
+                Some(path) => history_file = Some(path.to_string()),
+                None => usage(&args[0]),
+            },
+            "--breakpoints-file" => match arg_iter.next() {
+                Some(path) => breakpoints_file = Some(path.to_string()),
+                None => usage(&args[0]),
+            },
+            "--ex" => match arg_iter.next() {
+                Some(command) => startup_commands.push(command.to_string()),
+                None => usage(&args[0]),
+            },
+            _ if target.is_none() => target = Some(arg),
+            _ => usage(&args[0]),
+        }
     }
-    let target = &args[1];
+    let target = match target {
+        Some(target) => target,
+        None => usage(&args[0]),
+    };
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    Debugger::new(target, history_file, breakpoints_file, startup_commands).run();
 }
@@ -0,0 +1,157 @@
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+static BUILD_SAMPLES: Once = Once::new();
+static NEXT_SESSION: AtomicUsize = AtomicUsize::new(0);
+
+/// How long a deet session may run before we decide it's stuck and kill it.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Compiles the sample programs with the Makefile, once per test run.
+pub fn build_samples() {
+    BUILD_SAMPLES.call_once(|| {
+        let status = Command::new("make")
+            .arg("-s")
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .status()
+            .expect("Could not run make");
+        assert!(status.success(), "Building the samples failed");
+    });
+}
+
+/// Returns a path in the temp directory that no other session in this test run uses.
+pub fn temp_path(name: &str) -> PathBuf {
+    let session = NEXT_SESSION.fetch_add(1, Ordering::SeqCst);
+    std::env::temp_dir().join(format!(
+        "deet-test-{}-{}-{}",
+        std::process::id(),
+        session,
+        name
+    ))
+}
+
+/// How deet prints a line of one of the samples, e.g. "/path/to/deet/samples/loop.c:6".
+pub fn source_line(sample: &str, line: usize) -> String {
+    format!(
+        "{}/samples/{}.c:{}",
+        env!("CARGO_MANIFEST_DIR"),
+        sample,
+        line
+    )
+}
+
+/// Everything a deet session printed.
+pub struct Output {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Output {
+    /// Returns whether either stream contains `text`.
+    pub fn contains(&self, text: &str) -> bool {
+        self.stdout.contains(text) || self.stderr.contains(text)
+    }
+
+    /// Returns the address deet reported setting breakpoint `id` at.
+    pub fn breakpoint_address(&self, id: usize) -> usize {
+        let prefix = format!("Set breakpoint {} at 0x", id);
+        let line = self.stdout.lines().find(|line| line.starts_with(&prefix));
+        let line = line.unwrap_or_else(|| panic!("Breakpoint {} was not set: {:?}", id, self));
+        usize::from_str_radix(&line[prefix.len()..], 16).unwrap()
+    }
+
+    /// Counts the occurrences of `text` on stdout.
+    #[allow(dead_code)]
+    pub fn count(&self, text: &str) -> usize {
+        self.stdout.matches(text).count()
+    }
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stdout:\n{}\nstderr:\n{}", self.stdout, self.stderr)
+    }
+}
+
+/// Debugs `samples/<sample>`, typing each of `commands` at the prompt. The session ends when the
+/// commands run out, just like pressing ctrl+d.
+pub fn run_deet(sample: &str, commands: &[&str]) -> Output {
+    run_deet_with_args(&[], sample, commands)
+}
+
+/// Like run_deet, but passes `args` to deet before the target. History and breakpoints are kept
+/// in files of their own unless `args` says otherwise, so sessions don't affect each other.
+pub fn run_deet_with_args(args: &[&str], sample: &str, commands: &[&str]) -> Output {
+    let mut cmd = deet_command();
+    if !args.contains(&"--history-file") {
+        cmd.arg("--history-file").arg(temp_path("history"));
+    }
+    if !args.contains(&"--breakpoints-file") {
+        cmd.arg("--breakpoints-file").arg(temp_path("breakpoints"));
+    }
+    cmd.args(args);
+    run_session(cmd, sample, commands)
+}
+
+/// A command running deet from the crate directory, for tests that need to set it up themselves.
+/// The samples are built first.
+pub fn deet_command() -> Command {
+    build_samples();
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_deet"));
+    cmd.current_dir(env!("CARGO_MANIFEST_DIR"));
+    cmd
+}
+
+/// Runs `cmd` (from deet_command) on `samples/<sample>`, typing each of `commands` at the prompt.
+pub fn run_session(mut cmd: Command, sample: &str, commands: &[&str]) -> Output {
+    let mut child = cmd
+        .arg(format!("samples/{}", sample))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Could not start deet");
+
+    let mut input = String::new();
+    for command in commands {
+        input.push_str(command);
+        input.push('\n');
+    }
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .expect("Could not send commands to deet");
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let stdout_reader = thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stdout.read_to_string(&mut text);
+        text
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    });
+
+    let start = Instant::now();
+    while child.try_wait().expect("Error waiting for deet").is_none() {
+        if start.elapsed() > SESSION_TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            panic!("deet did not finish running {:?}", commands);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Output {
+        stdout: stdout_reader.join().unwrap(),
+        stderr: stderr_reader.join().unwrap(),
+    }
+}
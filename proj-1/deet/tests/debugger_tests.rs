@@ -0,0 +1,1123 @@
+mod common;
+
+use common::{
+    build_samples, deet_command, run_deet, run_deet_with_args, run_session, source_line, temp_path,
+};
+use std::fs;
+use std::process::{Command, Stdio};
+
+/// next should move one source line at a time without entering calls, while step goes into them.
+#[test]
+fn test_next_and_step() {
+    let output = run_deet(
+        "function_calls",
+        &["break func2", "run", "next", "next", "next", "next"],
+    );
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 0 at func2 ({})",
+            source_line("function_calls", 10)
+        )),
+        "{:?}",
+        output
+    );
+    for line in 11..=14 {
+        assert!(
+            output.contains(&format!(
+                "Stopped at func2 ({})",
+                source_line("function_calls", line)
+            )),
+            "next did not reach line {}: {:?}",
+            line,
+            output
+        );
+    }
+    assert!(
+        !output.contains("Stopped at func3"),
+        "next entered func3: {:?}",
+        output
+    );
+
+    let output = run_deet(
+        "function_calls",
+        &["break func2", "run", "next", "next", "next", "step"],
+    );
+    assert!(
+        output.contains("Stopped at func3"),
+        "step did not enter func3: {:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("func3 ({})", source_line("function_calls", 6))),
+        "{:?}",
+        output
+    );
+}
+
+/// Deleting the breakpoint we're stopped at has to leave the inferior able to carry on from it.
+#[test]
+fn test_delete_breakpoint() {
+    let output = run_deet(
+        "function_calls",
+        &["break func2", "run", "delete 0", "continue", "delete 0"],
+    );
+    assert!(output.contains("Deleted breakpoint 0"), "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+    assert!(output.contains("No breakpoint number 0"), "{:?}", output);
+
+    // Two breakpoints at one address share a trap, which has to stay until both are deleted
+    let output = run_deet(
+        "function_calls",
+        &[
+            "break func3",
+            "break func3",
+            "run",
+            "delete 1",
+            "continue",
+            "delete 0",
+            "continue",
+        ],
+    );
+    assert!(output.contains("Hit breakpoint 1 at func3"), "{:?}", output);
+    assert!(output.contains("Hit breakpoint 0 at func3"), "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+}
+
+/// Breakpoints on a function stop once its prologue has stored the parameters, so they can be
+/// printed and used in conditions.
+#[test]
+fn test_function_breakpoint_arguments() {
+    let output = run_deet(
+        "function_calls",
+        &["break func2", "run", "print a", "print b"],
+    );
+    assert_eq!(output.count("a = 42"), 2, "{:?}", output);
+    assert_eq!(output.count("b = 5"), 2, "{:?}", output);
+
+    // func1 and func2 both call func3(100)
+    let output = run_deet(
+        "function_calls",
+        &["break func3 if a == 100", "run", "continue", "continue"],
+    );
+    assert_eq!(output.count("Hit breakpoint 0 at func3"), 2, "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+
+    let output = run_deet("function_calls", &["break func3 if a != 100", "run"]);
+    assert!(!output.contains("Hit breakpoint"), "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+}
+
+/// print shows locals, parameters and globals visible from where the inferior stopped.
+#[test]
+fn test_print_variables() {
+    let output = run_deet(
+        "function_calls",
+        &[
+            "break 12",
+            "run",
+            "print sum",
+            "print a",
+            "print global",
+            "print nope",
+        ],
+    );
+    assert!(output.contains("sum = 47"), "{:?}", output);
+    assert!(output.contains("a = 42"), "{:?}", output);
+    assert!(output.contains("global = 5"), "{:?}", output);
+    assert!(
+        output.contains("No symbol \"nope\" in current context."),
+        "{:?}",
+        output
+    );
+}
+
+/// Continuing after a signal, where there is no breakpoint to step over, lets the inferior carry on.
+#[test]
+fn test_continue_after_signal() {
+    let output = run_deet("signal", &["run", "continue"]);
+    assert!(output.contains("Child stopped by SIGUSR1"), "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+    assert!(!output.contains("panicked"), "{:?}", output);
+
+    let output = run_deet("signal", &["continue"]);
+    assert!(
+        output.contains("Error no subprocess is running!"),
+        "{:?}",
+        output
+    );
+}
+
+/// list shows the source around where the inferior stopped, marking the current line.
+#[test]
+fn test_list() {
+    let output = run_deet("function_calls", &["break func2", "run", "list"]);
+    assert!(
+        output.contains("->   10\t    printf(\"func2(%d, %d) was called\\n\", a, b);"),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains("      5\tvoid func3(int a) {"),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("     15\t"), "{:?}", output);
+    assert!(!output.contains("     16\t"), "{:?}", output);
+}
+
+/// info breakpoints lists every breakpoint, and shows whether it's installed in a running inferior.
+#[test]
+fn test_info_breakpoints() {
+    let output = run_deet(
+        "function_calls",
+        &[
+            "info breakpoints",
+            "break func2",
+            "break 19",
+            "info breakpoints",
+            "run",
+            "info breakpoints",
+        ],
+    );
+    assert!(output.contains("No breakpoints."), "{:?}", output);
+    for active in &["no", "yes"] {
+        assert!(
+            output.contains(&format!(
+                "0\t{:#018x}\t{}\tfunc2 ({})",
+                output.breakpoint_address(0),
+                active,
+                source_line("function_calls", 10)
+            )),
+            "{:?}",
+            output
+        );
+        assert!(
+            output.contains(&format!(
+                "1\t{:#018x}\t{}\tfunc1 ({})",
+                output.breakpoint_address(1),
+                active,
+                source_line("function_calls", 19)
+            )),
+            "{:?}",
+            output
+        );
+    }
+}
+
+/// Once the inferior has exited its breakpoints are no longer active, and new ones are just
+/// remembered for the next run.
+#[test]
+fn test_info_breakpoints_after_exit() {
+    let output = run_deet(
+        "loop",
+        &["break 4", "run", "continue", "break 6", "info breakpoints"],
+    );
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+    assert!(!output.contains("could not write"), "{:?}", output);
+    for (id, line) in &[(0, 4), (1, 6)] {
+        assert!(
+            output.contains(&format!(
+                "{}\t{:#018x}\tno\tmain ({})",
+                id,
+                output.breakpoint_address(*id),
+                source_line("loop", *line)
+            )),
+            "{:?}",
+            output
+        );
+    }
+}
+
+/// The exit status and the fatal signal reported are the inferior's real ones.
+#[test]
+fn test_exit_status() {
+    let output = run_deet("exit_code", &["run"]);
+    assert!(output.contains("Child exited (status 42)"), "{:?}", output);
+
+    let output = run_deet("segfault", &["run", "continue SIGSEGV"]);
+    assert!(
+        output.contains("Child exited due to SIGSEGV (11)"),
+        "{:?}",
+        output
+    );
+}
+
+/// Running again starts a fresh process, whose breakpoints have to work just like the first one's.
+#[test]
+fn test_run_twice() {
+    let output = run_deet(
+        "function_calls",
+        &["break func3", "run", "run", "continue", "continue"],
+    );
+    assert_eq!(output.count("Hit breakpoint 0 at func3"), 3, "{:?}", output);
+    assert_eq!(output.count("Hello from func3! 100"), 2, "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+}
+
+/// registers shows rip as the address of the breakpoint the inferior is stopped at.
+#[test]
+fn test_registers() {
+    let output = run_deet(
+        "function_calls",
+        &["break func2", "registers", "run", "registers"],
+    );
+    assert!(
+        output.contains("Error no subprocess is running!"),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("rip     {:#018x}", output.breakpoint_address(0))),
+        "{:?}",
+        output
+    );
+    for name in &["rsp", "rbp", "rax", "r15", "eflags"] {
+        assert!(output.contains(&format!("{:<8}0x", name)), "{:?}", output);
+    }
+}
+
+/// A breakpoint on a function that doesn't exist is rejected without ending the session.
+#[test]
+fn test_break_unknown_function() {
+    let output = run_deet(
+        "function_calls",
+        &["break nonexistent_fn", "break func2", "run"],
+    );
+    assert!(
+        output.contains("Could not resolve breakpoint location \"nonexistent_fn\""),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("Hit breakpoint 0 at func2"), "{:?}", output);
+    assert!(!output.contains("panicked"), "{:?}", output);
+}
+
+/// continue can deliver a signal, given by name or number, which the inferior's handler catches.
+#[test]
+fn test_continue_with_signal() {
+    for signal in &["SIGUSR1", "usr1", "10"] {
+        let output = run_deet(
+            "signal",
+            &[
+                "break 10",
+                "run",
+                &format!("continue {}", signal),
+                "continue",
+            ],
+        );
+        // A plain continue discards the signal the inferior then raises itself, so the handler
+        // only runs for the one we delivered
+        assert_eq!(output.count("Caught signal 10"), 1, "{:?}", output);
+        assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+    }
+}
+
+/// Starting up prints nothing, while info functions lists the target's functions.
+#[test]
+fn test_info_functions() {
+    let output = run_deet("function_calls", &[]);
+    assert_eq!(output.stdout, "", "{:?}", output);
+
+    let output = run_deet("function_calls", &["info functions"]);
+    for (func, lines) in &[("func3", "5-7"), ("func2", "9-14"), ("main", "23-25")] {
+        assert!(
+            output.contains(&format!(
+                "{} (samples/function_calls.c, lines {}, at 0x",
+                func, lines
+            )),
+            "{:?}",
+            output
+        );
+    }
+}
+
+/// A watchpoint on a global stops the inferior each time it's written, showing both values.
+#[test]
+fn test_watch() {
+    let output = run_deet(
+        "counter",
+        &[
+            "break main",
+            "run",
+            "watch counter",
+            "continue",
+            "continue",
+            "continue",
+            "continue",
+        ],
+    );
+    assert!(output.contains("Watchpoint 0: counter (0x"), "{:?}", output);
+    for (old, new) in &[(0, 10), (10, 20), (20, 30)] {
+        assert!(
+            output.contains(&format!(
+                "Watchpoint 0: counter\nOld value = {}\nNew value = {}\n",
+                old, new
+            )),
+            "{:?}",
+            output
+        );
+    }
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+}
+
+/// Without HOME, history and breakpoints are kept in the temp directory, unless files are given.
+#[test]
+fn test_dotfiles_without_home() {
+    let temp_dir = temp_path("tmp");
+    fs::create_dir(&temp_dir).unwrap();
+    let mut cmd = deet_command();
+    cmd.env_remove("HOME").env("TMPDIR", &temp_dir);
+    let output = run_session(cmd, "function_calls", &["break func2", "run"]);
+    assert!(output.contains("Hit breakpoint 0 at func2"), "{:?}", output);
+    assert!(!output.contains("panicked"), "{:?}", output);
+    let history = fs::read_to_string(temp_dir.join(".deet_history")).unwrap();
+    assert!(history.contains("break func2"), "{}", history);
+    assert!(temp_dir.join(".deet_breakpoints").exists());
+    fs::remove_dir_all(&temp_dir).unwrap();
+
+    let history_file = temp_path("history");
+    let history_arg = history_file.to_string_lossy();
+    run_deet_with_args(
+        &["--history-file", &history_arg],
+        "function_calls",
+        &["list"],
+    );
+    let history = fs::read_to_string(&history_file).unwrap();
+    assert!(history.contains("list"), "{}", history);
+    fs::remove_file(&history_file).unwrap();
+}
+
+/// frame, up and down select the frame that print and list then look at.
+#[test]
+fn test_select_frame() {
+    let output = run_deet(
+        "function_calls",
+        &[
+            "break func3",
+            "run",
+            "frame 1",
+            "list",
+            "print a",
+            "up",
+            "down",
+            "down",
+            "down",
+        ],
+    );
+    assert!(
+        output.contains(&format!("#1 func2 ({})", source_line("function_calls", 13))),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("->   13\t    func3(100);"), "{:?}", output);
+    assert!(output.contains("a = 42"), "{:?}", output);
+    assert!(
+        output.contains(&format!("#2 func1 ({})", source_line("function_calls", 18))),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("#0 func3 ({})", source_line("function_calls", 6))),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains("Bottom (innermost) frame selected; you cannot go down."),
+        "{:?}",
+        output
+    );
+}
+
+/// Char pointers, const or not, are printed along with the string they point to.
+#[test]
+fn test_print_strings() {
+    let output = run_deet(
+        "strings",
+        &[
+            "break 10",
+            "run",
+            "print greeting",
+            "print mutable_greeting",
+            "print name",
+            "print answer",
+        ],
+    );
+    assert!(
+        output.contains("greeting = 0x") && output.contains(" \"Hello, deet!\"\n"),
+        "{:?}",
+        output
+    );
+    assert!(output.contains(" \"Hello again!\"\n"), "{:?}", output);
+    assert!(output.contains(" \"world\"\n"), "{:?}", output);
+    assert!(output.contains("answer = 42"), "{:?}", output);
+}
+
+/// Continuing once the inferior has exited says so rather than touching the dead process.
+#[test]
+fn test_continue_after_exit() {
+    let output = run_deet("hello", &["run", "continue"]);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+    assert!(output.contains("Child is not running"), "{:?}", output);
+}
+
+/// Signals are reported by name, with the number alongside.
+#[test]
+fn test_signal_names() {
+    let output = run_deet("segfault", &["run"]);
+    assert!(
+        output.contains("Child stopped by SIGSEGV (11)"),
+        "{:?}",
+        output
+    );
+}
+
+/// kill ends the inferior but not the session.
+#[test]
+fn test_kill() {
+    let output = run_deet(
+        "function_calls",
+        &["break func2", "run", "kill", "continue", "kill", "run"],
+    );
+    assert!(output.contains("Killed inferior (pid "), "{:?}", output);
+    assert!(
+        output.contains("Error no subprocess is running!"),
+        "{:?}",
+        output
+    );
+    assert_eq!(output.count("Hit breakpoint 0 at func2"), 2, "{:?}", output);
+}
+
+/// Killing an inferior that already exited keeps its breakpoints, which can still be deleted.
+#[test]
+fn test_kill_after_exit() {
+    let output = run_deet(
+        "function_calls",
+        &["break func2", "run", "continue", "kill", "delete 0", "run"],
+    );
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+    assert!(output.contains("Child is not running"), "{:?}", output);
+    assert!(output.contains("Deleted breakpoint 0"), "{:?}", output);
+    assert_eq!(output.count("Hit breakpoint 0 at func2"), 1, "{:?}", output);
+    assert!(!output.contains("panicked"), "{:?}", output);
+}
+
+/// examine dumps words of memory, showing what's under our breakpoints rather than their traps.
+#[test]
+fn test_examine() {
+    let addr = run_deet("function_calls", &["break func2"]).breakpoint_address(0);
+    let examine = format!("examine {:#x} 2", addr);
+    let at_breakpoint = run_deet("function_calls", &["break func2", "run", &examine]);
+    let elsewhere = run_deet("function_calls", &["break func1", "run", &examine]);
+    let dump = |output: &common::Output| {
+        let prefix = format!("{:#x}: 0x", addr);
+        let line = output.stdout.lines().find(|line| line.starts_with(&prefix));
+        line.unwrap_or_else(|| panic!("No memory dump: {:?}", output))
+            .to_string()
+    };
+    assert_eq!(dump(&at_breakpoint), dump(&elsewhere));
+    assert_eq!(dump(&at_breakpoint).split(' ').count(), 3);
+
+    let output = run_deet(
+        "function_calls",
+        &[
+            "break func2",
+            "run",
+            "examine 0x0",
+            "examine 12345",
+            "examine nope",
+        ],
+    );
+    assert!(
+        output.contains("0x0: <unreadable> <unreadable> <unreadable> <unreadable>"),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("0x3039: <unreadable>"), "{:?}", output);
+    assert!(output.contains("Invalid address nope"), "{:?}", output);
+
+    let output = run_deet("function_calls", &["run", "print global"]);
+    assert!(
+        output.contains("The program is not being run."),
+        "{:?}",
+        output
+    );
+}
+
+/// file:line breakpoints find the line in the named file of a program built from several.
+#[test]
+fn test_break_file_line() {
+    let output = run_deet(
+        "multifile",
+        &[
+            "break add.c:2",
+            "break multifile.c:7",
+            "run",
+            "print a",
+            "continue",
+            "print total",
+        ],
+    );
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 0 at add ({})",
+            source_line("multifile_lib/add", 2)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("a = 2"), "{:?}", output);
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 1 at main ({})",
+            source_line("multifile", 7)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("total = 5"), "{:?}", output);
+
+    let output = run_deet("multifile", &["break nope.c:2", "break add.c:nope"]);
+    assert!(
+        output.contains("Could not resolve breakpoint location \"nope.c:2\""),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains("Could not resolve breakpoint location \"add.c:nope\""),
+        "{:?}",
+        output
+    );
+}
+
+/// finish runs until the selected function returns to its caller.
+#[test]
+fn test_finish() {
+    let output = run_deet(
+        "function_calls",
+        &[
+            "break func3",
+            "run",
+            "finish",
+            "finish",
+            "continue",
+            "continue",
+        ],
+    );
+    assert!(output.contains("Run till exit from func3"), "{:?}", output);
+    assert!(
+        output.contains(&format!(
+            "Stopped at func2 ({})",
+            source_line("function_calls", 14)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!(
+            "Stopped at func1 ({})",
+            source_line("function_calls", 19)
+        )),
+        "{:?}",
+        output
+    );
+    // The return breakpoints are gone again, so the rest runs as normal
+    assert_eq!(output.count("Hit breakpoint 0 at func3"), 2, "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+
+    let output = run_deet("function_calls", &["break main", "run", "finish"]);
+    assert!(
+        output.contains("\"finish\" not meaningful in the outermost frame."),
+        "{:?}",
+        output
+    );
+}
+
+/// break without a location is rejected rather than taken apart.
+#[test]
+fn test_break_without_location() {
+    let output = run_deet("function_calls", &["b", "break", "run"]);
+    assert!(
+        output.contains("Ambiguous command \"b\": backtrace, break."),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains("Unrecognized command. Type \"help\" for a list of commands."),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+}
+
+/// Commands can be shortened to any unambiguous prefix, or to gdb's usual abbreviations.
+#[test]
+fn test_abbreviations() {
+    let output = run_deet(
+        "function_calls",
+        &["b func2", "r", "bt", "c", "bt", "d", "help"],
+    );
+    assert!(output.contains("Set breakpoint 0 at"), "{:?}", output);
+    assert!(output.contains("Hit breakpoint 0 at func2"), "{:?}", output);
+    assert!(
+        output.contains(&format!("main ({})", source_line("function_calls", 24))),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+    assert!(output.contains("Child is not running"), "{:?}", output);
+    assert!(
+        output.contains("Ambiguous command \"d\": delete, down."),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains("break <location> [if <cond>]  Set a breakpoint"),
+        "{:?}",
+        output
+    );
+}
+
+/// A trap leaves rip one past the breakpoint, which is how the breakpoint that was hit is found.
+#[test]
+fn test_break_address() {
+    let addr = run_deet("function_calls", &["break func2"]).breakpoint_address(0);
+    let location = format!("break {:#x}", addr);
+    let output = run_deet("function_calls", &[&location, "run", "registers"]);
+    assert_eq!(output.breakpoint_address(0), addr);
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 0 at func2 ({})",
+            source_line("function_calls", 10)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("rip     {:#018x}", addr)),
+        "{:?}",
+        output
+    );
+}
+
+/// Breakpoints are saved when deet exits and set again in the next session on the same target.
+#[test]
+fn test_saved_breakpoints() {
+    let breakpoints_file = temp_path("breakpoints");
+    let args = ["--breakpoints-file", &breakpoints_file.to_string_lossy()];
+    run_deet_with_args(
+        &args,
+        "function_calls",
+        &["break func3 if a == 100", "break 12"],
+    );
+    let output = run_deet_with_args(&args, "function_calls", &["info breakpoints", "run"]);
+    assert!(output.contains("Set breakpoint 0 at"), "{:?}", output);
+    assert!(output.contains("stop only if a == 100"), "{:?}", output);
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 1 at func2 ({})",
+            source_line("function_calls", 12)
+        )),
+        "{:?}",
+        output
+    );
+
+    // A saved breakpoint whose function has gone is dropped, and the rest are kept
+    let saved = fs::read_to_string(&breakpoints_file).unwrap();
+    fs::write(&breakpoints_file, saved.replace("func3", "no_such_func")).unwrap();
+    let output = run_deet_with_args(&args, "function_calls", &["info breakpoints"]);
+    assert!(
+        output.contains("Dropping saved breakpoint at \"no_such_func\""),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("func2 ({})", source_line("function_calls", 12))),
+        "{:?}",
+        output
+    );
+    fs::remove_file(&breakpoints_file).unwrap();
+}
+
+/// Exec is reported with the process that did it. Breakpoints are put back when the inferior
+/// execs the target again, and we warn that they're gone when it execs something else.
+#[test]
+fn test_exec() {
+    let output = run_deet(
+        "exec",
+        &[
+            "break main",
+            "run samples/exec",
+            "continue",
+            "continue",
+            "continue",
+            "continue",
+        ],
+    );
+    // Both execs are by the one process, whichever pid it got
+    let exec_report = output
+        .stdout
+        .lines()
+        .find(|line| line.starts_with("Process "))
+        .expect("deet did not report an exec");
+    let pid = exec_report.split(' ').nth(1).unwrap();
+    assert!(pid.parse::<u32>().is_ok(), "{:?}", output);
+    assert_eq!(
+        output.count(&format!("Process {} exec'd a new program", pid)),
+        2,
+        "{:?}",
+        output
+    );
+    assert_eq!(output.count("Hit breakpoint 0 at main"), 2, "{:?}", output);
+    assert!(
+        output.contains(&format!(
+            "Warning: the inferior exec'd {}/samples/hello; breakpoints are not set in it",
+            env!("CARGO_MANIFEST_DIR")
+        )),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("Hello world!"), "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+}
+
+/// Stopping at a function's breakpoint shows its arguments, whether they're still in registers at
+/// its first instruction or already stored in its frame past the prologue.
+#[test]
+fn test_arguments_at_function_breakpoint() {
+    let output = run_deet("function_calls", &["whereis func2"]);
+    let entry = output
+        .stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("func2 is defined at samples/function_calls.c:9, "))
+        .and_then(|rest| rest.strip_prefix("starting at 0x"))
+        .unwrap_or_else(|| panic!("whereis did not find func2: {:?}", output));
+    let location = format!("break 0x{}", entry);
+    for commands in &[["break func2", "run"], [location.as_str(), "run"]] {
+        let output = run_deet("function_calls", commands);
+        assert!(output.contains("Hit breakpoint 0 at func2"), "{:?}", output);
+        assert!(output.contains("\na = 42\nb = 5\n"), "{:?}", output);
+    }
+}
+
+/// attach debugs a process that's already running, and quitting leaves it running untraced.
+#[test]
+fn test_attach() {
+    build_samples();
+    let mut target = Command::new(format!(
+        "{}/samples/sleepy_print",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .arg("30")
+    .stdout(Stdio::null())
+    .spawn()
+    .expect("Could not start samples/sleepy_print");
+    let pid = target.id();
+    let output = run_deet(
+        "sleepy_print",
+        &[
+            &format!("attach {}", pid),
+            "break 12",
+            "continue",
+            "continue",
+        ],
+    );
+    let still_running = target.try_wait().unwrap().is_none();
+    // The state follows the command name, which is in parentheses; "t" is stopped by a tracer
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+    let state = stat.rsplit(") ").next().unwrap_or("").chars().next();
+    let _ = target.kill();
+    let _ = target.wait();
+
+    assert!(
+        output.contains(&format!("Attached to process {}", pid)),
+        "{:?}",
+        output
+    );
+    assert_eq!(
+        output.count(&format!(
+            "Hit breakpoint 0 at main ({})",
+            source_line("sleepy_print", 12)
+        )),
+        2,
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("Detached from process {}", pid)),
+        "{:?}",
+        output
+    );
+    assert!(still_running, "{:?}", output);
+    assert_ne!(state, Some('t'), "{}", stat);
+}
+
+/// Stepping over or into a libc call, which has no line info, comes back out in our own code.
+#[test]
+fn test_step_over_library_call() {
+    let output = run_deet("count", &["break main", "run", "step", "step", "next"]);
+    for line in 5..=7 {
+        assert!(
+            output.contains(&format!("Stopped at main ({})", source_line("count", line))),
+            "{:?}",
+            output
+        );
+    }
+    assert!(!output.contains("Stopped at 0x"), "{:?}", output);
+}
+
+/// `until` runs the rest of a loop without stopping on each iteration.
+#[test]
+fn test_until() {
+    let output = run_deet("loop", &["break 5", "run", "until 8", "print total"]);
+    assert_eq!(output.count("Hit breakpoint 0"), 1, "{:?}", output);
+    assert!(
+        output.contains(&format!("Stopped at main ({})", source_line("loop", 8))),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("total = 45"), "{:?}", output);
+}
+
+/// `set var` changes a local, and the program carries on with the new value.
+#[test]
+fn test_set_variable() {
+    let output = run_deet(
+        "loop",
+        &[
+            "break 6",
+            "run",
+            "set var total = 100",
+            "set nope = 1",
+            "set total = 99999999999",
+            "delete 0",
+            "continue",
+        ],
+    );
+    assert!(output.contains("total = 100"), "{:?}", output);
+    assert!(output.contains("total = 145"), "{:?}", output);
+    assert!(
+        output.contains("No symbol \"nope\" in current context."),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains("99999999999 does not fit in total (int)"),
+        "{:?}",
+        output
+    );
+}
+
+/// Stops at a breakpoint name the breakpoint; stops that deet asked for itself (a step, or
+/// running until a line) just say where the inferior is, without blaming a SIGTRAP.
+#[test]
+fn test_stop_reasons() {
+    let output = run_deet(
+        "loop",
+        &[
+            "break 4", "break 6", "run", "continue", "next", "delete 1", "until 8",
+        ],
+    );
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 0 at main ({})",
+            source_line("loop", 4)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 1 at main ({})",
+            source_line("loop", 6)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("Stopped at main ({})", source_line("loop", 8))),
+        "{:?}",
+        output
+    );
+    assert!(!output.contains("SIGTRAP"), "{:?}", output);
+}
+
+/// Each thread that hits a breakpoint is named, and killing a threaded inferior to run it again
+/// doesn't leave its threads to be reported as exiting from the new one.
+#[test]
+fn test_threads() {
+    let output = run_deet(
+        "threads",
+        &["break worker", "run", "run", "continue", "continue"],
+    );
+    let (first_run, second_run) = output
+        .stdout
+        .split_at(output.stdout.find("Killing running inferior").unwrap());
+    let tids: Vec<&str> = second_run
+        .lines()
+        .filter(|line| line.contains(" hit breakpoint 0 at worker"))
+        .map(|line| line.split(' ').nth(1).unwrap())
+        .collect();
+    assert_eq!(tids.len(), 2, "{:?}", output);
+    assert_ne!(tids[0], tids[1], "{:?}", output);
+    assert!(output.contains("all workers done"), "{:?}", output);
+
+    let old_tids = first_run
+        .lines()
+        .filter_map(|line| line.strip_prefix("[New thread "))
+        .map(|line| line.trim_end_matches(']'));
+    for tid in old_tids {
+        assert!(
+            !second_run.contains(&format!("[Thread {} exited]", tid)),
+            "{:?}",
+            output
+        );
+    }
+}
+
+/// Commands given with --ex run before the prompt, so the first thing typed there already sees
+/// the inferior stopped at the breakpoint they set.
+#[test]
+fn test_startup_commands() {
+    let output = run_deet_with_args(&["--ex", "break 6", "--ex", "run"], "loop", &["print i"]);
+    assert!(output.contains("(deet) break 6"), "{:?}", output);
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 0 at main ({})",
+            source_line("loop", 6)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("i = 0"), "{:?}", output);
+}
+
+/// Without debugging symbols deet warns that only address breakpoints work, refuses the others
+/// and still stops at an address, here the program's entry point from its ELF header.
+#[test]
+fn test_stripped_binary() {
+    build_samples();
+    let elf = fs::read(format!(
+        "{}/samples/hello_stripped",
+        env!("CARGO_MANIFEST_DIR")
+    ))
+    .unwrap();
+    let mut entry_bytes = [0; 8];
+    entry_bytes.copy_from_slice(&elf[24..32]);
+    let entry = u64::from_le_bytes(entry_bytes);
+
+    let output = run_deet(
+        "hello_stripped",
+        &[
+            "break main",
+            &format!("break {:#x}", entry),
+            "run",
+            "continue",
+        ],
+    );
+    assert!(output.contains("has no debugging symbols"), "{:?}", output);
+    assert!(
+        output.contains("No debugging symbols, so \"main\" can't be resolved"),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!("Hit breakpoint 0 at {:#x}", entry)),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("Hello world!"), "{:?}", output);
+    assert!(output.contains("Child exited (status 0)"), "{:?}", output);
+}
+
+/// `whereis` says where a function is defined and where its code starts, which is a little
+/// before a breakpoint on it (those go past the prologue).
+#[test]
+fn test_whereis() {
+    let output = run_deet("loop", &["break main", "whereis main", "whereis nope"]);
+    let prefix = "main is defined at samples/loop.c:3, starting at 0x";
+    let line = output.stdout.lines().find(|line| line.starts_with(prefix));
+    let line = line.unwrap_or_else(|| panic!("{:?}", output));
+    let start = usize::from_str_radix(&line[prefix.len()..], 16).unwrap();
+    let breakpoint = output.breakpoint_address(0);
+    assert!(
+        start < breakpoint && breakpoint - start < 0x10,
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains("No function named \"nope\""),
+        "{:?}",
+        output
+    );
+}
+
+/// A segfault reports the bad address and which function the faulting instruction is in.
+#[test]
+fn test_segfault_address() {
+    let output = run_deet("segfault", &["run"]);
+    assert!(
+        output.contains("Invalid memory access at address 0x0"),
+        "{:?}",
+        output
+    );
+    assert!(output.contains(" is in func2+0x"), "{:?}", output);
+    assert!(
+        output.contains(&format!(
+            "Stopped at func2 ({})",
+            source_line("segfault", 5)
+        )),
+        "{:?}",
+        output
+    );
+}
+
+/// Functions that are only declared, like printf and add in the file that calls it, have no code
+/// and aren't offered as places to break.
+#[test]
+fn test_declared_functions() {
+    let output = run_deet(
+        "multifile",
+        &["info functions", "whereis printf", "break add", "run"],
+    );
+    assert!(!output.contains("at 0x0"), "{:?}", output);
+    assert!(
+        output.contains("No function named \"printf\""),
+        "{:?}",
+        output
+    );
+    assert!(
+        output.contains(&format!(
+            "Hit breakpoint 0 at add ({}/samples/multifile_lib/add.c:2)",
+            env!("CARGO_MANIFEST_DIR")
+        )),
+        "{:?}",
+        output
+    );
+}
+
+/// `next` runs a recursive call to completion too, stopping in the frame it started in rather
+/// than in the callee or in a deeper call returning to the same place.
+#[test]
+fn test_next_over_recursive_call() {
+    let output = run_deet(
+        "recursion",
+        &[
+            "break 7",
+            "run",
+            "delete 0",
+            "next",
+            "print n",
+            "print rest",
+        ],
+    );
+    assert!(
+        output.contains(&format!(
+            "Stopped at factorial ({})",
+            source_line("recursion", 8)
+        )),
+        "{:?}",
+        output
+    );
+    assert!(output.contains("n = 5"), "{:?}", output);
+    assert!(output.contains("rest = 24"), "{:?}", output);
+}
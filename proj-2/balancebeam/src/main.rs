@@ -1,16 +1,25 @@
 mod request;
 mod response;
+mod tls;
 
 use clap::Parser;
+use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
+use tokio::sync::Notify;
 use tokio::sync::RwLock;
-use tokio::time::sleep;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
@@ -20,20 +29,255 @@ struct CmdOptions {
     /// "IP/port to bind to"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
     bind: String,
-    /// "Upstream host to forward requests to"
+    /// "Upstream host to forward requests to (host:port, or unix:PATH for a Unix socket)"
     #[arg(short, long)]
     upstream: Vec<String>,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
+    /// "Randomly shorten or lengthen each health check interval by up to this many percent"
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(0..=50))]
+    health_check_jitter: u64,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
-    /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
+    /// "Maximum number of requests to accept per IP per minute, or per --rate-limit-window-secs
+    /// (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "Length of the window that --max-requests-per-minute counts requests over, in seconds"
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "60",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    rate_limit_window_secs: u64,
+    /// "How --max-requests-per-minute is enforced"
+    #[arg(long, value_enum, default_value = "sliding")]
+    rate_limit_algorithm: RateLimitAlgorithm,
+    /// "PEM certificate chain to serve to clients; enables TLS (requires --tls-key)"
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+    /// "PEM private key for --tls-cert"
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+    /// "Connect to upstream servers over TLS"
+    #[arg(long)]
+    upstream_tls: bool,
+    /// "Skip certificate verification for TLS upstreams (for testing only)"
+    #[arg(long, requires = "upstream_tls")]
+    upstream_tls_insecure: bool,
+    /// "Replace the Host header with the upstream address (original kept in X-Forwarded-Host)"
+    #[arg(long)]
+    rewrite_host: bool,
+    /// "Send requests under PREFIX to their own upstream pool (PREFIX=host:port,host:port)"
+    #[arg(long, value_parser = parse_route)]
+    route: Vec<Route>,
+    /// "Answer requests for this path with the active upstreams as JSON (loopback clients only)"
+    #[arg(long)]
+    admin_path: Option<String>,
+    /// "Let loopback clients stop and restart new requests to an upstream with
+    /// POST /_admin/drain?upstream=host:port and POST /_admin/undrain?upstream=host:port"
+    #[arg(long)]
+    allow_drain: bool,
+    /// "Close client connections that send no request for this many seconds"
+    #[arg(long)]
+    client_idle_timeout: Option<u64>,
+    /// "Allow clients to open TCP tunnels to arbitrary hosts with CONNECT"
+    #[arg(long)]
+    allow_connect: bool,
+    /// "Answer OPTIONS * ourselves with the methods we support instead of forwarding it"
+    #[arg(long)]
+    handle_options: bool,
+    /// "Times to wait for an active upstream before failing a request (default: no limit)"
+    #[arg(long)]
+    no_upstream_retry_limit: Option<usize>,
+    /// "Seconds to wait between checks for an active upstream"
+    #[arg(long, default_value = "3")]
+    no_upstream_retry_delay: u64,
+    /// "Gzip text responses for clients that accept it"
+    #[arg(long)]
+    enable_compression: bool,
+    /// "Check that the upstreams resolve and pass a health check, then exit"
+    #[arg(long)]
+    check_config: bool,
+    /// "Don't add the client's IP to X-Forwarded-For on requests sent upstream"
+    #[arg(long)]
+    no_forwarded_for: bool,
+    /// "Remove any X-Forwarded-For header the client sent before forwarding"
+    #[arg(long)]
+    strip_forwarded_for: bool,
+    /// "Report how long the upstream took to respond in an X-Upstream-Time-Ms response header"
+    #[arg(long)]
+    add_timing_header: bool,
+    /// "Maximum number of simultaneous connections per client IP (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    max_connections_per_ip: usize,
+    /// "Maximum number of simultaneous connections in total (0 = unlimited). Further connections
+    /// wait until one closes"
+    #[arg(long, default_value = "0")]
+    max_connections: usize,
+    /// "Turn connections beyond --max-connections away with a 503 instead of making them wait"
+    #[arg(long)]
+    reject_when_full: bool,
+    /// "Log a warning for request or response bodies larger than this many bytes"
+    #[arg(long)]
+    log_large_threshold: Option<usize>,
+    /// "Ramp traffic to an upstream up over this many seconds after it comes back (0 = off)"
+    #[arg(long, default_value = "0")]
+    slow_start_seconds: u64,
+    /// "Tag each request with a unique ID in this header, forwarded upstream and echoed back"
+    #[arg(long, value_name = "HEADER", value_parser = parse_header_name)]
+    inject_request_id: Option<http::header::HeaderName>,
+    /// "Send only one of several identical GET requests arriving together upstream, and share its
+    /// response"
+    #[arg(long)]
+    coalesce_requests: bool,
+    /// "Retry requests carrying an Idempotency-Key (e.g. POSTs) on another upstream, replaying
+    /// each key at most once in this many seconds (0 = off)"
+    #[arg(long, value_name = "SECONDS", default_value = "0")]
+    idempotency_key_ttl: u64,
+    /// "Bytes to read at a time from request and response bodies"
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "512",
+        value_parser = parse_read_buffer_size
+    )]
+    read_buffer_size: usize,
+    /// "Try the active upstreams in a freshly shuffled order for each connection, instead of
+    /// picking one at random for every attempt"
+    #[arg(long)]
+    shuffle_upstreams: bool,
+    /// "Tell upstreams the client's address by sending a PROXY protocol v1 header on each
+    /// connection"
+    #[arg(long)]
+    send_proxy_protocol: bool,
+    /// "Replace FROM with TO in text response bodies (FROM=TO; may be repeated, applied in order)"
+    #[arg(long, value_name = "FROM=TO", value_parser = parse_rewrite_rule)]
+    rewrite_body: Vec<RewriteRule>,
+    /// "Remove this header from client requests before forwarding them (case-insensitive; a
+    /// trailing * matches any header starting with the rest, as in X-Admin-*; may be repeated)"
+    #[arg(long, value_name = "NAME")]
+    strip_request_header: Vec<String>,
+    /// "Remove this header from upstream responses before returning them (matched like
+    /// --strip-request-header; may be repeated)"
+    #[arg(long, value_name = "NAME")]
+    strip_response_header: Vec<String>,
+}
+
+/// Ways of enforcing --max-requests-per-minute.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum RateLimitAlgorithm {
+    /// Count the requests in the last window. Exact, but remembers every request in the window.
+    Sliding,
+    /// Allow bursts of up to the limit, refilling steadily over the window. Constant memory per IP.
+    TokenBucket,
+}
+
+/// Requests whose path is under `prefix` are sent to one of `upstreams` instead of the default
+/// pool.
+#[derive(Clone, Debug)]
+struct Route {
+    prefix: String,
+    upstreams: Vec<String>,
+}
+
+impl Route {
+    /// Returns whether `path` is under this route's prefix. The prefix has to end at a path
+    /// segment boundary, so that /api covers /api and /api/users but not /apiary.
+    fn matches(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+fn parse_route(spec: &str) -> Result<Route, String> {
+    let (prefix, upstreams) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected PREFIX=host:port[,host:port...], got {}", spec))?;
+    if !prefix.starts_with('/') {
+        return Err(format!("route prefix {} must start with /", prefix));
+    }
+    let upstreams: Vec<String> = upstreams
+        .split(',')
+        .filter(|upstream| !upstream.is_empty())
+        .map(|upstream| upstream.to_string())
+        .collect();
+    if upstreams.is_empty() {
+        return Err(format!("route {} has no upstreams", prefix));
+    }
+    Ok(Route {
+        prefix: prefix.to_string(),
+        upstreams,
+    })
+}
+
+/// A literal find/replace applied to text response bodies.
+#[derive(Clone, Debug)]
+struct RewriteRule {
+    from: String,
+    to: String,
+}
+
+fn parse_rewrite_rule(spec: &str) -> Result<RewriteRule, String> {
+    let (from, to) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("expected FROM=TO, got {}", spec))?;
+    if from.is_empty() {
+        return Err("the text to replace can't be empty".to_string());
+    }
+    Ok(RewriteRule {
+        from: from.to_string(),
+        to: to.to_string(),
+    })
+}
+
+fn parse_read_buffer_size(size: &str) -> Result<usize, String> {
+    let size: usize = size
+        .parse()
+        .map_err(|_| format!("{} is not a number of bytes", size))?;
+    if size < MIN_READ_BUFFER_SIZE {
+        return Err(format!("must be at least {} bytes", MIN_READ_BUFFER_SIZE));
+    }
+    Ok(size)
+}
+
+fn parse_header_name(name: &str) -> Result<http::header::HeaderName, String> {
+    http::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("{} is not a valid header name", name))
 }
 
+/// Longest a failing upstream goes without a health check, in health check intervals
+const MAX_BACKOFF_INTERVALS: u32 = 32;
+
+/// Smallest --read-buffer-size we accept; anything less just means many more reads per body
+const MIN_READ_BUFFER_SIZE: usize = 64;
+
+/// How often clients that have gone quiet are dropped from the rate limiting maps
+const RATE_LIMIT_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Seconds clients are told to wait (in Retry-After) while we are in maintenance mode
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 30;
+
+/// Path that stops new requests going to an upstream, with --allow-drain
+const DRAIN_PATH: &str = "/_admin/drain";
+
+/// Path that lets a drained upstream take new requests again, with --allow-drain
+const UNDRAIN_PATH: &str = "/_admin/undrain";
+
+/// Upstreams given as unix:PATH are reached over a Unix domain socket rather than TCP
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// A connection we can proxy HTTP over, e.g. a plain TCP connection or a TLS session on top of
+/// one.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
 ///
@@ -42,16 +286,296 @@ struct CmdOptions {
 struct ProxyState {
     /// How frequently we check whether upstream servers are alive (Milestone 4)
     active_health_check_interval: usize,
+    /// Maximum percentage by which a health check interval is randomly adjusted
+    health_check_jitter: u64,
     /// Where we should send requests when doing active health checks (Milestone 4)
     active_health_check_path: String,
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     #[allow(dead_code)]
     max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
+    /// Addresses of servers that we are proxying to when no route matches
     upstream_addresses: Vec<String>,
-    /// Active servers
+    /// Path prefixes that are proxied to their own set of upstreams
+    routes: Vec<Route>,
+    /// Active servers, across the default pool and every route
     active_upstream_addresses: Arc<RwLock<Vec<String>>>,
+    /// How --max-requests-per-minute is enforced
+    rate_limit_algorithm: RateLimitAlgorithm,
+    /// Window that --max-requests-per-minute counts requests over
+    rate_limit_window: Duration,
+    /// Times of each IP's requests in the last window, for the sliding window limiter
     request_state: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+    /// Tokens left for each IP and when they were last topped up, for the token bucket limiter
+    token_buckets: Arc<Mutex<HashMap<String, (f64, Instant)>>>,
+    /// Consecutive health check failures per upstream, and when to probe it next
+    upstream_backoff: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+    /// Set when upstream connections should be wrapped in TLS
+    upstream_tls: Option<TlsConnector>,
+    /// Whether to point the Host header of forwarded requests at the upstream
+    rewrite_host: bool,
+    /// Path served by balancebeam itself, listing the active upstreams
+    admin_path: Option<String>,
+    /// Whether the drain and undrain admin endpoints are answered
+    allow_drain: bool,
+    /// Upstreams that get no new requests, though connections already open to them carry on
+    drained_upstreams: Arc<Mutex<HashSet<String>>>,
+    /// Whether OPTIONS * is answered by balancebeam rather than passed to an upstream
+    handle_options: bool,
+    /// How long we wait for a client to send its next request before hanging up
+    client_idle_timeout: Option<Duration>,
+    /// Whether CONNECT requests are tunneled (otherwise they are refused)
+    allow_connect: bool,
+    /// How many times a request waits for an upstream to become active (None = forever)
+    no_upstream_retry_limit: Option<usize>,
+    /// How long each of those waits is
+    no_upstream_retry_delay: Duration,
+    /// Whether to gzip responses for clients that accept it
+    enable_compression: bool,
+    /// Whether to leave the client's IP out of X-Forwarded-For
+    no_forwarded_for: bool,
+    /// Whether to drop X-Forwarded-For values supplied by the client
+    strip_forwarded_for: bool,
+    /// Whether to tell clients how long the upstream took, in X-Upstream-Time-Ms
+    add_timing_header: bool,
+    /// Maximum number of open connections per client IP (0 = unlimited)
+    max_connections_per_ip: usize,
+    /// Open connections per client IP. This is a std Mutex since it is released from Drop.
+    connection_counts: Arc<std::sync::Mutex<HashMap<String, usize>>>,
+    /// Body size above which requests and responses are logged as unusually large
+    log_large_threshold: Option<usize>,
+    /// How long an upstream that comes back up takes to reach its full share of requests
+    slow_start: Duration,
+    /// When each upstream still in its slow start period came back up
+    upstream_healthy_since: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Upstreams that answered 503 with a Retry-After, and when they may be picked again
+    upstream_unavailable_until: Arc<Mutex<HashMap<String, Instant>>>,
+    /// Header that carries a per-request correlation ID, if we should add one
+    inject_request_id: Option<http::header::HeaderName>,
+    /// Set while we are out of rotation: requests get a 503 instead of being forwarded
+    maintenance: Arc<AtomicBool>,
+    /// Whether identical concurrent GETs share a single upstream request
+    coalesce_requests: bool,
+    /// GETs currently on their way to an upstream, by coalescing key. This is a std Mutex since
+    /// entries are removed from Drop.
+    in_flight_requests: Arc<std::sync::Mutex<HashMap<String, Arc<InFlightRequest>>>>,
+    /// How long a replayed Idempotency-Key is remembered (zero = such requests aren't replayed)
+    idempotency_key_ttl: Duration,
+    /// Idempotency keys whose requests were replayed on another upstream, and when
+    replayed_idempotency_keys: Arc<Mutex<HashMap<String, Instant>>>,
+    /// How many bytes of a request or response body are read at a time
+    read_buffer_size: usize,
+    /// Whether connections go through a shuffled list of upstreams rather than random picks
+    shuffle_upstreams: bool,
+    /// Whether upstream connections start with a PROXY protocol header
+    send_proxy_protocol: bool,
+    /// Find/replace rules applied, in order, to text response bodies
+    rewrite_body: Vec<RewriteRule>,
+    /// Lowercased names (or prefixes ending in *) of headers dropped from forwarded requests
+    strip_request_headers: Vec<String>,
+    /// Lowercased names (or prefixes ending in *) of headers dropped from upstream responses
+    strip_response_headers: Vec<String>,
+}
+
+impl ProxyState {
+    /// Every upstream we know about, across the default pool and all routes.
+    fn all_upstream_addresses(&self) -> Vec<String> {
+        let mut addresses = self.upstream_addresses.clone();
+        for route in self.routes.iter() {
+            for upstream in route.upstreams.iter() {
+                if !addresses.contains(upstream) {
+                    addresses.push(upstream.clone());
+                }
+            }
+        }
+        addresses
+    }
+
+    /// Returns the route with the longest prefix matching `path`, if any.
+    fn route_for(&self, path: &str) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.matches(path))
+            .max_by_key(|route| route.prefix.len())
+    }
+}
+
+/// Counts a client connection against its IP's limit for as long as the guard is alive.
+struct ConnectionGuard {
+    state: Arc<ProxyState>,
+    client_ip: String,
+}
+
+impl ConnectionGuard {
+    /// Registers a new connection from `client_ip`, or returns None if that IP already has
+    /// --max-connections-per-ip connections open.
+    fn acquire(state: &Arc<ProxyState>, client_ip: &str) -> Option<ConnectionGuard> {
+        let mut counts = state.connection_counts.lock().unwrap();
+        let count = counts.entry(client_ip.to_string()).or_insert(0);
+        if state.max_connections_per_ip != 0 && *count >= state.max_connections_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            state: state.clone(),
+            client_ip: client_ip.to_string(),
+        })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.state.connection_counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.client_ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.client_ip);
+            }
+        }
+    }
+}
+
+/// A GET that has been sent upstream on behalf of several clients. The first client to ask (the
+/// leader) forwards it; the others wait on `done` and then copy `response`.
+#[derive(Default)]
+struct InFlightRequest {
+    done: Notify,
+    /// Set by the leader once it has the upstream's response. Still None when `done` fires if
+    /// the leader failed.
+    response: std::sync::Mutex<Option<http::Response<Vec<u8>>>>,
+}
+
+/// Held by the leader of an in-flight request. Dropping it, whether or not a response was
+/// published, wakes the followers and lets the next identical request start a new flight.
+struct FlightGuard {
+    state: Arc<ProxyState>,
+    key: String,
+    flight: Arc<InFlightRequest>,
+}
+
+impl FlightGuard {
+    /// Hands the upstream's response to the followers.
+    fn publish(self, response: &http::Response<Vec<u8>>) {
+        *self.flight.response.lock().unwrap() = Some(response::clone_response(response));
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.state
+            .in_flight_requests
+            .lock()
+            .unwrap()
+            .remove(&self.key);
+        self.flight.done.notify_waiters();
+    }
+}
+
+/// What a request joining the in-flight requests should do.
+enum Flight {
+    /// Nobody else is fetching this, so forward it and publish the response
+    Leader(FlightGuard),
+    /// Someone else fetched it. None if they failed, in which case we are on our own.
+    Follower(Option<http::Response<Vec<u8>>>),
+}
+
+/// Key under which concurrent requests are coalesced, or None if this request shouldn't be. Only
+/// bodiless GETs are safe to answer with another client's response, and only if they don't carry
+/// credentials (the response may be for that client alone) or ask to upgrade the connection.
+fn coalescing_key(state: &ProxyState, request: &http::Request<Vec<u8>>) -> Option<String> {
+    let headers = request.headers();
+    if !state.coalesce_requests
+        || request.method() != http::Method::GET
+        || !request.body().is_empty()
+        || headers.contains_key(http::header::AUTHORIZATION)
+        || headers.contains_key(http::header::COOKIE)
+        || headers.contains_key(http::header::UPGRADE)
+    {
+        return None;
+    }
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .map_or(Vec::new(), |host| host.as_bytes().to_vec());
+    Some(format!(
+        "{} {}",
+        String::from_utf8_lossy(&host),
+        request.uri()
+    ))
+}
+
+/// Readies a response for the client that asked for it: echoes its request ID, reports how long
+/// the upstream took, compresses the body if the client accepts that, and sets the version and
+/// Connection header for our connection with the client. A coalesced response goes through this
+/// once for each client it is shared with.
+fn finish_response(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+    request_id: Option<(&http::header::HeaderName, http::HeaderValue)>,
+    upstream_time: Duration,
+    client_version: http::Version,
+    close_requested: bool,
+) {
+    if state.add_timing_header {
+        response.headers_mut().insert(
+            "x-upstream-time-ms",
+            http::HeaderValue::from(upstream_time.as_millis() as u64),
+        );
+    }
+    if let Some((header_name, id)) = request_id {
+        response.headers_mut().insert(header_name, id);
+    }
+    compress_if_accepted(state, request, response);
+
+    // The upstream's own Connection header describes its connection with us, not ours with the
+    // client. An HTTP/1.0 client assumes we will hang up unless we say otherwise.
+    *response.version_mut() = client_version;
+    if close_requested {
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    } else if client_version == http::Version::HTTP_10 {
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("keep-alive"),
+        );
+    }
+}
+
+/// Becomes the leader for `key`, or waits for the current leader to finish and returns its
+/// response.
+async fn join_flight(state: &Arc<ProxyState>, key: &str) -> Flight {
+    let flight;
+    let done;
+    {
+        let mut in_flight_requests = state.in_flight_requests.lock().unwrap();
+        match in_flight_requests.get(key) {
+            Some(existing) => flight = existing.clone(),
+            None => {
+                let flight = Arc::new(InFlightRequest::default());
+                in_flight_requests.insert(key.to_string(), flight.clone());
+                return Flight::Leader(FlightGuard {
+                    state: state.clone(),
+                    key: key.to_string(),
+                    flight,
+                });
+            }
+        }
+        // Created while holding the lock, so the leader can't finish before we are listening
+        done = flight.done.notified();
+    }
+    done.await;
+    let response = flight.response.lock().unwrap();
+    Flight::Follower(response.as_ref().map(response::clone_response))
+}
+
+/// Connection to the upstream serving the route of the most recent request.
+struct UpstreamConnection {
+    stream: Box<dyn Stream>,
+    address: String,
+    /// Prefix of the route this upstream was picked for (None for the default pool)
+    route: Option<String>,
 }
 
 #[tokio::main]
@@ -66,31 +590,96 @@ async fn main() {
 
     // Parse the command line arguments passed to this program
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
+    if options.upstream.is_empty() && options.route.is_empty() {
+        log::error!(
+            "At least one upstream server must be specified using the --upstream or --route \
+            option."
+        );
         std::process::exit(1);
     }
 
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
-            std::process::exit(1);
-        }
+    // Set up TLS termination if a certificate was provided
+    let tls_acceptor = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert_path), Some(key_path)) => match tls::make_acceptor(cert_path, key_path) {
+            Ok(acceptor) => {
+                log::info!("Terminating TLS for client connections");
+                Some(acceptor)
+            }
+            Err(err) => {
+                log::error!("Could not load TLS certificate: {}", err);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
     };
-    log::info!("Listening for requests on {}", options.bind);
 
     // Handle incoming connections
     let state = Arc::new(ProxyState {
         upstream_addresses: options.upstream,
+        routes: options.route,
         active_health_check_interval: options.active_health_check_interval,
+        health_check_jitter: options.health_check_jitter,
         active_health_check_path: options.active_health_check_path,
         max_requests_per_minute: options.max_requests_per_minute,
         active_upstream_addresses: Arc::new(RwLock::new(Vec::new())),
+        rate_limit_algorithm: options.rate_limit_algorithm,
+        rate_limit_window: Duration::from_secs(options.rate_limit_window_secs),
         request_state: Arc::new(Mutex::new(HashMap::new())),
+        token_buckets: Arc::new(Mutex::new(HashMap::new())),
+        upstream_backoff: Arc::new(Mutex::new(HashMap::new())),
+        upstream_tls: if options.upstream_tls {
+            Some(tls::make_connector(options.upstream_tls_insecure))
+        } else {
+            None
+        },
+        rewrite_host: options.rewrite_host,
+        admin_path: options.admin_path,
+        allow_drain: options.allow_drain,
+        drained_upstreams: Arc::new(Mutex::new(HashSet::new())),
+        handle_options: options.handle_options,
+        client_idle_timeout: options.client_idle_timeout.map(Duration::from_secs),
+        allow_connect: options.allow_connect,
+        no_upstream_retry_limit: options.no_upstream_retry_limit,
+        no_upstream_retry_delay: Duration::from_secs(options.no_upstream_retry_delay),
+        enable_compression: options.enable_compression,
+        no_forwarded_for: options.no_forwarded_for,
+        strip_forwarded_for: options.strip_forwarded_for,
+        add_timing_header: options.add_timing_header,
+        max_connections_per_ip: options.max_connections_per_ip,
+        connection_counts: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        log_large_threshold: options.log_large_threshold,
+        slow_start: Duration::from_secs(options.slow_start_seconds),
+        upstream_healthy_since: Arc::new(Mutex::new(HashMap::new())),
+        upstream_unavailable_until: Arc::new(Mutex::new(HashMap::new())),
+        inject_request_id: options.inject_request_id,
+        maintenance: Arc::new(AtomicBool::new(false)),
+        coalesce_requests: options.coalesce_requests,
+        in_flight_requests: Arc::new(std::sync::Mutex::new(HashMap::new())),
+        idempotency_key_ttl: Duration::from_secs(options.idempotency_key_ttl),
+        replayed_idempotency_keys: Arc::new(Mutex::new(HashMap::new())),
+        read_buffer_size: options.read_buffer_size,
+        shuffle_upstreams: options.shuffle_upstreams,
+        send_proxy_protocol: options.send_proxy_protocol,
+        rewrite_body: options.rewrite_body,
+        strip_request_headers: lowercase_all(&options.strip_request_header),
+        strip_response_headers: lowercase_all(&options.strip_response_header),
     });
 
+    if options.check_config {
+        let num_reachable = check_config(&state).await;
+        std::process::exit(if num_reachable == 0 { 1 } else { 0 });
+    }
+
+    // Start listening for connections
+    let listener = match TcpListener::bind(&options.bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            log::error!("Could not bind to {}: {}", options.bind, err);
+            std::process::exit(1);
+        }
+    };
+    log::info!("Listening for requests on {}", options.bind);
+
     if !state.active_health_check_path.is_empty() {
         log::info!("Starting health check task");
         log::info!(
@@ -103,65 +692,195 @@ async fn main() {
         });
     }
 
+    if state.max_requests_per_minute != 0 {
+        let sweep_state = state.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(RATE_LIMIT_SWEEP_INTERVAL).await;
+                evict_stale_rate_limit_entries(&sweep_state).await;
+            }
+        });
+    }
+
+    // SIGUSR1 takes us in and out of maintenance mode
+    let mut maintenance_signal = match signal(SignalKind::user_defined1()) {
+        Ok(maintenance_signal) => maintenance_signal,
+        Err(err) => {
+            log::error!("Could not listen for SIGUSR1: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let maintenance = state.maintenance.clone();
+    tokio::spawn(async move {
+        while maintenance_signal.recv().await.is_some() {
+            let was_in_maintenance = maintenance.fetch_xor(true, Ordering::SeqCst);
+            if was_in_maintenance {
+                log::info!("Leaving maintenance mode");
+            } else {
+                log::info!("Entering maintenance mode, refusing requests with 503");
+            }
+        }
+    });
+
+    // Each connection holds a permit until its handler finishes
+    let connection_permits = if options.max_connections != 0 {
+        Some(Arc::new(Semaphore::new(options.max_connections)))
+    } else {
+        None
+    };
+
     log::info!("Starting to accept connections");
-    while let Ok((stream, _socked_addr)) = listener.accept().await {
+    while let Ok((stream, client_addr)) = listener.accept().await {
         let shared_state = state.clone();
+        let tls_acceptor = tls_acceptor.clone();
+        let permit = match &connection_permits {
+            None => None,
+            Some(permits) if options.reject_when_full => {
+                match permits.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        tokio::spawn(reject_connection(stream, client_addr, tls_acceptor));
+                        continue;
+                    }
+                }
+            }
+            Some(permits) => {
+                if permits.available_permits() == 0 {
+                    log::info!("At --max-connections, {} has to wait", client_addr);
+                }
+                Some(permits.clone().acquire_owned().await.unwrap())
+            }
+        };
+        // The address the client connected to, which may be more specific than what we bound to
+        let local_addr = match stream.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(err) => {
+                log::warn!("Could not get local address of connection: {}", err);
+                continue;
+            }
+        };
         tokio::spawn(async move {
-            handle_connection(stream, shared_state).await;
+            let _permit = permit;
+            match tls_acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(tls_stream) => {
+                        handle_connection(tls_stream, client_addr, local_addr, shared_state).await
+                    }
+                    Err(err) => log::info!("TLS handshake with {} failed: {}", client_addr, err),
+                },
+                None => handle_connection(stream, client_addr, local_addr, shared_state).await,
+            }
         });
     }
 }
 
+/// Implements --check-config: resolves every upstream and runs one round of health checks,
+/// printing a report. Returns how many upstreams are reachable.
+async fn check_config(state: &ProxyState) -> usize {
+    let upstream_addresses = state.all_upstream_addresses();
+    let mut num_reachable = 0;
+    for upstream_addr in upstream_addresses.iter() {
+        // Unix sockets are paths, so there is nothing to resolve
+        if !upstream_addr.starts_with(UNIX_SOCKET_PREFIX) {
+            match lookup_host(upstream_addr.as_str()).await {
+                Ok(resolved) => {
+                    let resolved: Vec<String> = resolved.map(|addr| addr.to_string()).collect();
+                    println!("{}: resolves to {}", upstream_addr, resolved.join(", "));
+                }
+                Err(err) => {
+                    println!("{}: could not resolve: {}", upstream_addr, err);
+                    continue;
+                }
+            }
+        }
+        if probe_upstream(state, upstream_addr).await {
+            println!("{}: healthy", upstream_addr);
+            num_reachable += 1;
+        } else {
+            println!("{}: health check failed", upstream_addr);
+        }
+    }
+    println!(
+        "{} of {} upstreams reachable",
+        num_reachable,
+        upstream_addresses.len()
+    );
+    num_reachable
+}
+
+/// Returns `interval` randomly shortened or lengthened by up to `jitter_percent` percent, so that
+/// balancebeam instances sharing upstreams don't all probe them at the same moment.
+fn jittered_interval(interval: Duration, jitter_percent: u64) -> Duration {
+    if jitter_percent == 0 {
+        return interval;
+    }
+    let max_jitter = jitter_percent as f64 / 100.0;
+    let jitter = rand::thread_rng().gen_range(-max_jitter..=max_jitter);
+    interval.mul_f64(1.0 + jitter)
+}
+
 async fn health_check(state: Arc<ProxyState>) {
+    let interval = Duration::from_secs(state.active_health_check_interval.try_into().unwrap());
+    // Upstreams that are up when we start are treated as established rather than slow started
+    let mut first_cycle = true;
     loop {
         log::info!("Starting health check cycle");
-        sleep(Duration::from_secs(
-            state.active_health_check_interval.try_into().unwrap(),
-        ))
-        .await;
-        let mut active_upstream_addresses = state.active_upstream_addresses.write().await;
-        active_upstream_addresses.clear();
-
-        for upstream_addr in state.upstream_addresses.iter() {
-            let request = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(&state.active_health_check_path)
-                .header("Host", upstream_addr)
-                .body(Vec::<u8>::new())
-                .expect("build http::Request failed!");
-
-            match TcpStream::connect(upstream_addr).await {
-                Ok(mut stream) => {
-                    if let Err(e) = request::write_to_stream(&request, &mut stream).await {
-                        log::warn!("Health check request to {} failed: {}", upstream_addr, e);
-                        return;
-                    }
-                    let response = response::read_from_stream(&mut stream, request.method()).await;
-                    match response {
-                        Ok(resp) => {
-                            if resp.status() == http::StatusCode::OK {
-                                log::info!("Upstream {} is healthy", upstream_addr);
-                                active_upstream_addresses.push(upstream_addr.clone());
-                            } else {
-                                log::warn!(
-                                    "Upstream {} returned status code {}",
-                                    upstream_addr,
-                                    resp.status()
-                                );
-                            }
-                        }
-                        Err(_) => {
-                            log::warn!("Health check response from {} failed", upstream_addr);
-                        }
-                    }
-                }
-                Err(err) => {
-                    log::warn!("Could not connect to {}: {}", upstream_addr, err);
+        sleep(jittered_interval(interval, state.health_check_jitter)).await;
+
+        let mut healthy_upstreams = Vec::new();
+        for upstream_addr in state.all_upstream_addresses().iter() {
+            // Upstreams that keep failing are probed less and less often
+            let now = Instant::now();
+            if let Some((failures, next_probe_time)) =
+                state.upstream_backoff.lock().await.get(upstream_addr)
+            {
+                // Jitter moves cycles around a little, so a cycle that comes slightly early still
+                // counts as the one the probe was due in
+                if now + interval / 2 < *next_probe_time {
+                    log::debug!(
+                        "Skipping health check of {} after {} consecutive failures",
+                        upstream_addr,
+                        failures
+                    );
                     continue;
                 }
             }
+
+            let healthy = probe_upstream(&state, upstream_addr).await;
+            let mut upstream_backoff = state.upstream_backoff.lock().await;
+            if healthy {
+                log::info!("Upstream {} is healthy", upstream_addr);
+                upstream_backoff.remove(upstream_addr);
+                healthy_upstreams.push(upstream_addr.clone());
+            } else {
+                let failures = upstream_backoff
+                    .get(upstream_addr)
+                    .map_or(1, |(failures, _)| failures + 1);
+                let backoff_intervals =
+                    2u32.saturating_pow(failures - 1).min(MAX_BACKOFF_INTERVALS);
+                upstream_backoff.insert(
+                    upstream_addr.clone(),
+                    (failures, now + interval * backoff_intervals),
+                );
+            }
         }
 
+        let mut active_upstream_addresses = state.active_upstream_addresses.write().await;
+        if !state.slow_start.is_zero() {
+            let mut healthy_since = state.upstream_healthy_since.lock().await;
+            let now = Instant::now();
+            healthy_since.retain(|upstream, since| {
+                healthy_upstreams.contains(upstream) && now - *since < state.slow_start
+            });
+            for upstream in healthy_upstreams.iter() {
+                if !first_cycle && !active_upstream_addresses.contains(upstream) {
+                    log::info!("Upstream {} is back, starting slow start", upstream);
+                    healthy_since.insert(upstream.clone(), now);
+                }
+            }
+        }
+        first_cycle = false;
+        *active_upstream_addresses = healthy_upstreams;
         log::info!(
             "Health check complete: {} active upstream servers",
             active_upstream_addresses.len()
@@ -169,63 +888,612 @@ async fn health_check(state: Arc<ProxyState>) {
     }
 }
 
-async fn read_upstream_addresses(state: &Arc<ProxyState>) -> (usize, String) {
+/// Sends a health check request to an upstream. Returns true if it answered 200 OK.
+async fn probe_upstream(state: &ProxyState, upstream_addr: &str) -> bool {
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(&state.active_health_check_path)
+        .header("Host", upstream_addr)
+        .body(Vec::<u8>::new())
+        .expect("build http::Request failed!");
+
+    let mut stream = match open_upstream_stream(state, upstream_addr).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            log::warn!("Could not connect to {}: {}", upstream_addr, err);
+            return false;
+        }
+    };
+    // Health checks aren't made on behalf of a client, which the protocol has a header for
+    if state.send_proxy_protocol {
+        if let Err(e) = stream.write_all(b"PROXY UNKNOWN\r\n").await {
+            log::warn!("Health check request to {} failed: {}", upstream_addr, e);
+            return false;
+        }
+    }
+    if let Err(e) = request::write_to_stream(&request, &mut stream).await {
+        log::warn!("Health check request to {} failed: {}", upstream_addr, e);
+        return false;
+    }
+    match response::read_from_stream(&mut stream, request.method(), state.read_buffer_size).await {
+        Ok(resp) if resp.status() == http::StatusCode::OK => true,
+        Ok(resp) => {
+            log::warn!(
+                "Upstream {} returned status code {}",
+                upstream_addr,
+                resp.status()
+            );
+            false
+        }
+        Err(_) => {
+            log::warn!("Health check response from {} failed", upstream_addr);
+            false
+        }
+    }
+}
+
+/// Share of its normal traffic an upstream should get, growing linearly from 0 to 1 over the
+/// --slow-start-seconds after it came back up at `healthy_since`.
+fn slow_start_weight(healthy_since: Option<&Instant>, slow_start: Duration) -> f64 {
+    match healthy_since {
+        Some(since) if !slow_start.is_zero() => {
+            (since.elapsed().as_secs_f64() / slow_start.as_secs_f64()).min(1.0)
+        }
+        _ => 1.0,
+    }
+}
+
+/// Whether an upstream that asked us to back off with Retry-After is still waiting out that time.
+fn is_unavailable(unavailable_until: &HashMap<String, Instant>, upstream: &str) -> bool {
+    unavailable_until
+        .get(upstream)
+        .is_some_and(|until| Instant::now() < *until)
+}
+
+/// Picks a random active upstream out of `pool`. Upstreams in their slow start period are picked
+/// less often, and upstreams waiting out a Retry-After or drained aren't picked at all.
+async fn pick_active_upstream(state: &Arc<ProxyState>, pool: &[String]) -> Option<String> {
     let read_lock = state.active_upstream_addresses.read().await;
+    let unavailable_until = state.upstream_unavailable_until.lock().await;
+    let drained = state.drained_upstreams.lock().await;
+    let candidates: Vec<&String> = read_lock
+        .iter()
+        .filter(|upstream| pool.contains(upstream) && !is_unavailable(&unavailable_until, upstream))
+        .filter(|upstream| !drained.contains(*upstream))
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
     let mut rng = rand::rngs::StdRng::from_entropy();
-    let upstream_idx = rng.gen_range(0..read_lock.len());
-    let upstream_ip = read_lock[upstream_idx].clone();
-    (upstream_idx, upstream_ip)
+    let healthy_since = state.upstream_healthy_since.lock().await;
+    let weights: Vec<f64> = candidates
+        .iter()
+        .map(|upstream| slow_start_weight(healthy_since.get(*upstream), state.slow_start))
+        .collect();
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        // Everything just came back up, so there is nothing to prefer
+        return Some(candidates[rng.gen_range(0..candidates.len())].clone());
+    }
+    let mut choice = rng.gen_range(0.0..total);
+    for (upstream, weight) in candidates.iter().zip(weights.iter()) {
+        if choice < *weight {
+            return Some((*upstream).clone());
+        }
+        choice -= weight;
+    }
+    Some(candidates[candidates.len() - 1].clone())
+}
+
+/// Returns the active upstreams in `pool` in a random order, for --shuffle-upstreams. Every
+/// ordering is equally likely, so each upstream is tried first equally often, and a connection
+/// that works through the list tries every upstream once. Slow start weights aren't applied, but
+/// upstreams waiting out a Retry-After or drained are left out.
+async fn shuffled_active_upstreams(state: &ProxyState, pool: &[String]) -> Vec<String> {
+    let read_lock = state.active_upstream_addresses.read().await;
+    let unavailable_until = state.upstream_unavailable_until.lock().await;
+    let drained = state.drained_upstreams.lock().await;
+    let mut candidates: Vec<String> = read_lock
+        .iter()
+        .filter(|upstream| pool.contains(upstream) && !is_unavailable(&unavailable_until, upstream))
+        .filter(|upstream| !drained.contains(*upstream))
+        .cloned()
+        .collect();
+    candidates.shuffle(&mut rand::rngs::StdRng::from_entropy());
+    candidates
 }
 
-async fn delete_upstream_address(state: &Arc<ProxyState>, upstream_idx: usize) {
+async fn delete_upstream_address(state: &Arc<ProxyState>, upstream_ip: &str) {
     let mut write_lock = state.active_upstream_addresses.write().await;
-    if upstream_idx < write_lock.len() {
+    if let Some(upstream_idx) = write_lock
+        .iter()
+        .position(|upstream| upstream == upstream_ip)
+    {
         log::info!(
             "Upstream {} is down, removed from upstream list\n",
-            upstream_idx
+            upstream_ip
         );
         write_lock.remove(upstream_idx);
     }
 }
 
-async fn add_upstream_address(state: &Arc<ProxyState>, upstream_ip: String) {
-    let mut write_lock = state.active_upstream_addresses.write().await;
-    log::info!("Pick activate upstream {}\n", upstream_ip);
-    if !write_lock.contains(&upstream_ip) {
-        write_lock.push(upstream_ip);
+/// Opens a connection to the given upstream, performing a TLS handshake if --upstream-tls is set.
+/// Unix socket upstreams are always spoken to in plain text, since they are on this machine.
+async fn open_upstream_stream(
+    state: &ProxyState,
+    upstream_addr: &str,
+) -> Result<Box<dyn Stream>, std::io::Error> {
+    if let Some(path) = upstream_addr.strip_prefix(UNIX_SOCKET_PREFIX) {
+        return Ok(Box::new(UnixStream::connect(path).await?));
     }
+    let stream = TcpStream::connect(upstream_addr).await?;
+    match &state.upstream_tls {
+        Some(connector) => {
+            let server_name = tls::server_name(upstream_addr)?;
+            Ok(Box::new(connector.connect(server_name, stream).await?))
+        }
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Builds the PROXY protocol v1 header announcing a connection from `client_addr` to us at
+/// `local_addr`. Both ends have to be the same address family, otherwise it says UNKNOWN.
+fn proxy_protocol_header(client_addr: SocketAddr, local_addr: SocketAddr) -> String {
+    let protocol = match (client_addr, local_addr) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+        (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+        _ => return "PROXY UNKNOWN\r\n".to_string(),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        protocol,
+        client_addr.ip(),
+        local_addr.ip(),
+        client_addr.port(),
+        local_addr.port()
+    )
 }
 
-async fn connect_to_upstream(state: Arc<ProxyState>) -> Result<TcpStream, std::io::Error> {
+/// Connects to a random active upstream from `pool`, returning the connection along with the
+/// address of the upstream it goes to. With --shuffle-upstreams, the active upstreams are tried in
+/// a shuffled order until one accepts. `proxy_header` (if any) is sent as soon as the connection
+/// is open.
+async fn connect_to_upstream(
+    state: Arc<ProxyState>,
+    pool: &[String],
+    proxy_header: Option<&str>,
+) -> Result<(Box<dyn Stream>, String), std::io::Error> {
+    if pool.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "No upstreams configured for this request",
+        ));
+    }
+    let mut retries = 0;
     loop {
-        if state.active_upstream_addresses.read().await.len() == 0 {
+        let candidates: Vec<String> = if state.shuffle_upstreams {
+            shuffled_active_upstreams(&state, pool).await
+        } else {
+            pick_active_upstream(&state, pool)
+                .await
+                .into_iter()
+                .collect()
+        };
+        if candidates.is_empty() {
             log::error!("No active upstream servers available");
-            sleep(Duration::from_secs(3)).await;
+            if state
+                .no_upstream_retry_limit
+                .is_some_and(|limit| retries >= limit)
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "Gave up waiting for an active upstream",
+                ));
+            }
+            retries += 1;
+            sleep(state.no_upstream_retry_delay).await;
             continue;
         }
-        let (upstream_idx, mut upstream_ip) = read_upstream_addresses(&state).await;
-        log::debug!("Connecting to upstream {}", upstream_ip);
-        // TODO: implement failover (milestone 3)
-        let stream = TcpStream::connect(upstream_ip).await;
-        let ret = match stream {
-            Ok(stream) => stream,
-            Err(_) => {
-                delete_upstream_address(&state, upstream_idx).await;
+        for upstream_ip in candidates {
+            log::debug!("Connecting to upstream {}", upstream_ip);
+            let connected = match open_upstream_stream(&state, &upstream_ip).await {
+                Ok(mut stream) => match proxy_header {
+                    Some(header) => stream.write_all(header.as_bytes()).await.map(|()| stream),
+                    None => Ok(stream),
+                },
+                Err(err) => Err(err),
+            };
+            match connected {
+                Ok(stream) => return Ok((stream, upstream_ip)),
+                Err(err) => {
+                    log::warn!("Could not connect to upstream {}: {}", upstream_ip, err);
+                    delete_upstream_address(&state, &upstream_ip).await;
+                }
+            }
+        }
+    }
+}
+
+/// Formats upstream addresses as a JSON array of strings.
+fn upstreams_json<'a>(upstreams: impl Iterator<Item = &'a String>) -> String {
+    let entries: Vec<String> = upstreams
+        .map(|upstream| {
+            format!(
+                "\"{}\"",
+                upstream.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+/// Builds the admin endpoint's reply: a JSON array of the currently active upstreams.
+async fn active_upstreams_response(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let active_upstream_addresses = state.active_upstream_addresses.read().await;
+    response::make_json_response(upstreams_json(active_upstream_addresses.iter()))
+}
+
+/// Handles POST /_admin/drain and /_admin/undrain, which take the upstream to drain or undrain
+/// in an `upstream` query parameter. Replies with the upstreams that are now drained, as JSON.
+async fn drain_response(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    if request.method() != http::Method::POST {
+        return response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let upstream = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix("upstream="))
+    });
+    let upstream = match upstream {
+        Some(upstream) if !upstream.is_empty() => upstream.to_string(),
+        _ => return response::make_http_error(http::StatusCode::BAD_REQUEST),
+    };
+    if !state.all_upstream_addresses().contains(&upstream) {
+        return response::make_http_error(http::StatusCode::NOT_FOUND);
+    }
+    let mut drained = state.drained_upstreams.lock().await;
+    if request.uri().path() == DRAIN_PATH {
+        log::info!("Draining upstream {}", upstream);
+        drained.insert(upstream);
+    } else {
+        log::info!("Undraining upstream {}", upstream);
+        drained.remove(&upstream);
+    }
+    let mut drained: Vec<&String> = drained.iter().collect();
+    drained.sort();
+    response::make_json_response(upstreams_json(drained.into_iter()))
+}
 
-                (_, upstream_ip) = read_upstream_addresses(&state).await;
+/// Methods listed in the Allow header of our reply to OPTIONS *. CONNECT is added when
+/// --allow-connect is on.
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS";
 
-                add_upstream_address(&state, upstream_ip.clone()).await;
-                let new_stream = TcpStream::connect(&upstream_ip).await?;
-                new_stream
+/// OPTIONS * asks about the server as a whole rather than any one resource, so with
+/// --handle-options we answer it instead of letting a random upstream speak for the pool.
+fn is_server_options_request(request: &http::Request<Vec<u8>>) -> bool {
+    request.method() == http::Method::OPTIONS && request.uri() == "*"
+}
+
+/// Builds our reply to OPTIONS *: an empty 200 whose Allow header lists the methods we proxy.
+fn server_options_response(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let allow = if state.allow_connect {
+        format!("{}, CONNECT", ALLOWED_METHODS)
+    } else {
+        ALLOWED_METHODS.to_string()
+    };
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::ALLOW, allow)
+        .header(http::header::CONTENT_LENGTH, "0")
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .unwrap()
+}
+
+/// GET and HEAD requests have no side effects, so they can be replayed on another upstream if the
+/// first one fails partway through.
+fn is_retryable(method: &http::Method) -> bool {
+    method == http::Method::GET || method == http::Method::HEAD
+}
+
+/// Returns the request's Idempotency-Key if --idempotency-key-ttl is on and the key is what makes
+/// the request safe to replay (GETs and HEADs are replayed regardless).
+fn idempotency_key(state: &ProxyState, request: &http::Request<Vec<u8>>) -> Option<String> {
+    if state.idempotency_key_ttl.is_zero() || is_retryable(request.method()) {
+        return None;
+    }
+    let key = request.headers().get("idempotency-key")?;
+    Some(String::from_utf8_lossy(key.as_bytes()).into_owned())
+}
+
+/// Records that the request with this Idempotency-Key is about to be replayed. Returns false if it
+/// was already replayed within the TTL, in which case it must not be sent again.
+async fn claim_idempotency_key(state: &ProxyState, key: &str) -> bool {
+    let now = Instant::now();
+    let mut replayed = state.replayed_idempotency_keys.lock().await;
+    replayed.retain(|_, replayed_at| now.duration_since(*replayed_at) < state.idempotency_key_ttl);
+    if replayed.contains_key(key) {
+        false
+    } else {
+        replayed.insert(key.to_string(), now);
+        true
+    }
+}
+
+/// Moves the client's Host header to X-Forwarded-Host and sets Host to the upstream address.
+fn rewrite_host_header(request: &mut http::Request<Vec<u8>>, upstream_ip: &str) {
+    if let Some(original_host) = request.headers_mut().remove(http::header::HOST) {
+        request
+            .headers_mut()
+            .insert("x-forwarded-host", original_host);
+    }
+    match http::HeaderValue::from_str(upstream_ip) {
+        Ok(upstream_host) => {
+            request
+                .headers_mut()
+                .insert(http::header::HOST, upstream_host);
+        }
+        Err(_) => log::warn!("Upstream address {} is not a valid Host", upstream_ip),
+    }
+}
+
+/// Sends a request to an upstream and reads back its response. Returns None (after logging why)
+/// if either direction fails.
+async fn forward_request(
+    state: &ProxyState,
+    upstream_conn: &mut UpstreamConnection,
+    request: &http::Request<Vec<u8>>,
+) -> Option<http::Response<Vec<u8>>> {
+    if let Err(error) = request::write_to_stream(request, &mut upstream_conn.stream).await {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream_conn.address,
+            error
+        );
+        return None;
+    }
+    log::debug!("Forwarded request to server");
+
+    match response::read_from_stream(
+        &mut upstream_conn.stream,
+        request.method(),
+        state.read_buffer_size,
+    )
+    .await
+    {
+        Ok(response) => Some(response),
+        Err(error) => {
+            log::error!("Error reading response from server: {:?}", error);
+            None
+        }
+    }
+}
+
+/// Handles a CONNECT request: connects to the requested host:port, tells the client the tunnel
+/// is up, and then shuttles bytes in both directions until either side hangs up.
+async fn tunnel_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+) {
+    let target = match request.uri().authority() {
+        Some(authority) => authority.to_string(),
+        None => {
+            let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+            send_response(client_conn, client_ip, &response).await;
+            return;
+        }
+    };
+    let mut target_conn = match TcpStream::connect(&target).await {
+        Ok(stream) => stream,
+        Err(error) => {
+            log::warn!("Could not open tunnel to {}: {}", target, error);
+            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            send_response(client_conn, client_ip, &response).await;
+            return;
+        }
+    };
+    log::info!("{} <-> {}: tunnel established", client_ip, target);
+    if let Err(error) = client_conn
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await
+    {
+        log::warn!("Failed to send response to client: {}", error);
+        return;
+    }
+    // Anything the client sent right after the CONNECT headers was read into the body
+    if !request.body().is_empty() {
+        if let Err(error) = target_conn.write_all(request.body()).await {
+            log::warn!("Failed to write to tunnel target {}: {}", target, error);
+            return;
+        }
+    }
+    match copy_bidirectional(client_conn, &mut target_conn).await {
+        Ok((to_target, to_client)) => log::info!(
+            "{} <-> {}: tunnel closed ({} bytes sent, {} bytes received)",
+            client_ip,
+            target,
+            to_target,
+            to_client
+        ),
+        Err(error) => log::info!("{} <-> {}: tunnel failed: {}", client_ip, target, error),
+    }
+}
+
+/// Records a request from `client_ip` against --max-requests-per-minute. Returns true if it goes
+/// over the limit and should be rejected.
+async fn is_rate_limited(state: &ProxyState, client_ip: &str) -> bool {
+    let now = Instant::now();
+    match state.rate_limit_algorithm {
+        RateLimitAlgorithm::Sliding => {
+            let mut stats = state.request_state.lock().await;
+            let entry = stats
+                .entry(client_ip.to_string())
+                .or_insert_with(VecDeque::new);
+
+            while let Some(ts) = entry.front() {
+                if now.duration_since(*ts) > state.rate_limit_window {
+                    entry.pop_front();
+                } else {
+                    break;
+                }
             }
-        };
 
-        return Ok(ret);
+            if entry.len() >= state.max_requests_per_minute {
+                log::debug!(
+                    "sliding windows len = {}, max_requests_per_minute = {}",
+                    entry.len(),
+                    state.max_requests_per_minute
+                );
+                true
+            } else {
+                entry.push_back(now);
+                false
+            }
+        }
+        RateLimitAlgorithm::TokenBucket => {
+            let capacity = state.max_requests_per_minute as f64;
+            let mut buckets = state.token_buckets.lock().await;
+            let (tokens, last_refill) = buckets
+                .entry(client_ip.to_string())
+                .or_insert((capacity, now));
+            // The bucket refills at the allowed rate, but never holds more than a window's worth
+            let elapsed = now.duration_since(*last_refill).as_secs_f64();
+            let window = state.rate_limit_window.as_secs_f64();
+            *tokens = (*tokens + elapsed * capacity / window).min(capacity);
+            *last_refill = now;
+            if *tokens < 1.0 {
+                log::debug!(
+                    "token bucket for {} is empty ({:.2} tokens)",
+                    client_ip,
+                    tokens
+                );
+                true
+            } else {
+                *tokens -= 1.0;
+                false
+            }
+        }
+    }
+}
+
+/// Forgets clients that haven't sent a request within the rate limiting window, so the maps don't
+/// keep growing as new client IPs show up. A client with no entry is treated exactly like one
+/// whose window is empty (or whose bucket is full), so this doesn't change who gets limited.
+async fn evict_stale_rate_limit_entries(state: &ProxyState) {
+    let now = Instant::now();
+    let mut evicted = Vec::new();
+    state
+        .request_state
+        .lock()
+        .await
+        .retain(|client_ip, timestamps| {
+            let active = match timestamps.back() {
+                Some(newest) => now.duration_since(*newest) <= state.rate_limit_window,
+                None => false,
+            };
+            if !active {
+                evicted.push(client_ip.clone());
+            }
+            active
+        });
+    state
+        .token_buckets
+        .lock()
+        .await
+        .retain(|client_ip, (_, last_refill)| {
+            let active = now.duration_since(*last_refill) <= state.rate_limit_window;
+            if !active {
+                evicted.push(client_ip.clone());
+            }
+            active
+        });
+    for client_ip in evicted {
+        log::debug!("Evicted rate limiting state for {}", client_ip);
+    }
+}
+
+/// Lowercases header names from the command line so they can be compared with http's header
+/// names, which are always lowercase.
+fn lowercase_all(names: &[String]) -> Vec<String> {
+    names.iter().map(|name| name.to_lowercase()).collect()
+}
+
+/// Removes every header matching one of the --strip-request-header or --strip-response-header
+/// patterns. A pattern ending in * matches any header starting with the rest of it.
+fn strip_headers(headers: &mut http::HeaderMap, patterns: &[String]) {
+    let matches = |name: &str| {
+        patterns
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    };
+    let stripped: Vec<http::header::HeaderName> = headers
+        .keys()
+        .filter(|name| matches(name.as_str()))
+        .cloned()
+        .collect();
+    for name in stripped {
+        log::debug!("Stripping header {}", name);
+        // Takes out every value the header has, not just the first
+        headers.remove(&name);
+    }
+}
+
+/// Applies the --rewrite-body rules to a text response, one after another.
+fn rewrite_response_body(state: &ProxyState, response: &mut http::Response<Vec<u8>>) {
+    for rule in state.rewrite_body.iter() {
+        if response::replace_in_body(response, rule.from.as_bytes(), rule.to.as_bytes()) {
+            log::debug!("Rewrote {:?} to {:?} in response body", rule.from, rule.to);
+        }
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Gzips the response body if --enable-compression is on and the client can take it.
+fn compress_if_accepted(
+    state: &ProxyState,
+    request: &http::Request<Vec<u8>>,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    if state.enable_compression
+        && request::accepts_gzip(request)
+        && response::is_compressible(response)
+    {
+        if let Err(error) = response::gzip_body(response) {
+            log::warn!("Failed to compress response: {}", error);
+        }
+    }
+}
+
+/// Turns a connection away with a 503 because --max-connections connections are already open.
+async fn reject_connection(
+    mut stream: TcpStream,
+    client_addr: SocketAddr,
+    tls_acceptor: Option<TlsAcceptor>,
+) {
+    let client_ip = client_addr.ip().to_string();
+    log::info!("Too many connections open, rejecting {}", client_ip);
+    let response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+    match tls_acceptor {
+        Some(acceptor) => {
+            if let Ok(mut tls_stream) = acceptor.accept(stream).await {
+                send_response(&mut tls_stream, &client_ip, &response).await;
+            }
+        }
+        None => send_response(&mut stream, &client_ip, &response).await,
+    }
+}
+
+async fn send_response<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    response: &http::Response<Vec<u8>>,
+) {
     log::info!(
         "{} <- {}",
         client_ip,
@@ -237,30 +1505,64 @@ async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Ve
     }
 }
 
-
-async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client_conn: S,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    state: Arc<ProxyState>,
+) {
+    let client_ip = client_addr.ip().to_string();
     log::info!("Connection received from {}", client_ip);
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state.clone()).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            // connect_to_upstream(state).await?
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            // current stream is died we need to choose another upstream
-            log::debug!("Failed to connect to upstream server");
-            send_response(&mut client_conn, &response).await;
+    // Don't let one client tie up an unbounded number of connections
+    let _connection_guard = match ConnectionGuard::acquire(&state, &client_ip) {
+        Some(guard) => guard,
+        None => {
+            log::info!("Too many connections from {}, rejecting", client_ip);
+            let response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+            send_response(&mut client_conn, &client_ip, &response).await;
             return;
         }
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
+
+    // Connection to a random server in the pool of the current request's route. It is opened
+    // lazily, since which pool we need depends on the request path.
+    let mut upstream: Option<UpstreamConnection> = None;
+
+    // Every upstream connection made for this client starts by saying who the client is
+    let proxy_header = if state.send_proxy_protocol {
+        Some(proxy_protocol_header(client_addr, local_addr))
+    } else {
+        None
+    };
+
+    // Set once the client asks us to hang up after answering its current request
+    let mut close_requested = false;
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        if close_requested {
+            log::debug!("Closing connection with {} as requested", client_ip);
+            return;
+        }
+        // Read a request from the client, giving up if it stays quiet for too long
+        let read_result = match state.client_idle_timeout {
+            Some(idle_timeout) => {
+                let read = request::read_from_stream(&mut client_conn, state.read_buffer_size);
+                match timeout(idle_timeout, read).await {
+                    Ok(read_result) => read_result,
+                    Err(_) => {
+                        log::info!("Client {} was idle for too long, hanging up", client_ip);
+                        let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                        send_response(&mut client_conn, &client_ip, &response).await;
+                        return;
+                    }
+                }
+            }
+            None => request::read_from_stream(&mut client_conn, state.read_buffer_size).await,
+        };
+        let mut request = match read_result {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
@@ -278,86 +1580,308 @@ async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
+                    | request::Error::ContentLengthMismatch
+                    | request::Error::AmbiguousFraming => http::StatusCode::BAD_REQUEST,
                     request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
                     request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
                 });
-                send_response(&mut client_conn, &response).await;
+                send_response(&mut client_conn, &client_ip, &response).await;
                 continue;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
-        );
+        close_requested = request::wants_close(&request);
+        let client_version = request.version();
 
-        if state.max_requests_per_minute != 0 {
-            let now = Instant::now();
-            let should_reject = {
-                let mut stats = state.request_state.lock().await;
-                let entry = stats.entry(client_ip.clone()).or_insert_with(VecDeque::new);
-
-                while let Some(ts) = entry.front() {
-                    if now.duration_since(*ts) > Duration::from_secs(60) {
-                        entry.pop_front();
-                    } else {
-                        break;
-                    }
-                }
+        // Requests for the admin path are answered by us rather than forwarded
+        if state.admin_path.as_deref() == Some(request.uri().path()) {
+            let response = if client_addr.ip().is_loopback() {
+                active_upstreams_response(&state).await
+            } else {
+                response::make_http_error(http::StatusCode::FORBIDDEN)
+            };
+            send_response(&mut client_conn, &client_ip, &response).await;
+            continue;
+        }
+        let path = request.uri().path();
+        if state.allow_drain && (path == DRAIN_PATH || path == UNDRAIN_PATH) {
+            let response = if client_addr.ip().is_loopback() {
+                drain_response(&state, &request).await
+            } else {
+                response::make_http_error(http::StatusCode::FORBIDDEN)
+            };
+            send_response(&mut client_conn, &client_ip, &response).await;
+            continue;
+        }
+        if state.handle_options && is_server_options_request(&request) {
+            let response = server_options_response(&state);
+            send_response(&mut client_conn, &client_ip, &response).await;
+            continue;
+        }
 
-                if entry.len() >= state.max_requests_per_minute {
-                    log::debug!(
-                        "sliding windows len = {}, max_requests_per_minute = {}",
-                        entry.len(),
-                        state.max_requests_per_minute
+        if state.maintenance.load(Ordering::SeqCst) {
+            let mut response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+            response.headers_mut().insert(
+                http::header::RETRY_AFTER,
+                http::HeaderValue::from(MAINTENANCE_RETRY_AFTER_SECS),
+            );
+            send_response(&mut client_conn, &client_ip, &response).await;
+            continue;
+        }
+
+        if state.max_requests_per_minute != 0 && is_rate_limited(&state, &client_ip).await {
+            let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            send_response(&mut client_conn, &client_ip, &response).await;
+            continue;
+        }
+
+        // CONNECT asks us to become a raw TCP tunnel to the requested host rather than proxy HTTP
+        if request.method() == http::Method::CONNECT {
+            if !state.allow_connect {
+                let response = response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED);
+                send_response(&mut client_conn, &client_ip, &response).await;
+                continue;
+            }
+            tunnel_connection(&mut client_conn, &client_ip, &request).await;
+            return;
+        }
+
+        // Tag the request so it can be followed through the upstream's logs as well as ours. A
+        // client (or another proxy in front of us) that already picked an ID keeps it.
+        let request_id = state.inject_request_id.as_ref().map(|header_name| {
+            let existing = request.headers().get(header_name).cloned();
+            let id = existing.unwrap_or_else(|| {
+                http::HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).unwrap()
+            });
+            request.headers_mut().insert(header_name, id.clone());
+            (header_name, id)
+        });
+        // Identical GETs that arrive while one is already on its way upstream wait for its
+        // response instead of being forwarded themselves
+        let mut flight = None;
+        if let Some(key) = coalescing_key(&state, &request) {
+            let wait_start = Instant::now();
+            match join_flight(&state, &key).await {
+                Flight::Leader(guard) => flight = Some(guard),
+                Flight::Follower(Some(mut response)) => {
+                    log::debug!("Answering {} with a coalesced response", key);
+                    finish_response(
+                        &state,
+                        &request,
+                        &mut response,
+                        request_id,
+                        wait_start.elapsed(),
+                        client_version,
+                        close_requested,
                     );
-                    true
-                } else {
-                    entry.push_back(now);
-                    false
+                    send_response(&mut client_conn, &client_ip, &response).await;
+                    continue;
                 }
-            };
+                Flight::Follower(None) => {}
+            }
+        }
 
-            if should_reject {
-                let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                send_response(&mut client_conn, &response).await;
-                continue;
+        // Open a connection to a random destination server, unless the one we already have serves
+        // this request's route and hasn't been drained since
+        let route = state.route_for(request.uri().path());
+        let route_prefix = route.map(|route| route.prefix.clone());
+        let pool = route.map_or(&state.upstream_addresses, |route| &route.upstreams);
+        let current_address = upstream.as_ref().map(|conn| conn.address.clone());
+        let drained = match current_address {
+            Some(address) => state.drained_upstreams.lock().await.contains(&address),
+            None => false,
+        };
+        if drained
+            || upstream
+                .as_ref()
+                .is_none_or(|conn| conn.route != route_prefix)
+        {
+            match connect_to_upstream(state.clone(), pool, proxy_header.as_deref()).await {
+                Ok((stream, address)) => {
+                    upstream = Some(UpstreamConnection {
+                        stream,
+                        address,
+                        route: route_prefix.clone(),
+                    })
+                }
+                Err(error) => {
+                    log::debug!("Failed to connect to upstream server: {}", error);
+                    let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+                    send_response(&mut client_conn, &client_ip, &response).await;
+                    return;
+                }
+            }
+        }
+        let upstream_conn = upstream.as_mut().unwrap();
+        let upstream_ip = upstream_conn.address.clone();
+
+        match &request_id {
+            Some((_, id)) => log::info!(
+                "{} -> {}: {} [{}]",
+                client_ip,
+                upstream_ip,
+                request::format_request_line(&request),
+                String::from_utf8_lossy(id.as_bytes())
+            ),
+            None => log::info!(
+                "{} -> {}: {}",
+                client_ip,
+                upstream_ip,
+                request::format_request_line(&request)
+            ),
+        }
+        if let Some(threshold) = state.log_large_threshold {
+            if request.body().len() > threshold {
+                log::warn!(
+                    "Large request from {}: {} ({} bytes)",
+                    client_ip,
+                    request::format_request_line(&request),
+                    request.body().len()
+                );
             }
         }
 
+        // Internal-only headers must never reach an upstream, whatever the client sent
+        strip_headers(request.headers_mut(), &state.strip_request_headers);
+
         // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
         // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // upstream server will only know our IP, not the client's.) Operators who would rather not
+        // reveal client IPs can turn this off, and also drop whatever the client claimed.
+        if state.strip_forwarded_for {
+            request.headers_mut().remove("x-forwarded-for");
+        }
+        if !state.no_forwarded_for {
+            request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        }
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+        // We speak HTTP/1.1 to upstreams whatever the client speaks, so that an HTTP/1.0 request
+        // doesn't make the upstream close a connection we want to reuse
+        *request.version_mut() = http::Version::HTTP_11;
+
+        // Keep a copy of the request in case the upstream fails and it is safe to send it again
+        let idempotency_key = idempotency_key(&state, &request);
+        let mut retry_request = if is_retryable(request.method()) || idempotency_key.is_some() {
+            Some(request::clone_request(&request))
+        } else {
+            None
+        };
+
+        // Some upstreams only answer to their own name, so optionally send them that instead of
+        // whatever Host the client asked for.
+        if state.rewrite_host {
+            rewrite_host_header(&mut request, &upstream_ip);
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
-            Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
+        // Forward the request to the server and read its response
+        let upstream_start = Instant::now();
+        let mut response = forward_request(&state, upstream_conn, &request).await;
+        if response.is_none() {
+            // Whatever went wrong, this connection can't be trusted with further requests
+            upstream = None;
+            if let (Some(key), Some(_)) = (&idempotency_key, &retry_request) {
+                if !claim_idempotency_key(&state, key).await {
+                    log::info!("Not replaying request with Idempotency-Key {} again", key);
+                    retry_request = None;
+                }
+            }
+            if let Some(mut retry_request) = retry_request {
+                delete_upstream_address(&state, &upstream_ip).await;
+                log::info!(
+                    "Retrying {} on another upstream",
+                    request::format_request_line(&retry_request)
+                );
+                if let Ok((stream, address)) =
+                    connect_to_upstream(state.clone(), pool, proxy_header.as_deref()).await
+                {
+                    if state.rewrite_host {
+                        rewrite_host_header(&mut retry_request, &address);
+                    }
+                    let mut retry_conn = UpstreamConnection {
+                        stream,
+                        address,
+                        route: route_prefix,
+                    };
+                    response = forward_request(&state, &mut retry_conn, &retry_request).await;
+                    if response.is_some() {
+                        upstream = Some(retry_conn);
+                    }
+                }
+            }
+        }
+        let mut response = match response {
+            Some(response) => response,
+            None => {
                 let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                send_response(&mut client_conn, &client_ip, &response).await;
                 return;
             }
         };
+        // An upstream that is overloaded or down for maintenance may say when to come back. Leave
+        // it alone until then, and send this client's next request elsewhere.
+        if response.status() == http::StatusCode::SERVICE_UNAVAILABLE {
+            let address = upstream.as_ref().map(|conn| conn.address.clone());
+            if let (Some(retry_after), Some(address)) = (response::retry_after(&response), address)
+            {
+                log::info!(
+                    "Upstream {} is unavailable, not using it for {} seconds",
+                    address,
+                    retry_after.as_secs()
+                );
+                state
+                    .upstream_unavailable_until
+                    .lock()
+                    .await
+                    .insert(address, Instant::now() + retry_after);
+                upstream = None;
+            }
+        }
+        rewrite_response_body(&state, &mut response);
+        strip_headers(response.headers_mut(), &state.strip_response_headers);
+        if let Some(flight) = flight.take() {
+            flight.publish(&response);
+        }
+        // The upstream time includes any retry, since that's part of what the client waited for
+        finish_response(
+            &state,
+            &request,
+            &mut response,
+            request_id,
+            upstream_start.elapsed(),
+            client_version,
+            close_requested,
+        );
+
+        if let Some(threshold) = state.log_large_threshold {
+            if response.body().len() > threshold {
+                log::warn!(
+                    "Large response to {} for {}: {} bytes",
+                    client_ip,
+                    request.uri().path(),
+                    response.body().len()
+                );
+            }
+        }
+
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
+        send_response(&mut client_conn, &client_ip, &response).await;
         log::debug!("Forwarded response to client");
+
+        // After an upgrade (e.g. to WebSocket) the connection no longer speaks HTTP, so just relay
+        // bytes between the client and the upstream until one of them hangs up
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            if let Some(upstream_conn) = upstream.as_mut() {
+                log::info!(
+                    "{} <-> {}: switched protocols",
+                    client_ip,
+                    upstream_conn.address
+                );
+                if let Err(error) =
+                    copy_bidirectional(&mut client_conn, &mut upstream_conn.stream).await
+                {
+                    log::info!("Upgraded connection with {} failed: {}", client_ip, error);
+                }
+            }
+            return;
+        }
     }
 }
@@ -1,6 +1,5 @@
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
@@ -19,7 +18,10 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// The request has both Content-Length and Transfer-Encoding, or more than one Content-Length.
+    /// Proxies and servers may disagree on where such a request ends, which enables smuggling
+    AmbiguousFraming,
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
@@ -45,6 +47,72 @@ fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>,
     }
 }
 
+/// Deep-copies a request (method, URI, version, headers and body). http::Request isn't Clone, but
+/// we need a second copy to replay a request against another upstream.
+pub fn clone_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut clone = http::Request::new(request.body().clone());
+    *clone.method_mut() = request.method().clone();
+    *clone.uri_mut() = request.uri().clone();
+    *clone.version_mut() = request.version();
+    *clone.headers_mut() = request.headers().clone();
+    clone
+}
+
+/// Rejects requests whose body length could be interpreted differently by us and the upstream:
+/// Content-Length together with Transfer-Encoding, repeated Content-Length headers, or a
+/// Content-Length holding a list of values.
+fn validate_framing(request: &http::Request<Vec<u8>>) -> Result<(), Error> {
+    let content_lengths = request.headers().get_all("content-length");
+    let num_content_lengths = content_lengths.iter().count();
+    if num_content_lengths > 0 && request.headers().contains_key("transfer-encoding") {
+        return Err(Error::AmbiguousFraming);
+    }
+    if num_content_lengths > 1 {
+        return Err(Error::AmbiguousFraming);
+    }
+    if let Some(content_length) = request.headers().get("content-length") {
+        if content_length.as_bytes().contains(&b',') {
+            return Err(Error::AmbiguousFraming);
+        }
+    }
+    Ok(())
+}
+
+/// Whether the client wants the connection closed after this request: it said Connection: close,
+/// or it speaks HTTP/1.0 (where connections close by default) without asking for keep-alive.
+pub fn wants_close(request: &http::Request<Vec<u8>>) -> bool {
+    let has_token = |token: &str| {
+        request
+            .headers()
+            .get_all(http::header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case(token))
+    };
+    if request.version() <= http::Version::HTTP_10 {
+        !has_token("keep-alive")
+    } else {
+        has_token("close")
+    }
+}
+
+/// Whether the client listed gzip in Accept-Encoding (and didn't explicitly refuse it with q=0).
+pub fn accepts_gzip(request: &http::Request<Vec<u8>>) -> bool {
+    request
+        .headers()
+        .get_all("accept-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(|param| param.trim());
+            let name = params.next().unwrap_or("");
+            let refused = params.any(|param| param == "q=0" || param == "q=0.0");
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
 /// This function appends to a header value (adding a new header if the header is not already
 /// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
 /// or to add a new X-Forwarded-For header if one is not already present.
@@ -85,7 +153,12 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
         let mut request = http::Request::builder()
             .method(req.method.unwrap())
             .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match req.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
+        // Builder::header appends rather than replaces, so repeated headers are all kept, and
+        // HeaderMap keeps values of the same name in the order they were sent
         for header in req.headers {
             request = request.header(header.name, header.value);
         }
@@ -103,7 +176,9 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
@@ -143,16 +218,17 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
 /// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
+    buffer_size: usize,
 ) -> Result<(), Error> {
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
+        // Read up to buffer_size bytes at a time. (If the client only sent a small body, then only
+        // allocate space to read that body.)
+        let mut buffer = vec![0_u8; min(buffer_size, content_length)];
         let bytes_read = match stream.read(&mut buffer).await {
             Ok(n) => n,
             Err(e) => {
@@ -187,18 +263,23 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// closes the connection prematurely or sends an invalid request. The body is read `buffer_size`
+/// bytes at a time.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer_size: usize,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Read headers
     let mut request = read_headers(stream).await?;
+    validate_framing(&request)?;
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
     if let Some(content_length) = get_content_length(&request)? {
         if content_length > MAX_BODY_SIZE {
             return Err(Error::RequestBodyTooLarge);
         } else {
-            read_body(stream, &mut request, content_length).await?;
+            read_body(stream, &mut request, content_length, buffer_size).await?;
         }
     }
     Ok(request)
@@ -207,9 +288,9 @@ pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Ve
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
         .write(&format_request_line(request).into_bytes())
@@ -1,5 +1,8 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
@@ -17,7 +20,7 @@ pub enum Error {
     ContentLengthMismatch,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
+    /// Encountered an I/O error when reading/writing a stream
     ConnectionError(std::io::Error),
 }
 
@@ -62,7 +65,10 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(match resp.version {
+                Some(0) => http::Version::HTTP_10,
+                _ => http::Version::HTTP_11,
+            });
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -80,7 +86,9 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -119,17 +127,18 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     response: &mut http::Response<Vec<u8>>,
+    buffer_size: usize,
 ) -> Result<(), Error> {
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
 
+    let mut buffer = vec![0_u8; buffer_size];
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
-        let mut buffer = [0_u8; 512];
         let bytes_read = match stream.read(&mut buffer).await {
             Ok(n) => n,
             Err(e) => {
@@ -167,32 +176,43 @@ async fn read_body(
 }
 
 /// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// closes the connection prematurely or sends an invalid response. The body is read `buffer_size`
+/// bytes at a time.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
+    buffer_size: usize,
 ) -> Result<http::Response<Vec<u8>>, Error> {
     let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
+    if has_body(request_method, response.status()) {
+        read_body(stream, &mut response, buffer_size).await?;
+    } else if response.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+        // Content-Length (if any) describes the body a GET would have gotten, not this response.
+        // Nothing after the headers belongs to it, so don't forward stray bytes as a body.
+        // (After a 101, those bytes are the start of the new protocol and must be kept.)
+        response.body_mut().clear();
     }
     Ok(response)
 }
 
+/// A response may have a body as long as it is not responding to a HEAD request and as long as
+/// the response status code is not 1xx, 204 (no content), or 304 (not modified). See RFC 7230
+/// section 3.3.3.
+fn has_body(request_method: &http::Method, status: http::StatusCode) -> bool {
+    !(request_method == http::Method::HEAD
+        || status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED)
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
     stream
         .write(&format_response_line(response).into_bytes())
@@ -212,6 +232,15 @@ pub async fn write_to_stream(
     Ok(())
 }
 
+/// Makes a copy of a response, e.g. to hand the same upstream response to several clients.
+pub fn clone_response(response: &http::Response<Vec<u8>>) -> http::Response<Vec<u8>> {
+    let mut clone = http::Response::new(response.body().clone());
+    *clone.status_mut() = response.status();
+    *clone.version_mut() = response.version();
+    *clone.headers_mut() = response.headers().clone();
+    clone
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
@@ -238,3 +267,107 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         .body(body)
         .unwrap()
 }
+
+/// Creates a 200 OK response carrying the given JSON document.
+pub fn make_json_response(body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
+    http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+/// How long the upstream asked us to wait before sending it more requests, from a Retry-After
+/// header given in seconds. Retry-After may also be an HTTP date, which we don't parse.
+pub fn retry_after(response: &http::Response<Vec<u8>>) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get("retry-after")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Whether gzipping this response is worthwhile: it has a body, isn't already encoded, and is a
+/// text-like content type (compressing images or archives gains nothing).
+pub fn is_compressible(response: &http::Response<Vec<u8>>) -> bool {
+    is_plain_text(response)
+}
+
+/// Whether the response has a body of text we can work on directly: it isn't empty, isn't
+/// already encoded, and has a text-like content type.
+fn is_plain_text(response: &http::Response<Vec<u8>>) -> bool {
+    if response.body().is_empty() || response.headers().contains_key("content-encoding") {
+        return false;
+    }
+    let content_type = match response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(content_type) => content_type.to_lowercase(),
+        None => return false,
+    };
+    let mime_type = content_type.split(';').next().unwrap_or("").trim();
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("+json")
+        || mime_type.ends_with("+xml")
+        || [
+            "application/json",
+            "application/javascript",
+            "application/xml",
+            "image/svg+xml",
+        ]
+        .contains(&mime_type)
+}
+
+/// Replaces every occurrence of `from` in a text response's body with `to`, updating
+/// Content-Length. Other responses are left alone. Returns whether anything was replaced.
+pub fn replace_in_body(response: &mut http::Response<Vec<u8>>, from: &[u8], to: &[u8]) -> bool {
+    if from.is_empty() || !is_plain_text(response) {
+        return false;
+    }
+    let body = response.body();
+    let mut replaced = Vec::with_capacity(body.len());
+    let mut found = false;
+    let mut i = 0;
+    while i < body.len() {
+        if body[i..].starts_with(from) {
+            replaced.extend_from_slice(to);
+            i += from.len();
+            found = true;
+        } else {
+            replaced.push(body[i]);
+            i += 1;
+        }
+    }
+    if !found {
+        return false;
+    }
+    *response.body_mut() = replaced;
+    let content_length = response.body().len();
+    response
+        .headers_mut()
+        .insert("content-length", http::HeaderValue::from(content_length));
+    true
+}
+
+/// Replaces the response body with its gzipped version, updating Content-Encoding and
+/// Content-Length to match.
+pub fn gzip_body(response: &mut http::Response<Vec<u8>>) -> Result<(), std::io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(response.body())?;
+    *response.body_mut() = encoder.finish()?;
+    let content_length = response.body().len();
+    let headers = response.headers_mut();
+    headers.insert("content-encoding", http::HeaderValue::from_static("gzip"));
+    headers.insert("content-length", http::HeaderValue::from(content_length));
+    headers.append("vary", http::HeaderValue::from_static("Accept-Encoding"));
+    Ok(())
+}
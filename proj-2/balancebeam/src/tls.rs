@@ -0,0 +1,101 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Reads every certificate in a PEM file (e.g. a certificate followed by its intermediates).
+fn load_certs(path: &str) -> Result<Vec<rustls::Certificate>, String> {
+    let file = File::open(path).map_err(|err| format!("Could not open {}: {}", path, err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|err| format!("Could not parse certificates in {}: {}", path, err))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+/// Reads the first private key (PKCS#8, RSA or EC) from a PEM file.
+fn load_private_key(path: &str) -> Result<rustls::PrivateKey, String> {
+    let file = File::open(path).map_err(|err| format!("Could not open {}: {}", path, err))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|err| format!("Could not parse private key in {}: {}", path, err))?;
+    for item in items {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => {}
+        }
+    }
+    Err(format!("No private key found in {}", path))
+}
+
+/// Builds an acceptor that terminates TLS for client connections using the given PEM-encoded
+/// certificate chain and private key.
+pub fn make_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+        .map_err(|err| format!("Invalid certificate/key pair: {}", err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Certificate verifier that accepts anything. Only meant for testing against upstreams with
+/// self-signed certificates.
+struct NoCertificateVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Builds a connector for dialing upstreams over TLS. Upstream certificates are checked against
+/// the webpki root store unless `skip_verification` is set.
+pub fn make_connector(skip_verification: bool) -> TlsConnector {
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let mut config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    if skip_verification {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+    TlsConnector::from(Arc::new(config))
+}
+
+/// Returns the name to use for SNI and certificate verification when connecting to an upstream
+/// given as host:port.
+pub fn server_name(upstream_addr: &str) -> Result<rustls::ServerName, std::io::Error> {
+    let host = match upstream_addr.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => upstream_addr,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    rustls::ServerName::try_from(host).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid upstream hostname {}: {}", host, err),
+        )
+    })
+}
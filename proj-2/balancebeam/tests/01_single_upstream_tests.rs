@@ -1,7 +1,12 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{init_logging, start_canned_server, BalanceBeam, EchoServer, Server};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -99,3 +104,907 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// With --rewrite-host, the upstream should see its own address as the Host and the client's
+/// original Host in X-Forwarded-Host.
+#[tokio::test]
+async fn test_rewrite_host() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1", "--rewrite-host"],
+    )
+    .await;
+
+    log::info!("Sending a GET request");
+    let response_text = balancebeam
+        .get("/rewrite_url")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /rewrite_url HTTP/1.1"));
+    assert!(response_text.contains(&format!("host: {}\n", upstream.address)));
+    assert!(response_text.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// A client that connects and never sends anything should get a 408 and be disconnected once
+/// --client-idle-timeout expires.
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--client-idle-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    log::info!("Opening a connection and sending nothing");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not close the idle connection")
+        .expect("Error reading from balancebeam");
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Expected 408 Request Timeout, got: {}",
+        response
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Repeated headers must all be forwarded, in the order the client sent them.
+#[tokio::test]
+async fn test_duplicate_headers_preserved() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    let response_text = reqwest::Client::new()
+        .get(&format!("http://{}/duplicate_headers", balancebeam.address))
+        .header("x-custom", "first")
+        .header("x-custom", "second")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    let first = response_text
+        .find("x-custom: first\n")
+        .expect("First x-custom header was not forwarded");
+    let second = response_text
+        .find("x-custom: second\n")
+        .expect("Second x-custom header was not forwarded");
+    assert!(first < second, "x-custom headers were reordered");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --no-forwarded-for the upstream shouldn't learn the client's IP, and with
+/// --strip-forwarded-for it shouldn't see a client-supplied X-Forwarded-For either.
+#[tokio::test]
+async fn test_no_forwarded_for() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--no-forwarded-for",
+            "--strip-forwarded-for",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a GET request");
+    let response_text = balancebeam
+        .get("/private_url")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /private_url HTTP/1.1"));
+    assert!(!response_text.contains("x-forwarded-for"));
+
+    log::info!("Sending a GET request with a spoofed X-Forwarded-For");
+    let response_text = reqwest::Client::new()
+        .get(&format!("http://{}/private_url", balancebeam.address))
+        .header("x-forwarded-for", "10.1.2.3")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(!response_text.contains("x-forwarded-for"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --add-timing-header, responses should say how many milliseconds the upstream took.
+#[tokio::test]
+async fn test_timing_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1", "--add-timing-header"],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/timed_url", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let upstream_time = response
+        .headers()
+        .get("x-upstream-time-ms")
+        .expect("Response is missing X-Upstream-Time-Ms")
+        .to_str()
+        .expect("X-Upstream-Time-Ms is not valid text");
+    assert!(
+        upstream_time.parse::<u64>().is_ok(),
+        "X-Upstream-Time-Ms is not a number: {}",
+        upstream_time
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Connections from one IP beyond --max-connections-per-ip should be turned away with a 503, and
+/// accepted again once an earlier connection closes.
+#[tokio::test]
+async fn test_max_connections_per_ip() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--max-connections-per-ip",
+            "2",
+        ],
+    )
+    .await;
+
+    log::info!("Opening two idle connections");
+    let mut idle_connections = Vec::new();
+    for _ in 0..2 {
+        idle_connections.push(
+            TcpStream::connect(&balancebeam.address)
+                .await
+                .expect("Could not connect to balancebeam"),
+        );
+    }
+    // Give balancebeam a moment to register them
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    log::info!("Opening one connection too many");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not reject the excess connection")
+        .expect("Error reading from balancebeam");
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 503"),
+        "Expected 503 Service Unavailable, got: {}",
+        response
+    );
+
+    log::info!("Closing an idle connection and trying again");
+    idle_connections.pop();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let response_text = balancebeam
+        .get("/after_limit")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after_limit HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Opens `n` connections to balancebeam that stay idle, holding on to their slots.
+async fn open_idle_connections(balancebeam: &BalanceBeam, n: usize) -> Vec<TcpStream> {
+    let mut idle_connections = Vec::new();
+    for _ in 0..n {
+        idle_connections.push(
+            TcpStream::connect(&balancebeam.address)
+                .await
+                .expect("Could not connect to balancebeam"),
+        );
+    }
+    // Give balancebeam a moment to register them
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    idle_connections
+}
+
+/// With --max-connections, a connection beyond the limit isn't served until an earlier one
+/// closes.
+#[tokio::test]
+async fn test_max_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--max-connections",
+            "2",
+        ],
+    )
+    .await;
+
+    let mut idle_connections = open_idle_connections(&balancebeam, 2).await;
+    log::info!("Sending a request on one connection too many");
+    let request = balancebeam.get("/waiting");
+    tokio::pin!(request);
+    assert!(
+        timeout(Duration::from_secs(1), &mut request).await.is_err(),
+        "A connection beyond --max-connections was served"
+    );
+
+    log::info!("Closing an idle connection");
+    idle_connections.pop();
+    let response_text = timeout(Duration::from_secs(5), request)
+        .await
+        .expect("The waiting connection was not served once there was room")
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /waiting HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --reject-when-full, a connection beyond --max-connections gets a 503 right away.
+#[tokio::test]
+async fn test_max_connections_reject_when_full() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--max-connections",
+            "2",
+            "--reject-when-full",
+        ],
+    )
+    .await;
+
+    let mut idle_connections = open_idle_connections(&balancebeam, 2).await;
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not reject the excess connection")
+        .expect("Error reading from balancebeam");
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 503"),
+        "Expected 503 Service Unavailable, got: {}",
+        response
+    );
+
+    idle_connections.pop();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let response_text = balancebeam
+        .get("/after_limit")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after_limit HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --log-large-threshold, bodies over the threshold should be logged as warnings.
+#[tokio::test]
+async fn test_log_large_threshold() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--log-large-threshold",
+            "1000",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a small request");
+    balancebeam
+        .post("/small_url", "tiny")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(!balancebeam.output_contains("Large response"));
+
+    log::info!("Sending a request whose echo is over the threshold");
+    let large_body = "x".repeat(2000);
+    balancebeam
+        .post("/large_url", &large_body)
+        .await
+        .expect("Error sending request to balancebeam");
+    // Give balancebeam's output a moment to reach us
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(balancebeam.output_contains("Large request from 127.0.0.1"));
+    assert!(balancebeam.output_contains("Large response to 127.0.0.1 for /large_url"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --inject-request-id, each request should get an ID that the upstream sees, the client gets
+/// back, and balancebeam logs. An ID the client already chose should be kept.
+#[tokio::test]
+async fn test_inject_request_id() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--inject-request-id",
+            "X-Request-Id",
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request without an ID");
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/traced_url", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let request_id = response
+        .headers()
+        .get("x-request-id")
+        .expect("Response is missing X-Request-Id")
+        .to_str()
+        .expect("X-Request-Id is not valid text")
+        .to_string();
+    assert_eq!(request_id.len(), 36, "Not a UUID: {}", request_id);
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains(&format!("x-request-id: {}", request_id)));
+    // Give balancebeam's output a moment to reach us
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(balancebeam.output_contains(&format!("GET /traced_url HTTP/1.1 [{}]", request_id)));
+
+    log::info!("Sending a request that already has an ID");
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/traced_url", balancebeam.address))
+        .header("x-request-id", "client-chosen-id")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response.headers().get("x-request-id").unwrap(),
+        "client-chosen-id"
+    );
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("x-request-id: client-chosen-id"));
+    assert_eq!(response_text.matches("x-request-id").count(), 1);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// SIGUSR1 should toggle maintenance mode, during which requests get a 503 with Retry-After and
+/// never reach the upstream.
+#[tokio::test]
+async fn test_maintenance_mode() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "1"])
+            .await;
+
+    log::info!("Sending a request before maintenance");
+    balancebeam
+        .get("/before")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    log::info!("Entering maintenance mode");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    for _ in 0..3 {
+        let response = reqwest::Client::new()
+            .get(&format!("http://{}/during", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 503);
+        assert!(response.headers().get("retry-after").is_some());
+    }
+
+    log::info!("Leaving maintenance mode");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let response_text = balancebeam
+        .get("/after")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /after HTTP/1.1"));
+
+    assert!(balancebeam.output_contains("Entering maintenance mode"));
+    assert!(
+        !balancebeam.output_contains("GET /during"),
+        "A request made during maintenance was forwarded"
+    );
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts a server that answers health checks (requests for /) right away but takes half a second
+/// to answer anything else. Returns its address and how many of the slow requests it received.
+async fn start_slow_server() -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let num_requests = Arc::new(AtomicUsize::new(0));
+    let server_num_requests = num_requests.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let num_requests = server_num_requests.clone();
+            tokio::spawn(async move {
+                loop {
+                    let mut buf = vec![0u8; 8192];
+                    let mut len = 0;
+                    while !buf[..len].windows(4).any(|window| window == b"\r\n\r\n") {
+                        match stream.read(&mut buf[len..]).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => len += n,
+                        }
+                    }
+                    if !buf.starts_with(b"GET / ") {
+                        num_requests.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                    }
+                    let reply = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow";
+                    if stream.write_all(reply).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    (address, num_requests)
+}
+
+/// With --coalesce-requests, a burst of identical GETs should reach the upstream only once, with
+/// every client getting the response.
+#[tokio::test]
+async fn test_coalesce_requests() {
+    init_logging();
+    let (upstream, num_requests) = start_slow_server().await;
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&upstream],
+            &["--active-health-check-interval", "1", "--coalesce-requests"],
+        )
+        .await,
+    );
+    log::info!("Waiting for the upstream to pass a health check");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    log::info!("Sending 50 identical requests at once");
+    let mut tasks = Vec::new();
+    for _ in 0..50 {
+        let balancebeam = balancebeam.clone();
+        tasks.push(tokio::spawn(async move {
+            balancebeam
+                .get("/popular")
+                .await
+                .expect("Error sending request to balancebeam")
+        }));
+    }
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), "slow");
+    }
+    assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+    log::info!("All done :)");
+}
+
+/// Coalesced clients each get their own request ID and timing back, and requests carrying
+/// credentials are never answered with someone else's response.
+#[tokio::test]
+async fn test_coalesce_requests_per_client() {
+    init_logging();
+    let (upstream, num_requests) = start_slow_server().await;
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&upstream],
+            &[
+                "--active-health-check-interval",
+                "1",
+                "--coalesce-requests",
+                "--inject-request-id",
+                "X-Request-Id",
+                "--add-timing-header",
+            ],
+        )
+        .await,
+    );
+    log::info!("Waiting for the upstream to pass a health check");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    log::info!("Sending 10 identical requests with different IDs at once");
+    let mut tasks = Vec::new();
+    for i in 0..10 {
+        let balancebeam = balancebeam.clone();
+        tasks.push(tokio::spawn(async move {
+            reqwest::Client::new()
+                .get(&format!("http://{}/popular", balancebeam.address))
+                .header("x-request-id", format!("client-{}", i))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        let response = task.await.unwrap();
+        assert_eq!(
+            response.headers().get("x-request-id").unwrap(),
+            format!("client-{}", i).as_str()
+        );
+        assert!(response.headers().contains_key("x-upstream-time-ms"));
+        assert_eq!(response.text().await.unwrap(), "slow");
+    }
+    assert_eq!(num_requests.load(Ordering::SeqCst), 1);
+
+    for header in ["authorization", "cookie"] {
+        log::info!("Sending 5 requests with different {} headers at once", header);
+        let mut tasks = Vec::new();
+        for i in 0..5 {
+            let balancebeam = balancebeam.clone();
+            tasks.push(tokio::spawn(async move {
+                reqwest::Client::new()
+                    .get(&format!("http://{}/popular", balancebeam.address))
+                    .header(header, format!("secret-{}", i))
+                    .send()
+                    .await
+                    .expect("Error sending request to balancebeam")
+            }));
+        }
+        for task in tasks {
+            assert_eq!(task.await.unwrap().text().await.unwrap(), "slow");
+        }
+    }
+    assert_eq!(num_requests.load(Ordering::SeqCst), 11);
+    log::info!("All done :)");
+}
+
+/// A client that sends Connection: close should get one response, after which balancebeam hangs
+/// up instead of waiting for another request.
+#[tokio::test]
+async fn test_connection_close() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "1"])
+            .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /closing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not close the connection after the response")
+        .expect("Error reading from balancebeam");
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 200"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    assert!(response.contains("GET /closing HTTP/1.1"));
+    assert_eq!(response.matches("HTTP/1.1 200").count(), 1);
+    assert!(response.to_lowercase().contains("connection: close"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// HTTP/1.0 connections close after each response unless the client asks for keep-alive, in which
+/// case balancebeam has to say that it is keeping the connection open.
+#[tokio::test]
+async fn test_http_10_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--active-health-check-interval", "1"])
+            .await;
+
+    log::info!("Sending an HTTP/1.0 request");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /old-client HTTP/1.0\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam kept an HTTP/1.0 connection open")
+        .expect("Error reading from balancebeam");
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.0 200"),
+        "Expected an HTTP/1.0 200 OK, got: {}",
+        response
+    );
+    assert!(response.contains("GET /old-client"));
+
+    log::info!("Sending two HTTP/1.0 keep-alive requests on one connection");
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    for path in &["/first", "/second"] {
+        let request = format!("GET {} HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", path);
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .expect("Could not send request to balancebeam");
+        let mut response = Vec::new();
+        let mut buf = [0u8; 4096];
+        while !String::from_utf8_lossy(&response).contains(&format!("GET {}", path)) {
+            let n = timeout(Duration::from_secs(10), stream.read(&mut buf))
+                .await
+                .expect("Timed out waiting for a response")
+                .expect("Error reading from balancebeam");
+            assert_ne!(n, 0, "balancebeam closed a keep-alive connection");
+            response.extend_from_slice(&buf[..n]);
+        }
+        let response = String::from_utf8_lossy(&response).to_lowercase();
+        assert!(response.starts_with("http/1.0 200"));
+        assert!(response.contains("connection: keep-alive"));
+    }
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Bodies much larger than --read-buffer-size take many reads, and should come through intact in
+/// both directions.
+#[tokio::test]
+async fn test_read_buffer_size() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--read-buffer-size",
+            "100",
+        ],
+    )
+    .await;
+
+    let body: String = (0..20000)
+        .map(|i| char::from(b'a' + (i % 26) as u8))
+        .collect();
+    let response = reqwest::Client::new()
+        .post(&format!("http://{}/bulk", balancebeam.address))
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let response_text = response.text().await.unwrap();
+    assert!(response_text.contains("POST /bulk HTTP/1.1"));
+    assert!(
+        response_text.ends_with(&body),
+        "The body did not make it through intact"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts a server that answers each connection's first request with the first line it received
+/// on that connection, and then hangs up. Returns its address.
+async fn start_first_line_server() -> String {
+    start_canned_server(|head| {
+        let first_line = head.split("\r\n").next().unwrap();
+        Some(format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            first_line.len(),
+            first_line
+        ))
+    })
+    .await
+}
+
+/// With --send-proxy-protocol, the first thing an upstream hears on a connection should be a
+/// PROXY line giving the client's address and the address it connected to.
+#[tokio::test]
+async fn test_send_proxy_protocol() {
+    init_logging();
+    let upstream = start_first_line_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--active-health-check-interval", "1", "--send-proxy-protocol"],
+    )
+    .await;
+
+    let first_line = reqwest::get(&format!("http://{}/proxied", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Error reading response from balancebeam");
+    let fields: Vec<&str> = first_line.split(' ').collect();
+    assert_eq!(fields.len(), 6, "Malformed PROXY line: {}", first_line);
+    assert_eq!(&fields[..4], &["PROXY", "TCP4", "127.0.0.1", "127.0.0.1"]);
+    assert!(fields[4].parse::<u16>().is_ok(), "Bad client port in {}", first_line);
+    let balancebeam_port = balancebeam.address.rsplit(':').next().unwrap();
+    assert_eq!(fields[5], balancebeam_port);
+
+    log::info!("All done :)");
+}
+
+/// Sends OPTIONS * on a fresh connection and returns everything balancebeam sends back.
+async fn send_server_options(balancebeam: &BalanceBeam) -> String {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .expect("Could not send request to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not close the connection after the response")
+        .expect("Error reading from balancebeam");
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// OPTIONS * is passed through to an upstream by default, and answered by balancebeam itself with
+/// an Allow header when --handle-options is given.
+#[tokio::test]
+async fn test_handle_options() {
+    init_logging();
+    let upstream = start_first_line_server().await;
+
+    log::info!("Sending OPTIONS * without --handle-options");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "1"]).await;
+    let response = send_server_options(&balancebeam).await;
+    assert!(
+        response.ends_with("OPTIONS * HTTP/1.1"),
+        "Expected OPTIONS * to reach the upstream, got: {}",
+        response
+    );
+
+    log::info!("Sending OPTIONS * with --handle-options");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--active-health-check-interval", "1", "--handle-options"],
+    )
+    .await;
+    let response = send_server_options(&balancebeam).await;
+    assert!(
+        response.starts_with("HTTP/1.1 200"),
+        "Expected 200 OK, got: {}",
+        response
+    );
+    let allow = response
+        .lines()
+        .find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("allow: ")
+                .map(str::to_string)
+        })
+        .expect("No Allow header in the response");
+    assert!(
+        allow.starts_with("get, head, post"),
+        "Unexpected Allow header: {}",
+        allow
+    );
+    assert!(
+        !allow.contains("connect"),
+        "CONNECT allowed without --allow-connect"
+    );
+    assert!(
+        !response.contains("OPTIONS * HTTP/1.1"),
+        "OPTIONS * was forwarded: {}",
+        response
+    );
+
+    log::info!("All done :)");
+}
+
+/// Headers named by --strip-request-header should never reach the upstream, matched without
+/// regard to case and by prefix when the name ends in *. --strip-response-header does the same
+/// for the upstream's response.
+#[tokio::test]
+async fn test_strip_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--strip-request-header",
+            "X-Admin-*",
+            "--strip-request-header",
+            "x-internal-token",
+            "--strip-response-header",
+            "DATE",
+        ],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/stripped_url", balancebeam.address))
+        .header("x-admin-user", "root")
+        .header("X-Admin-Override", "1")
+        .header("X-Internal-Token", "secret")
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(
+        response.headers().get("date").is_none(),
+        "Date header was not stripped from the response"
+    );
+    let response_text = response.text().await.unwrap().to_lowercase();
+    assert!(response_text.contains("get /stripped_url http/1.1"));
+    assert!(
+        !response_text.contains("x-admin"),
+        "X-Admin-* header reached the upstream: {}",
+        response_text
+    );
+    assert!(
+        !response_text.contains("x-internal-token"),
+        "X-Internal-Token reached the upstream: {}",
+        response_text
+    );
+    assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
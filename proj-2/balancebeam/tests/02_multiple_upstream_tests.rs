@@ -1,8 +1,10 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{init_logging, start_canned_server, BalanceBeam, EchoServer, ErrorServer, Server};
 
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
 use tokio::time::sleep;
 
 async fn setup_with_params(
@@ -294,3 +296,753 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Send requests under /api and /web to their own upstreams and everything else to the default
+/// pool. --rewrite-host makes each upstream echo back its own address, so we can tell them apart.
+/// /apiary only shares its first letters with /api, so it isn't under it.
+#[tokio::test]
+async fn test_path_prefix_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let web_upstream = EchoServer::new().await;
+    let api_route = format!("/api={}", api_upstream.address);
+    let web_route = format!("/web/={}", web_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--rewrite-host",
+            "--route",
+            &api_route,
+            "--route",
+            &web_route,
+        ],
+    )
+    .await;
+
+    for (path, upstream) in [
+        ("/api/users", &api_upstream),
+        ("/web/index.html", &web_upstream),
+        ("/other", &default_upstream),
+        ("/api/again", &api_upstream),
+        ("/api", &api_upstream),
+        ("/apiary", &default_upstream),
+    ] {
+        log::info!("Requesting {}", path);
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(
+            response_text.contains(&format!("host: {}\n", upstream.address)),
+            "{} was not routed to the expected upstream",
+            path
+        );
+    }
+
+    Box::new(default_upstream).stop().await;
+    Box::new(api_upstream).stop().await;
+    Box::new(web_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// After a health check cycle, the admin endpoint should list only the upstreams that are up.
+#[tokio::test]
+async fn test_admin_active_upstreams() {
+    init_logging();
+    let healthy_upstream = EchoServer::new().await;
+    let failed_upstream = EchoServer::new().await;
+    let failed_ip = failed_upstream.address.clone();
+    Box::new(failed_upstream).stop().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy_upstream.address, &failed_ip],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--admin-path",
+            "/_admin/upstreams",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for a health check cycle to complete");
+    sleep(Duration::from_secs(2)).await;
+
+    let response_text = balancebeam
+        .get("/_admin/upstreams")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(
+        response_text,
+        format!("[\"{}\"]", healthy_upstream.address),
+        "Admin endpoint did not list exactly the healthy upstream"
+    );
+
+    Box::new(healthy_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts a server that passes health checks (requests for /) but hangs up on any other request
+/// without responding. Returns its address.
+async fn start_resetting_server() -> String {
+    start_canned_server(|head| {
+        // Anything but a health check: drop the connection without a response
+        head.starts_with("GET / ")
+            .then(|| "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string())
+    })
+    .await
+}
+
+/// GET requests sent to an upstream that hangs up after accepting the connection should be
+/// retried on another upstream instead of failing.
+#[tokio::test]
+async fn test_retry_idempotent_requests() {
+    init_logging();
+    let resetting_upstream = start_resetting_server().await;
+    let working_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(
+        &[&resetting_upstream, &working_upstream.address],
+        Some(1),
+        None,
+    )
+    .await;
+    log::info!("Waiting for a health check cycle to complete");
+    sleep(Duration::from_secs(2)).await;
+
+    for i in 0..10 {
+        let path = format!("/retry-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "Request was not retried on the working upstream"
+        );
+    }
+
+    Box::new(working_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --idempotency-key-ttl, a POST carrying an Idempotency-Key is safe to replay, so it should
+/// be retried on another upstream just like a GET.
+#[tokio::test]
+async fn test_retry_with_idempotency_key() {
+    init_logging();
+    let resetting_upstream = start_resetting_server().await;
+    let working_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&resetting_upstream, &working_upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--idempotency-key-ttl",
+            "60",
+        ],
+    )
+    .await;
+    log::info!("Waiting for a health check cycle to complete");
+    sleep(Duration::from_secs(2)).await;
+
+    for i in 0..10 {
+        let path = format!("/charge-{}", i);
+        let response = reqwest::Client::new()
+            .post(&format!("http://{}{}", balancebeam.address, path))
+            .header("Idempotency-Key", format!("key-{}", i))
+            .body("amount=10")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        let response_text = response.text().await.unwrap();
+        assert!(
+            response_text.contains(&format!("POST {} HTTP/1.1", path)),
+            "Request was not retried on the working upstream"
+        );
+        assert!(response_text.contains("amount=10"));
+        // Let the health checks bring the resetting upstream back, so that later requests
+        // also get a chance to fail there first
+        sleep(Duration::from_millis(300)).await;
+    }
+    assert!(
+        balancebeam.output_contains("Retrying POST"),
+        "No POST ever hit the resetting upstream"
+    );
+
+    Box::new(working_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts a server that answers every request with a 500 and records when each one arrived.
+async fn start_failing_server() -> (String, Arc<Mutex<Vec<Instant>>>) {
+    start_recording_server("500 Internal Server Error").await
+}
+
+/// Starts a server that answers every request with the given status and records when each one
+/// arrived.
+async fn start_recording_server(status: &'static str) -> (String, Arc<Mutex<Vec<Instant>>>) {
+    let probe_times = Arc::new(Mutex::new(Vec::new()));
+    let server_probe_times = probe_times.clone();
+    let address = start_canned_server(move |_| {
+        server_probe_times.lock().unwrap().push(Instant::now());
+        Some(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status))
+    })
+    .await;
+    (address, probe_times)
+}
+
+/// An upstream that keeps failing health checks should be probed at increasing intervals.
+#[tokio::test]
+async fn test_failing_upstream_backoff() {
+    init_logging();
+    let healthy_upstream = EchoServer::new().await;
+    let (failing_upstream, probe_times) = start_failing_server().await;
+    let _balancebeam = BalanceBeam::new(
+        &[&healthy_upstream.address, &failing_upstream],
+        Some(1),
+        None,
+    )
+    .await;
+
+    log::info!("Letting several health check cycles run...");
+    sleep(Duration::from_secs(10)).await;
+
+    let probe_times = probe_times.lock().unwrap().clone();
+    log::info!("Failing upstream was probed {} times", probe_times.len());
+    // With a 1 second interval, probes should come roughly 1, 2 and 4 seconds apart rather than
+    // every second
+    assert!(
+        probe_times.len() >= 3 && probe_times.len() <= 5,
+        "Expected backoff to limit probes to ~4 in 10 seconds, got {}",
+        probe_times.len()
+    );
+    let gaps: Vec<Duration> = probe_times
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .collect();
+    for pair in gaps.windows(2) {
+        assert!(
+            pair[1] + Duration::from_millis(500) >= pair[0] * 2,
+            "Probe intervals did not grow: {:?}",
+            gaps
+        );
+    }
+
+    Box::new(healthy_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With a retry limit, a request made while no upstream is up should fail promptly instead of
+/// hanging until one comes back.
+#[tokio::test]
+async fn test_no_upstream_retry_limit() {
+    init_logging();
+    // Grab a free port and release it, so nothing is listening there
+    let dead_upstream = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_upstream],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--no-upstream-retry-limit",
+            "2",
+            "--no-upstream-retry-delay",
+            "1",
+        ],
+    )
+    .await;
+
+    let started = Instant::now();
+    let response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap()
+        .get(&format!("http://{}/no-upstreams", balancebeam.address))
+        .send()
+        .await
+        .expect("balancebeam did not answer while no upstreams were up");
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_GATEWAY);
+    assert!(
+        started.elapsed() < Duration::from_secs(5),
+        "Request took {:?} to fail",
+        started.elapsed()
+    );
+    log::info!("All done :)");
+}
+
+/// --check-config should report which upstreams resolve and pass a health check, and fail only
+/// if none of them do.
+#[tokio::test]
+async fn test_check_config() {
+    init_logging();
+    let healthy_upstream = EchoServer::new().await;
+    let dead_upstream = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let unresolvable_upstream = "no-such-host.invalid:80";
+
+    let output = BalanceBeam::run_to_completion(
+        &[
+            &healthy_upstream.address,
+            &dead_upstream,
+            unresolvable_upstream,
+        ],
+        &["--check-config"],
+    )
+    .await;
+    let report = String::from_utf8_lossy(&output.stdout);
+    log::info!("Report:\n{}", report);
+    assert!(output.status.success());
+    assert!(report.contains(&format!("{}: healthy", healthy_upstream.address)));
+    assert!(report.contains(&format!("{}: health check failed", dead_upstream)));
+    assert!(report.contains(&format!("{}: could not resolve", unresolvable_upstream)));
+    assert!(report.contains("1 of 3 upstreams reachable"));
+
+    let output = BalanceBeam::run_to_completion(
+        &[&dead_upstream, unresolvable_upstream],
+        &["--check-config"],
+    )
+    .await;
+    assert!(
+        !output.status.success(),
+        "--check-config should fail when no upstream is reachable"
+    );
+
+    Box::new(healthy_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With --health-check-jitter, the time between health checks should vary, but stay within the
+/// jitter bounds.
+#[tokio::test]
+async fn test_health_check_jitter() {
+    init_logging();
+    let (upstream, probe_times) = start_recording_server("200 OK").await;
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--health-check-jitter",
+            "50",
+        ],
+    )
+    .await;
+
+    log::info!("Letting several health check cycles run...");
+    sleep(Duration::from_secs(8)).await;
+
+    let probe_times = probe_times.lock().unwrap().clone();
+    let gaps: Vec<Duration> = probe_times
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .collect();
+    assert!(gaps.len() >= 4, "Too few health checks: {:?}", gaps);
+    for gap in gaps.iter() {
+        assert!(
+            *gap >= Duration::from_millis(400) && *gap <= Duration::from_millis(1700),
+            "Health check interval outside the jitter bounds: {:?}",
+            gaps
+        );
+    }
+    let shortest = gaps.iter().min().unwrap();
+    let longest = gaps.iter().max().unwrap();
+    assert!(
+        *longest - *shortest >= Duration::from_millis(50),
+        "Health check intervals did not vary: {:?}",
+        gaps
+    );
+    log::info!("All done :)");
+}
+
+/// With --slow-start-seconds, an upstream that comes back up should get only a small share of the
+/// requests at first, while the established upstream keeps most of them.
+#[tokio::test]
+async fn test_slow_start() {
+    init_logging();
+    let established = EchoServer::new().await;
+    let returning = EchoServer::new().await;
+    let returning_ip = returning.address();
+    Box::new(returning).stop().await;
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&established.address(), &returning_ip],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--slow-start-seconds",
+            "30",
+        ],
+    )
+    .await;
+    log::info!("Waiting for the first health check, with only one upstream up...");
+    sleep(Duration::from_secs(2)).await;
+
+    log::info!("Bringing up the second upstream");
+    let returning = EchoServer::new_at_address(returning_ip).await;
+    sleep(Duration::from_secs(2)).await;
+
+    let n_requests = 60;
+    for i in 0..n_requests {
+        let path = format!("/slow-start-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let returning_count = Box::new(returning).stop().await;
+    let established_count = Box::new(established).stop().await;
+    log::info!(
+        "Established upstream got {} requests, returning upstream got {}",
+        established_count,
+        returning_count
+    );
+    assert!(
+        returning_count * 3 < established_count,
+        "The returning upstream got {} of {} requests during slow start",
+        returning_count,
+        n_requests
+    );
+    log::info!("All done :)");
+}
+
+/// Sends a request and returns its status code.
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    reqwest::Client::new()
+        .get(&format!("http://{}{}", balancebeam.address, path))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Both rate limiting algorithms allow a burst up to the limit. Afterwards, the token bucket lets
+/// requests through again as tokens trickle back in, while the sliding window stays closed until
+/// the burst is a minute old.
+#[tokio::test]
+async fn test_rate_limit_algorithms() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // 10 requests per minute: the token bucket regains one token every 6 seconds, slowly enough
+    // that the burst can't earn one back even on a busy machine
+    let rate_limit_threshold = 10;
+    let sliding = BalanceBeam::new_with_args(
+        &[&upstream.address()],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--max-requests-per-minute",
+            &rate_limit_threshold.to_string(),
+        ],
+    )
+    .await;
+    let token_bucket = BalanceBeam::new_with_args(
+        &[&upstream.address()],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--max-requests-per-minute",
+            &rate_limit_threshold.to_string(),
+            "--rate-limit-algorithm",
+            "token-bucket",
+        ],
+    )
+    .await;
+    log::info!("Waiting for the upstream to pass a health check");
+    sleep(Duration::from_secs(2)).await;
+
+    for balancebeam in [&sliding, &token_bucket] {
+        for i in 0..rate_limit_threshold {
+            assert_eq!(get_status(balancebeam, &format!("/burst-{}", i)).await, 200);
+        }
+        assert_eq!(get_status(balancebeam, "/over-the-limit").await, 429);
+    }
+
+    log::info!("Waiting for the token bucket to refill a little");
+    sleep(Duration::from_secs(7)).await;
+    let mut num_allowed = [0, 0];
+    for (idx, balancebeam) in [&sliding, &token_bucket].iter().enumerate() {
+        for i in 0..rate_limit_threshold {
+            if get_status(balancebeam, &format!("/after-wait-{}", i)).await == 200 {
+                num_allowed[idx] += 1;
+            }
+        }
+    }
+    log::info!("Requests allowed after the wait: {:?}", num_allowed);
+    assert_eq!(num_allowed[0], 0, "The sliding window let requests through");
+    assert!(
+        num_allowed[1] >= 1 && num_allowed[1] < rate_limit_threshold,
+        "The token bucket should have refilled a little, not completely"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Clients that stop sending requests should be dropped from the rate limiting state once their
+/// window has passed, rather than being remembered forever.
+#[tokio::test]
+async fn test_rate_limit_eviction() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--max-requests-per-minute",
+            "5",
+        ],
+    )
+    .await;
+
+    assert_eq!(get_status(&balancebeam, "/remember-me").await, 200);
+    log::info!("Waiting for the rate limiting window to pass");
+    sleep(Duration::from_secs(55)).await;
+    assert!(
+        !balancebeam.output_contains("Evicted rate limiting state for 127.0.0.1"),
+        "The client was forgotten before its window was over"
+    );
+    // The window is 60 seconds and stale clients are swept every 10 seconds
+    sleep(Duration::from_secs(20)).await;
+    assert!(
+        balancebeam.output_contains("Evicted rate limiting state for 127.0.0.1"),
+        "The client is still remembered after its window was over"
+    );
+
+    log::info!("All done :)");
+}
+
+/// --rate-limit-window-secs shortens the window, so a client that hit the limit is let back in
+/// once that many seconds have passed.
+#[tokio::test]
+async fn test_rate_limit_window() {
+    init_logging();
+    let rate_limit_threshold = 5;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--max-requests-per-minute",
+            &rate_limit_threshold.to_string(),
+            "--rate-limit-window-secs",
+            "3",
+        ],
+    )
+    .await;
+    log::info!("Waiting for the upstream to pass a health check");
+    sleep(Duration::from_secs(2)).await;
+
+    for i in 0..rate_limit_threshold {
+        assert_eq!(get_status(&balancebeam, &format!("/window-{}", i)).await, 200);
+    }
+    assert_eq!(get_status(&balancebeam, "/over-the-limit").await, 429);
+
+    log::info!("Waiting for the window to pass");
+    sleep(Duration::from_millis(3500)).await;
+    assert_eq!(get_status(&balancebeam, "/after-the-window").await, 200);
+
+    log::info!("All done :)");
+}
+
+/// With --shuffle-upstreams, each connection starts at a random point of a shuffled list, so every
+/// upstream should come first about equally often, and a dead upstream in the list is skipped.
+#[tokio::test]
+async fn test_shuffle_upstreams() {
+    init_logging();
+    let n_requests = 90;
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    // Nothing listens here, so connections to it fail and the next upstream in line is used
+    let dead_upstream = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let mut upstream_addresses: Vec<String> =
+        upstreams.iter().map(|upstream| upstream.address()).collect();
+    upstream_addresses.push(dead_upstream);
+    let upstream_addresses: Vec<&str> = upstream_addresses.iter().map(String::as_str).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--shuffle-upstreams",
+        ],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/shuffled-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    for count in request_counters {
+        assert!(
+            count >= n_requests / 3 / 2,
+            "Upstream got {} of {} requests; load doesn't seem evenly distributed",
+            count,
+            n_requests
+        );
+    }
+
+    log::info!("All done :)");
+}
+
+/// Starts a server that passes health checks (requests for /) but answers any other request with
+/// a 503 asking to be left alone for `retry_after` seconds. Returns its address and the number of
+/// 503s it has sent.
+async fn start_unavailable_server(retry_after: u64) -> (String, Arc<Mutex<usize>>) {
+    let refused = Arc::new(Mutex::new(0));
+    let server_refused = refused.clone();
+    let address = start_canned_server(move |head| {
+        if head.starts_with("GET / ") {
+            return Some("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string());
+        }
+        *server_refused.lock().unwrap() += 1;
+        Some(format!(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\n\
+            Content-Length: 0\r\n\r\n",
+            retry_after
+        ))
+    })
+    .await;
+    (address, refused)
+}
+
+/// An upstream that answers 503 with a Retry-After shouldn't be sent any requests until that time
+/// has passed.
+#[tokio::test]
+async fn test_retry_after_skips_upstream() {
+    init_logging();
+    let (unavailable_upstream, refused) = start_unavailable_server(5).await;
+    let working_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&unavailable_upstream, &working_upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+    sleep(Duration::from_secs(2)).await;
+
+    // Requests go to a random upstream, so keep going until one lands on the unavailable one
+    let mut attempts = 0;
+    while get_status(&balancebeam, "/busy").await != 503 {
+        attempts += 1;
+        assert!(attempts < 50, "No request was sent to the unavailable upstream");
+    }
+    assert_eq!(*refused.lock().unwrap(), 1);
+
+    // For the next few seconds, everything goes to the working upstream
+    for _ in 0..10 {
+        assert_eq!(get_status(&balancebeam, "/busy").await, 200);
+    }
+    assert_eq!(*refused.lock().unwrap(), 1);
+
+    // Once Retry-After has passed, the upstream is picked again
+    sleep(Duration::from_secs(5)).await;
+    let mut attempts = 0;
+    while get_status(&balancebeam, "/busy").await != 503 {
+        attempts += 1;
+        assert!(attempts < 50, "The unavailable upstream was never used again");
+    }
+
+    Box::new(working_upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts a server that answers every request with `name` as its body, so tests can tell which
+/// upstream a request went to. Returns its address.
+async fn start_named_server(name: &'static str) -> String {
+    start_canned_server(move |_| {
+        Some(format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            name.len(),
+            name
+        ))
+    })
+    .await
+}
+
+/// Sends a POST to one of the drain admin endpoints and returns the status and body.
+async fn post_admin(balancebeam: &BalanceBeam, path: &str, upstream: &str) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .post(&format!(
+            "http://{}{}?upstream={}",
+            balancebeam.address, path, upstream
+        ))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// A drained upstream gets no new requests until it is undrained.
+#[tokio::test]
+async fn test_drain_upstream() {
+    init_logging();
+    let drained_upstream = start_named_server("drained").await;
+    let other_upstream = start_named_server("other").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&drained_upstream, &other_upstream],
+        &["--active-health-check-interval", "1", "--allow-drain"],
+    )
+    .await;
+    sleep(Duration::from_secs(2)).await;
+
+    let (status, body) = post_admin(&balancebeam, "/_admin/drain", &drained_upstream).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, format!("[\"{}\"]", drained_upstream));
+    for _ in 0..20 {
+        let response_text = balancebeam
+            .get("/who")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "other");
+    }
+    // Unknown upstreams can't be drained
+    let (status, _) = post_admin(&balancebeam, "/_admin/drain", "127.0.0.1:1").await;
+    assert_eq!(status, 404);
+
+    let (status, body) = post_admin(&balancebeam, "/_admin/undrain", &drained_upstream).await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "[]");
+    let mut attempts = 0;
+    while balancebeam.get("/who").await.unwrap() != "drained" {
+        attempts += 1;
+        assert!(attempts < 50, "The undrained upstream was never used again");
+    }
+
+    log::info!("All done :)");
+}
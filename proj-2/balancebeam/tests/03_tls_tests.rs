@@ -0,0 +1,110 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Generates a self-signed certificate for localhost and writes it and its private key to
+/// temporary PEM files, returning the (certificate, key) paths.
+fn write_self_signed_cert() -> (String, String) {
+    let cert =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_string(), "127.0.0.1".to_string()])
+            .expect("Could not generate self-signed certificate");
+    let id: u32 = rand::random();
+    let cert_path = std::env::temp_dir().join(format!("balancebeam-test-{}.crt", id));
+    let key_path = std::env::temp_dir().join(format!("balancebeam-test-{}.key", id));
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).expect("Could not write cert");
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).expect("Could not write key");
+    (
+        cert_path.to_str().unwrap().to_string(),
+        key_path.to_str().unwrap().to_string(),
+    )
+}
+
+/// Connect to balancebeam over TLS and make sure the request is proxied to a plain HTTP upstream.
+#[tokio::test]
+async fn test_tls_client_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (cert_path, key_path) = write_self_signed_cert();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--tls-cert",
+            &cert_path,
+            "--tls-key",
+            &key_path,
+        ],
+    )
+    .await;
+
+    log::info!("Sending a GET request over TLS");
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let response_text = client
+        .get(format!("https://{}/tls_url", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam over TLS")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("GET /tls_url HTTP/1.1"));
+    assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+
+    log::info!("Making sure plain HTTP is not accepted on the TLS port");
+    assert!(balancebeam.get("/plain_url").await.is_err());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Put a TLS-terminating balancebeam in front of an echo server and make sure a second balancebeam
+/// started with --upstream-tls can proxy to it.
+#[tokio::test]
+async fn test_tls_upstream_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (cert_path, key_path) = write_self_signed_cert();
+    let tls_upstream = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--tls-cert",
+            &cert_path,
+            "--tls-key",
+            &key_path,
+        ],
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&tls_upstream.address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--upstream-tls",
+            "--upstream-tls-insecure",
+        ],
+    )
+    .await;
+    // Give both instances a chance to mark their upstreams as healthy
+    sleep(Duration::from_secs(2)).await;
+
+    log::info!("Sending a GET request that is forwarded to the upstream over TLS");
+    let response_text = balancebeam
+        .get("/tls_upstream_url")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /tls_upstream_url HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Writes a raw request to balancebeam and returns the status line and headers of its response.
+async fn send_raw_request(balancebeam: &BalanceBeam, raw_request: &str) -> String {
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(raw_request.as_bytes())
+        .await
+        .expect("Could not send request to balancebeam");
+    let mut response = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream
+            .read(&mut buf)
+            .await
+            .expect("Error reading response from balancebeam");
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Sends a request with ambiguous framing and makes sure balancebeam answers 400 itself.
+async fn assert_rejected(raw_request: &str) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    let response = send_raw_request(&balancebeam, raw_request).await;
+    assert!(
+        response.starts_with("HTTP/1.1 400"),
+        "Expected 400 Bad Request, got: {}",
+        response
+    );
+
+    Box::new(upstream).stop().await;
+}
+
+/// Content-Length and Transfer-Encoding together could be framed differently by the upstream.
+#[tokio::test]
+async fn test_reject_content_length_with_transfer_encoding() {
+    assert_rejected(
+        "POST /smuggle HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\
+        Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    )
+    .await;
+}
+
+/// Two Content-Length headers, even with the same value, are rejected.
+#[tokio::test]
+async fn test_reject_duplicate_content_length() {
+    assert_rejected(
+        "POST /smuggle HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+        Content-Length: 5\r\n\r\nhello",
+    )
+    .await;
+}
+
+/// Conflicting Content-Length headers are rejected.
+#[tokio::test]
+async fn test_reject_conflicting_content_length() {
+    assert_rejected(
+        "POST /smuggle HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\
+        Content-Length: 11\r\n\r\nhello world",
+    )
+    .await;
+}
+
+/// A Content-Length holding a list of values is rejected.
+#[tokio::test]
+async fn test_reject_content_length_list() {
+    assert_rejected(
+        "POST /smuggle HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5, 5\r\n\r\nhello",
+    )
+    .await;
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::sleep;
+
+/// Starts a plain TCP server that writes back whatever it receives. Returns its address.
+async fn start_tcp_echo_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    address
+}
+
+/// Reads from the stream until the end of an HTTP response head.
+async fn read_response_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        let n = stream
+            .read(&mut byte)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(n > 0, "balancebeam closed the connection early");
+        head.push(byte[0]);
+    }
+    String::from_utf8_lossy(&head).to_string()
+}
+
+/// Open a tunnel through balancebeam with CONNECT and make sure bytes make it to the target and
+/// back.
+#[tokio::test]
+async fn test_connect_tunnel() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let target = start_tcp_echo_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1", "--allow-connect"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes())
+        .await
+        .unwrap();
+    let head = read_response_head(&mut stream).await;
+    assert!(
+        head.starts_with("HTTP/1.1 200"),
+        "Expected the tunnel to be established, got: {}",
+        head
+    );
+
+    log::info!("Sending data through the tunnel");
+    stream.write_all(b"hello through the tunnel").await.unwrap();
+    let mut echoed = [0u8; 24];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello through the tunnel");
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Without --allow-connect, CONNECT is refused.
+#[tokio::test]
+async fn test_connect_refused_by_default() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let target = start_tcp_echo_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "1"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", target).as_bytes())
+        .await
+        .unwrap();
+    let head = read_response_head(&mut stream).await;
+    assert!(
+        head.starts_with("HTTP/1.1 405"),
+        "Expected CONNECT to be refused, got: {}",
+        head
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that passes health checks and accepts WebSocket upgrades, after which it
+/// echoes back whatever it receives. Returns its address.
+async fn start_websocket_echo_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let head = read_response_head(&mut stream).await;
+                if !head.to_lowercase().contains("upgrade: websocket") {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    return;
+                }
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                        Connection: Upgrade\r\n\
+                        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n",
+                    )
+                    .await;
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    address
+}
+
+/// Upgrade a connection to WebSocket through balancebeam and make sure frames are relayed both
+/// ways afterwards.
+#[tokio::test]
+async fn test_websocket_upgrade() {
+    init_logging();
+    let upstream = start_websocket_echo_server().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "1"]).await;
+    // Wait for the upstream to pass a health check
+    sleep(Duration::from_secs(2)).await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            format!(
+                "GET /chat HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\n\
+                Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 13\r\n\r\n",
+                balancebeam.address
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = read_response_head(&mut stream).await;
+    assert!(
+        head.starts_with("HTTP/1.1 101"),
+        "Expected the upgrade to succeed, got: {}",
+        head
+    );
+    assert!(head.to_lowercase().contains("upgrade: websocket"));
+
+    log::info!("Sending frames over the upgraded connection");
+    // Masked text frames carrying "hello" and "again"
+    for payload in [b"hello", b"again"] {
+        let mask = [0x37u8, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![0x81u8, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        stream.write_all(&frame).await.unwrap();
+        let mut echoed = vec![0u8; frame.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, frame);
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use common::{init_logging, start_canned_server, BalanceBeam};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Starts an upstream that advertises a Content-Length on bodyless responses and then sends
+/// nothing, keeping the connection open. A proxy that tries to read a body from these responses
+/// will hang. It answers:
+///
+/// * GET / (health checks): 200 with an empty body
+/// * HEAD anything: 200 with Content-Length: 10 and no body
+/// * GET /not-modified: 304 with Content-Length: 10 and no body
+async fn start_bodyless_server() -> String {
+    start_canned_server(|head| {
+        let reply = if head.starts_with("HEAD ") {
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n"
+        } else if head.starts_with("GET /not-modified ") {
+            "HTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\n\r\n"
+        } else {
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        };
+        Some(reply.to_string())
+    })
+    .await
+}
+
+async fn setup() -> BalanceBeam {
+    init_logging();
+    let upstream = start_bodyless_server().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "1"]).await;
+    // Wait for the upstream to pass a health check
+    sleep(Duration::from_secs(2)).await;
+    balancebeam
+}
+
+fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap()
+}
+
+/// The response to a HEAD request has no body, whatever its Content-Length says.
+#[tokio::test]
+async fn test_head_response_has_no_body() {
+    let balancebeam = setup().await;
+    let response = client()
+        .head(&format!("http://{}/head_url", balancebeam.address))
+        .send()
+        .await
+        .expect("balancebeam did not answer the HEAD request (waiting for a body?)");
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    log::info!("All done :)");
+}
+
+/// A 304 response has no body, whatever its Content-Length says.
+#[tokio::test]
+async fn test_not_modified_response_has_no_body() {
+    let balancebeam = setup().await;
+    let response = client()
+        .get(&format!("http://{}/not-modified", balancebeam.address))
+        .send()
+        .await
+        .expect("balancebeam did not answer with the 304 (waiting for a body?)");
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_MODIFIED);
+    log::info!("All done :)");
+}
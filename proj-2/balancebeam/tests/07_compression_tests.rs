@@ -0,0 +1,103 @@
+mod common;
+
+use common::{init_logging, start_canned_server, BalanceBeam};
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::time::Duration;
+use tokio::time::sleep;
+
+const TEXT_BODY: &str =
+    "Hello from the upstream! Hello from the upstream! Hello from the upstream!";
+
+/// Starts an upstream that answers every request with a plain text body.
+async fn start_text_server() -> String {
+    start_canned_server(|_| {
+        Some(format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\n\
+            Content-Length: {}\r\n\r\n{}",
+            TEXT_BODY.len(),
+            TEXT_BODY
+        ))
+    })
+    .await
+}
+
+async fn setup(extra_args: &[&str]) -> BalanceBeam {
+    init_logging();
+    let upstream = start_text_server().await;
+    let mut args = vec!["--active-health-check-interval", "1"];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream], &args).await;
+    // Wait for the upstream to pass a health check
+    sleep(Duration::from_secs(2)).await;
+    balancebeam
+}
+
+async fn get_with_accept_encoding(balancebeam: &BalanceBeam, encoding: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(&format!("http://{}/text", balancebeam.address))
+        .header("accept-encoding", encoding)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// A text response to a client accepting gzip comes back gzipped and decodes to the original.
+#[tokio::test]
+async fn test_gzip_text_response() {
+    let balancebeam = setup(&["--enable-compression"]).await;
+    let response = get_with_accept_encoding(&balancebeam, "gzip, deflate").await;
+    assert_eq!(
+        response
+            .headers()
+            .get("content-encoding")
+            .map(|value| value.to_str().unwrap()),
+        Some("gzip")
+    );
+    let compressed = response.bytes().await.unwrap();
+    let mut decoded = String::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_string(&mut decoded)
+        .expect("Response body is not valid gzip");
+    assert_eq!(decoded, TEXT_BODY);
+    log::info!("All done :)");
+}
+
+/// Clients that don't accept gzip, or proxies without --enable-compression, get the plain body.
+#[tokio::test]
+async fn test_no_gzip_unless_accepted_and_enabled() {
+    let balancebeam = setup(&["--enable-compression"]).await;
+    let response = get_with_accept_encoding(&balancebeam, "identity").await;
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.text().await.unwrap(), TEXT_BODY);
+
+    let balancebeam = setup(&[]).await;
+    let response = get_with_accept_encoding(&balancebeam, "gzip").await;
+    assert!(response.headers().get("content-encoding").is_none());
+    assert_eq!(response.text().await.unwrap(), TEXT_BODY);
+    log::info!("All done :)");
+}
+
+/// --rewrite-body rules are applied to text bodies in the order given, and Content-Length
+/// matches the rewritten body.
+#[tokio::test]
+async fn test_rewrite_body() {
+    let balancebeam = setup(&[
+        "--rewrite-body",
+        "Hello=Hi",
+        "--rewrite-body",
+        "Hi from the upstream=Greetings from the proxy",
+    ])
+    .await;
+    let response = get_with_accept_encoding(&balancebeam, "identity").await;
+    let expected = TEXT_BODY.replace("Hello from the upstream", "Greetings from the proxy");
+    assert_eq!(
+        response
+            .headers()
+            .get("content-length")
+            .map(|value| value.to_str().unwrap().to_string()),
+        Some(expected.len().to_string())
+    );
+    assert_eq!(response.text().await.unwrap(), expected);
+    log::info!("All done :)");
+}
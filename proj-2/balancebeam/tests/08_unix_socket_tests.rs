@@ -0,0 +1,70 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+use tokio::time::sleep;
+
+/// Starts an upstream listening on a Unix socket that answers every request with the request's
+/// head as the body. Returns the socket path.
+async fn start_unix_echo_server() -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}-{}.sock",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let listener = UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut pending = Vec::new();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let head_end = match pending.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(pos) => pos + 4,
+                        None => match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => {
+                                pending.extend_from_slice(&buf[..n]);
+                                continue;
+                            }
+                        },
+                    };
+                    let head: Vec<u8> = pending.drain(..head_end).collect();
+                    let mut reply =
+                        format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", head.len())
+                            .into_bytes();
+                    reply.extend_from_slice(&head);
+                    if stream.write_all(&reply).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    path
+}
+
+/// Requests should be proxied to an upstream given as unix:PATH.
+#[tokio::test]
+async fn test_unix_socket_upstream() {
+    init_logging();
+    let socket_path = start_unix_echo_server().await;
+    let upstream = format!("unix:{}", socket_path.to_str().unwrap());
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "1"]).await;
+    // Wait for the upstream to pass a health check
+    sleep(Duration::from_secs(2)).await;
+
+    log::info!("Sending a GET request");
+    let response_text = balancebeam
+        .get("/unix_url")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET /unix_url HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+
+    std::fs::remove_file(socket_path).unwrap();
+    log::info!("All done :)");
+}
@@ -1,5 +1,6 @@
 use rand::Rng;
 // use std::time::Duration;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
@@ -9,6 +10,8 @@ pub struct BalanceBeam {
     #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Every line balancebeam has printed so far
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -20,11 +23,27 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
+        let mut args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams plus arbitrary extra command-line arguments.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
@@ -32,14 +51,7 @@ impl BalanceBeam {
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
-        }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
@@ -51,10 +63,12 @@ impl BalanceBeam {
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let output = Arc::new(Mutex::new(Vec::new()));
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let stdout_output = output.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -63,12 +77,14 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let stderr_output = output.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -77,12 +93,54 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         sleep(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    /// Returns whether balancebeam has printed a line containing `text`.
+    #[allow(dead_code)]
+    pub fn output_contains(&self, text: &str) -> bool {
+        self.output
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line.contains(text))
+    }
+
+    /// Sends a signal to the balancebeam process.
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id().unwrap() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Could not signal balancebeam");
+    }
+
+    /// Runs balancebeam to completion (e.g. with --check-config) and returns its output.
+    #[allow(dead_code)]
+    pub async fn run_to_completion(
+        upstreams: &[&str],
+        extra_args: &[&str],
+    ) -> std::process::Output {
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        for upstream in upstreams {
+            cmd.arg("--upstream").arg(upstream);
+        }
+        cmd.args(extra_args);
+        cmd.output().await.unwrap_or_else(|err| {
+            panic!(
+                "Could not execute balancebeam binary {}: {}",
+                BalanceBeam::target_bin_path().to_str().unwrap(),
+                err
+            )
+        })
     }
 
     #[allow(dead_code)]
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Starts an upstream for responses that hyper wouldn't send (or no response at all). Each
+/// request head (request line and headers, without the final blank line) read from a connection
+/// is passed to `reply`, and whatever it returns is written back as-is. Request bodies are not
+/// read. If `reply` returns None, or the reply has a `Connection: close` header, the server hangs
+/// up afterwards. Returns the server's address.
+#[allow(dead_code)]
+pub async fn start_canned_server<F>(reply: F) -> String
+where
+    F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let reply = Arc::new(reply);
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let reply = reply.clone();
+            tokio::spawn(async move {
+                let mut pending = Vec::new();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let head_end = match pending.windows(4).position(|w| w == b"\r\n\r\n") {
+                        Some(pos) => pos,
+                        None => match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => {
+                                pending.extend_from_slice(&buf[..n]);
+                                continue;
+                            }
+                        },
+                    };
+                    let head: Vec<u8> = pending.drain(..head_end + 4).collect();
+                    let head = String::from_utf8_lossy(&head[..head_end]);
+                    let response = match reply(&head) {
+                        Some(response) => response,
+                        None => return,
+                    };
+                    if stream.write_all(response.as_bytes()).await.is_err()
+                        || response.contains("\r\nConnection: close\r\n")
+                    {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
@@ -1,4 +1,5 @@
 mod balancebeam;
+mod canned_server;
 mod echo_server;
 mod error_server;
 mod server;
@@ -6,6 +7,7 @@ mod server;
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
+pub use canned_server::start_canned_server;
 pub use echo_server::EchoServer;
 pub use error_server::ErrorServer;
 pub use server::Server;
@@ -1,27 +1,50 @@
 use grid::Grid;
+use std::borrow::Cow;
 use std::cmp::max;
 // For lcs()
 use std::env;
 use std::error::Error;
-use std::fs::File; // For read_file_lines()
-use std::io::{self, BufRead}; // For read_file_lines()
+use std::fs::File; // For read_file_lines_bytes()
+use std::io::{self, BufRead, Write}; // For read_file_lines_bytes()
 use std::process;
 
 pub mod grid;
 
-/// Reads the file at the supplied path, and returns a vector of strings.
-fn read_file_lines(filename: &String) -> Result<Vec<String>, io::Error> {
-    // Be sure to delete the #[allow(unused)] line above
+/// Width of side-by-side output when --width isn't given, the same as diff's.
+const DEFAULT_WIDTH: usize = 130;
+
+/// Reads the file at the supplied path, and returns its lines as raw bytes, so that files that
+/// aren't valid UTF-8 (Latin-1 text, binaries) can still be compared. Like BufRead::lines, the
+/// "\n" or "\r\n" ending each line is dropped.
+fn read_file_lines_bytes(filename: &String) -> Result<Vec<Vec<u8>>, io::Error> {
     let file = File::open(filename)?;
-    let mut file_vec: Vec<String> = vec![];
-    for line in io::BufReader::new(file).lines() {
-        let content = line?;
+    let mut file_vec: Vec<Vec<u8>> = vec![];
+    for line in io::BufReader::new(file).split(b'\n') {
+        let mut content = line?;
+        if content.last() == Some(&b'\r') {
+            content.pop();
+        }
         file_vec.push(content);
     }
     Ok(file_vec)
 }
 
-fn lcs(seq1: &Vec<String>, seq2: &Vec<String>) -> Grid {
+/// Whether a file looks like a binary rather than text. Like diff, we take a NUL byte as the sign.
+fn is_binary(lines: &[Vec<u8>]) -> bool {
+    lines.iter().any(|line| line.contains(&0))
+}
+
+/// Prints a line with the given prefix, writing its bytes as they are even if they aren't UTF-8.
+fn print_line(prefix: &str, line: &[u8]) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(prefix.as_bytes());
+    let _ = stdout.write_all(line);
+    let _ = stdout.write_all(b"\n");
+}
+
+/// Builds the longest-common-subsequence table for any two sequences whose elements can be
+/// compared, e.g. the lines of two files or the words of two lines.
+fn lcs<T: PartialEq>(seq1: &[T], seq2: &[T]) -> Grid {
     // Note: Feel free to use unwrap() in this code, as long as you're basically certain it'll
     // never happen. Conceptually, unwrap() is justified here, because there's not really any error
     // condition you're watching out for (i.e. as long as your code is written correctly, nothing
@@ -38,9 +61,9 @@ fn lcs(seq1: &Vec<String>, seq2: &Vec<String>) -> Grid {
         let _ = grid.set(0, j, 0);
     }
 
-    for i in 0..seq1_len {
-        for j in 0..seq2_len {
-            if seq1[i] == seq2[j] {
+    for (i, line1) in seq1.iter().enumerate() {
+        for (j, line2) in seq2.iter().enumerate() {
+            if line1 == line2 {
                 let val = grid.get(i, j).unwrap();
                 let _ = grid.set(i + 1, j + 1, val + 1);
             } else {
@@ -52,41 +75,391 @@ fn lcs(seq1: &Vec<String>, seq2: &Vec<String>) -> Grid {
     grid
 }
 
-fn print_diff(lcs_table: &Grid, lines1: &Vec<String>, lines2: &Vec<String>, i: usize, j: usize) {
+/// Prints the diff of the first i lines of lines1 and the first j lines of lines2. Returns whether
+/// any added or removed lines were printed.
+fn print_diff<T: PartialEq + AsRef<[u8]>>(
+    lcs_table: &Grid,
+    lines1: &[T],
+    lines2: &[T],
+    i: usize,
+    j: usize,
+) -> bool {
     // Be sure to delete the #[allow(unused)] line above
     if i > 0 && j > 0 && lines1[i - 1] == lines2[j - 1] {
-        print_diff(lcs_table, lines1, lines2, i - 1, j - 1);
-        println!(" {}", lines1[i - 1]);
+        let differs = print_diff(lcs_table, lines1, lines2, i - 1, j - 1);
+        print_line(" ", lines1[i - 1].as_ref());
+        differs
     } else if j > 0 && (i == 0 || lcs_table.get(i, j - 1) >= lcs_table.get(i - 1, j)) {
         print_diff(lcs_table, lines1, lines2, i, j - 1);
-        println!("> {}", lines2[j - 1]);
+        print_line("> ", lines2[j - 1].as_ref());
+        true
     } else if i > 0 && (j == 0 || lcs_table.get(i, j - 1) < lcs_table.get(i - 1, j)) {
         print_diff(lcs_table, lines1, lines2, i - 1, j);
-        println!("< {}", lines1[i - 1]);
+        print_line("< ", lines1[i - 1].as_ref());
+        true
     } else {
-        println!("");
+        println!();
+        false
+    }
+}
+
+/// One step in turning the first sequence into the second.
+#[derive(Debug, PartialEq)]
+enum Edit<'a, T> {
+    Same(&'a T),
+    Removed(&'a T),
+    Added(&'a T),
+}
+
+/// Walks the LCS table back from the end to list the edits between two sequences, in order. Like
+/// print_diff, a removal is listed before the addition that replaces it.
+fn diff_edits<'a, T: PartialEq>(
+    lcs_table: &Grid,
+    seq1: &'a [T],
+    seq2: &'a [T],
+) -> Vec<Edit<'a, T>> {
+    let (mut i, mut j) = (seq1.len(), seq2.len());
+    let mut edits = Vec::new();
+    while i > 0 || j > 0 {
+        if i > 0 && j > 0 && seq1[i - 1] == seq2[j - 1] {
+            edits.push(Edit::Same(&seq1[i - 1]));
+            i -= 1;
+            j -= 1;
+        } else if j > 0 && (i == 0 || lcs_table.get(i, j - 1) >= lcs_table.get(i - 1, j)) {
+            edits.push(Edit::Added(&seq2[j - 1]));
+            j -= 1;
+        } else {
+            edits.push(Edit::Removed(&seq1[i - 1]));
+            i -= 1;
+        }
+    }
+    edits.reverse();
+    edits
+}
+
+/// Appends the pending removed and added words to `output` as a single `[-old-]{+new+}` change.
+fn flush_word_change(removed: &mut Vec<&str>, added: &mut Vec<&str>, output: &mut Vec<String>) {
+    let mut change = String::new();
+    if !removed.is_empty() {
+        change.push_str(&format!("[-{}-]", removed.join(" ")));
+    }
+    if !added.is_empty() {
+        change.push_str(&format!("{{+{}+}}", added.join(" ")));
+    }
+    if !change.is_empty() {
+        output.push(change);
+    }
+    removed.clear();
+    added.clear();
+}
+
+/// Shows how line1 became line2 word by word, marking removed words as `[-old-]` and added ones
+/// as `{+new+}`. Words are re-joined with single spaces.
+fn word_diff(line1: &str, line2: &str) -> String {
+    let words1: Vec<&str> = line1.split_whitespace().collect();
+    let words2: Vec<&str> = line2.split_whitespace().collect();
+    let lcs_table = lcs(&words1, &words2);
+
+    let mut output: Vec<String> = Vec::new();
+    let mut removed: Vec<&str> = Vec::new();
+    let mut added: Vec<&str> = Vec::new();
+    for edit in diff_edits(&lcs_table, &words1, &words2) {
+        match edit {
+            Edit::Same(word) => {
+                flush_word_change(&mut removed, &mut added, &mut output);
+                output.push(word.to_string());
+            }
+            Edit::Removed(word) => removed.push(word),
+            Edit::Added(word) => added.push(word),
+        }
+    }
+    flush_word_change(&mut removed, &mut added, &mut output);
+    output.join(" ")
+}
+
+/// Lines the edits up in rows of (marker, left line, right line), the way diff -y shows them: " "
+/// for lines in both files, "|" for a removed line paired with the added line that replaced it,
+/// and "<" or ">" for lines only in the first or second file.
+fn aligned_rows<'a, T>(edits: &[Edit<'a, T>]) -> Vec<(char, Option<&'a T>, Option<&'a T>)> {
+    let mut rows = Vec::new();
+    let mut idx = 0;
+    while idx < edits.len() {
+        if let Edit::Same(line) = edits[idx] {
+            rows.push((' ', Some(line), Some(line)));
+            idx += 1;
+            continue;
+        }
+        // Gather the run of removals and the run of additions that follows it, then pair them up
+        let mut removed = Vec::new();
+        while let Some(Edit::Removed(line)) = edits.get(idx) {
+            removed.push(*line);
+            idx += 1;
+        }
+        let mut added = Vec::new();
+        while let Some(Edit::Added(line)) = edits.get(idx) {
+            added.push(*line);
+            idx += 1;
+        }
+        for pos in 0..max(removed.len(), added.len()) {
+            let (old, new) = (removed.get(pos).copied(), added.get(pos).copied());
+            let marker = match (old, new) {
+                (Some(_), Some(_)) => '|',
+                (Some(_), None) => '<',
+                (None, Some(_)) => '>',
+                (None, None) => unreachable!(),
+            };
+            rows.push((marker, old, new));
+        }
+    }
+    rows
+}
+
+/// Prints a line diff in which each removed line that was replaced by an added line is shown once,
+/// prefixed with "~", with only the changed words marked. Returns whether anything differed. Words
+/// are split on whitespace, so bytes that aren't UTF-8 are shown as U+FFFD on "~" lines.
+fn print_word_diff<T: PartialEq + AsRef<[u8]>>(lines1: &[T], lines2: &[T]) -> bool {
+    let lcs_table = lcs(lines1, lines2);
+    let edits = diff_edits(&lcs_table, lines1, lines2);
+    let mut differs = false;
+    for row in aligned_rows(&edits) {
+        match row {
+            (' ', Some(line), _) => print_line(" ", line.as_ref()),
+            (_, Some(old), Some(new)) => println!(
+                "~ {}",
+                word_diff(
+                    &String::from_utf8_lossy(old.as_ref()),
+                    &String::from_utf8_lossy(new.as_ref())
+                )
+            ),
+            (_, Some(old), None) => print_line("< ", old.as_ref()),
+            (_, None, Some(new)) => print_line("> ", new.as_ref()),
+            (_, None, None) => unreachable!(),
+        }
+        differs |= row.0 != ' ';
+    }
+    differs
+}
+
+/// Formats one row of side-by-side output: each line is cut off to fit its column, and the marker
+/// sits in a gutter between them. The row fits in `width` columns and has no trailing spaces.
+fn side_by_side_row(marker: char, left: &str, right: &str, width: usize) -> String {
+    let column = width.saturating_sub(3) / 2;
+    let left: String = left.chars().take(column).collect();
+    let right: String = right.chars().take(column).collect();
+    let row = format!("{:<column$} {} {}", left, marker, right, column = column);
+    row.trim_end().to_string()
+}
+
+/// Prints the two files next to each other in columns, like diff -y. Lines that aren't UTF-8 are
+/// shown with U+FFFD, since they have to be cut to a number of characters. Returns whether
+/// anything differed.
+fn print_side_by_side<T: PartialEq + AsRef<[u8]>>(
+    lines1: &[T],
+    lines2: &[T],
+    options: &DiffOptions,
+) -> bool {
+    let lcs_table = lcs(lines1, lines2);
+    let edits = diff_edits(&lcs_table, lines1, lines2);
+    let mut differs = false;
+    for (marker, left, right) in aligned_rows(&edits) {
+        let text = |line: Option<&T>| {
+            line.map(|line| String::from_utf8_lossy(line.as_ref()).into_owned())
+                .unwrap_or_default()
+        };
+        println!(
+            "{}",
+            side_by_side_row(marker, &text(left), &text(right), options.width)
+        );
+        differs |= marker != ' ';
+    }
+    differs
+}
+
+/// Reads a file for diffing, exiting with diff's "trouble" status (2) if it can't be read.
+fn read_file_lines_or_exit(filename: &String) -> Vec<Vec<u8>> {
+    match read_file_lines_bytes(filename) {
+        Ok(lines) => lines,
+        Err(err) => {
+            eprintln!("rdiff: {}: {}", filename, err);
+            process::exit(2);
+        }
+    }
+}
+
+/// Whether two files differ, without building the LCS grid: files with different line counts
+/// can't be identical, otherwise compare line by line and stop at the first difference.
+fn files_differ<T: PartialEq>(seq1: &[T], seq2: &[T]) -> bool {
+    seq1.len() != seq2.len() || seq1.iter().zip(seq2).any(|(line1, line2)| line1 != line2)
+}
+
+/// A line of a file along with the form it's compared in, which with -i is the line case-folded.
+/// Folding once per line keeps it out of the LCS loop, which compares every pair of lines. Lines
+/// count as the same when their comparison forms are, but are printed as they were read.
+struct Line<'a> {
+    text: &'a [u8],
+    key: Cow<'a, [u8]>,
+}
+
+impl PartialEq for Line<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
     }
 }
 
+impl AsRef<[u8]> for Line<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self.text
+    }
+}
+
+/// Lowercases a line for -i. Lines that aren't UTF-8 can only have their ASCII letters folded.
+fn fold_case(line: &[u8]) -> Vec<u8> {
+    match std::str::from_utf8(line) {
+        Ok(text) => text.to_lowercase().into_bytes(),
+        Err(_) => line.to_ascii_lowercase(),
+    }
+}
+
+/// Pairs each line with the form it's compared in.
+fn comparable_lines<'a, T: AsRef<[u8]>>(lines: &'a [T], options: &DiffOptions) -> Vec<Line<'a>> {
+    lines
+        .iter()
+        .map(|line| {
+            let text = line.as_ref();
+            let key = if options.ignore_case {
+                Cow::Owned(fold_case(text))
+            } else {
+                Cow::Borrowed(text)
+            };
+            Line { text, key }
+        })
+        .collect()
+}
+
+/// How two files should be compared and reported, as chosen on the command line.
+#[derive(Default)]
+struct DiffOptions {
+    /// Like diff -q, only report whether the files differ
+    brief: bool,
+    /// Like diff -s, say so when there are no differences
+    report_identical: bool,
+    /// Mark the changed words within lines that were edited
+    word_diff: bool,
+    /// Like diff -i, treat lines that differ only in case as the same
+    ignore_case: bool,
+    /// Like diff -a, diff files line by line even if they look binary
+    text: bool,
+    /// Like diff -y, show the files next to each other in two columns
+    side_by_side: bool,
+    /// Total width of side-by-side output, in characters
+    width: usize,
+}
+
+/// Compares two files and prints the result. Returns whether they differ.
+fn diff_files(filename1: &String, filename2: &String, options: &DiffOptions) -> bool {
+    let seq1 = read_file_lines_or_exit(filename1);
+    let seq2 = read_file_lines_or_exit(filename2);
+    let binary = !options.text && (is_binary(&seq1) || is_binary(&seq2));
+    let lines1 = comparable_lines(&seq1, options);
+    let lines2 = comparable_lines(&seq2, options);
+
+    // Identical files have nothing to show, so don't bother building the grid for them
+    if !files_differ(&lines1, &lines2) {
+        if options.report_identical {
+            println!("Files {} and {} are identical", filename1, filename2);
+        }
+        return false;
+    }
+    if binary {
+        println!("Binary files {} and {} differ", filename1, filename2);
+        return true;
+    }
+    if options.brief {
+        println!("Files {} and {} differ", filename1, filename2);
+        return true;
+    }
+    if options.word_diff {
+        return print_word_diff(&lines1, &lines2);
+    }
+    if options.side_by_side {
+        return print_side_by_side(&lines1, &lines2, options);
+    }
+
+    let grid = lcs(&lines1, &lines2);
+    print_diff(&grid, &lines1, &lines2, lines1.len(), lines2.len())
+}
+
 #[allow(unused)] // TODO: delete this line when you implement this function
 fn main() -> Result<(), Box<dyn Error>> {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
+    let flags = [
+        "--brief",
+        "-q",
+        "--report-identical",
+        "-s",
+        "--word-diff",
+        "--pairwise",
+        "--ignore-case",
+        "-i",
+        "--text",
+        "-a",
+        "--side-by-side",
+        "-y",
+    ];
+    let has_flag = |names: &[&str]| args.iter().skip(1).any(|arg| names.contains(&arg.as_str()));
+    let mut options = DiffOptions {
+        brief: has_flag(&["--brief", "-q"]),
+        report_identical: has_flag(&["--report-identical", "-s"]),
+        word_diff: has_flag(&["--word-diff"]),
+        ignore_case: has_flag(&["--ignore-case", "-i"]),
+        text: has_flag(&["--text", "-a"]),
+        side_by_side: has_flag(&["--side-by-side", "-y"]),
+        width: DEFAULT_WIDTH,
+    };
+    // --pairwise diffs each file against the next one: f1 with f2, f2 with f3, and so on
+    let pairwise = has_flag(&["--pairwise"]);
+    let mut filenames: Vec<&String> = Vec::new();
+    let mut arg_iter = args.iter().skip(1);
+    while let Some(arg) = arg_iter.next() {
+        // --width takes a value, either as the next argument or after an "="
+        let width = match arg.strip_prefix("--width") {
+            Some("") => arg_iter.next().map(|value| value.as_str()),
+            Some(value) if value.starts_with('=') => Some(&value[1..]),
+            _ => {
+                if !flags.contains(&arg.as_str()) {
+                    filenames.push(arg);
+                }
+                continue;
+            }
+        };
+        options.width = match width.and_then(|width| width.parse().ok()) {
+            Some(width) => width,
+            None => {
+                println!("--width needs a number of columns.");
+                process::exit(2);
+            }
+        };
+    }
+    if filenames.len() < 2 {
         println!("Too few arguments.");
-        process::exit(1);
+        process::exit(2);
     }
-    let filename1 = &args[1];
-    let filename2 = &args[2];
 
     // Be sure to delete the #[allow(unused)] line above
-    let seq1 = read_file_lines(filename1)?;
-    let seq2 = read_file_lines(filename2)?;
-    let grid = lcs(&seq1, &seq2);
-
-    let seq1_len = seq1.len();
-    let seq2_len = seq2.len();
-    print_diff(&grid, &seq1, &seq2, seq1_len, seq2_len);
-    
+    // Like diff, exit with 0 if the files are identical, 1 if they differ and 2 on trouble
+    let differs = if pairwise {
+        let mut any_differ = false;
+        for pair in filenames.windows(2) {
+            println!("diff {} {}", pair[0], pair[1]);
+            any_differ |= diff_files(pair[0], pair[1], &options);
+        }
+        any_differ
+    } else {
+        diff_files(filenames[0], filenames[1], &options)
+    };
+    if differs {
+        process::exit(1);
+    }
 
     Ok(())
 }
@@ -97,13 +470,113 @@ mod test {
 
     #[test]
     fn test_read_file_lines() {
-        let lines_result = read_file_lines(&String::from("handout-a.txt"));
+        let lines_result = read_file_lines_bytes(&String::from("handout-a.txt"));
         assert!(lines_result.is_ok());
         let lines = lines_result.unwrap();
         assert_eq!(lines.len(), 8);
         assert_eq!(
             lines[0],
-            "This week's exercises will continue easing you into Rust and will feature some"
+            b"This week's exercises will continue easing you into Rust and will feature some"
+        );
+        assert!(!is_binary(&lines));
+    }
+
+    #[test]
+    fn test_lines_not_utf8() {
+        let latin1 = vec![b"caf\xe9".to_vec(), b"same".to_vec()];
+        let other = vec![b"caf\xe8".to_vec(), b"same".to_vec()];
+        assert!(files_differ(&latin1, &other));
+        assert!(!files_differ(&latin1, &latin1.clone()));
+        assert!(!is_binary(&latin1));
+        assert!(is_binary(&[b"\x7fELF\x02\x01\x01\x00".to_vec()]));
+
+        assert_eq!(fold_case(b"CAF\xe9"), fold_case(&latin1[0]));
+        assert_ne!(fold_case(&latin1[0]), fold_case(&other[0]));
+    }
+
+    #[test]
+    fn test_files_differ() {
+        let lines = read_file_lines_bytes(&String::from("handout-a.txt")).unwrap();
+        let other = read_file_lines_bytes(&String::from("handout-b.txt")).unwrap();
+        assert!(!files_differ(&lines, &lines));
+        assert!(files_differ(&lines, &other));
+        // Same length, one changed line
+        let mut changed = lines.clone();
+        changed[3].push(b'!');
+        assert!(files_differ(&lines, &changed));
+        // Prefix of the other file
+        assert!(files_differ(&lines, &lines[..lines.len() - 1]));
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        let file1 = vec!["Hello World", "same"];
+        let file2 = vec!["hello WORLD", "same"];
+        let options = DiffOptions::default();
+        assert!(files_differ(
+            &comparable_lines(&file1, &options),
+            &comparable_lines(&file2, &options)
+        ));
+
+        let options = DiffOptions {
+            ignore_case: true,
+            ..DiffOptions::default()
+        };
+        let lines1 = comparable_lines(&file1, &options);
+        let lines2 = comparable_lines(&file2, &options);
+        assert!(!files_differ(&lines1, &lines2));
+        let lcs_table = lcs(&lines1, &lines2);
+        assert_eq!(lcs_table.get(2, 2), Some(2));
+        // Lines that match keep the casing of the first file
+        let edits = diff_edits(&lcs_table, &lines1, &lines2);
+        assert_eq!(edits.len(), 2);
+        assert!(matches!(edits[0], Edit::Same(line) if line.text == b"Hello World"));
+    }
+
+    #[test]
+    fn test_word_diff() {
+        assert_eq!(
+            word_diff("the quick brown fox", "the quick red fox"),
+            "the quick [-brown-]{+red+} fox"
+        );
+        assert_eq!(
+            word_diff("same words here", "same words here"),
+            "same words here"
+        );
+        assert_eq!(word_diff("a b", "a b c d"), "a b {+c d+}");
+        assert_eq!(word_diff("x a b", "a b"), "[-x-] a b");
+    }
+
+    #[test]
+    fn test_side_by_side() {
+        let lines1 = vec!["one", "two", "three", "gone"];
+        let lines2 = vec!["one", "2", "three"];
+        let lcs_table = lcs(&lines1, &lines2);
+        let edits = diff_edits(&lcs_table, &lines1, &lines2);
+        let markers: Vec<char> = aligned_rows(&edits).iter().map(|row| row.0).collect();
+        assert_eq!(markers, vec![' ', '|', ' ', '<']);
+        let edits = diff_edits(&lcs_table, &lines2, &lines1);
+        assert_eq!(aligned_rows(&edits)[3], ('>', None, Some(&"gone")));
+
+        assert_eq!(side_by_side_row('|', "abc", "xyz", 11), "abc  | xyz");
+        assert_eq!(side_by_side_row('<', "abcdefgh", "", 11), "abcd <");
+        assert_eq!(side_by_side_row(' ', "", "abcdefgh", 11), "       abcd");
+    }
+
+    #[test]
+    fn test_diff_edits() {
+        let lines1 = vec!["one", "two", "three"];
+        let lines2 = vec!["one", "2", "three", "four"];
+        let lcs_table = lcs(&lines1, &lines2);
+        assert_eq!(
+            diff_edits(&lcs_table, &lines1, &lines2),
+            vec![
+                Edit::Same(&"one"),
+                Edit::Removed(&"two"),
+                Edit::Added(&"2"),
+                Edit::Same(&"three"),
+                Edit::Added(&"four"),
+            ]
         );
     }
 
@@ -126,8 +599,14 @@ mod test {
         println!("Expected:");
         expected.display();
         let result = lcs(
-            &"abcd".chars().map(|c| c.to_string()).collect(),
-            &"adb".chars().map(|c| c.to_string()).collect(),
+            &"abcd"
+                .chars()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>(),
+            &"adb"
+                .chars()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>(),
         );
         println!("Got:");
         result.display();
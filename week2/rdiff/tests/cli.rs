@@ -0,0 +1,209 @@
+use std::process::Command;
+
+/// Runs the rdiff binary from the crate root, returning (stdout, exit code).
+fn run_rdiff(args: &[&str]) -> (String, i32) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rdiff"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("Could not run rdiff");
+    (
+        // Lines are printed as they appear in the files, which may not be UTF-8
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        output.status.code().unwrap(),
+    )
+}
+
+#[test]
+fn test_brief_identical() {
+    let (stdout, code) = run_rdiff(&["--brief", "handout-a.txt", "handout-a.txt"]);
+    assert_eq!(stdout, "");
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn test_brief_different() {
+    let (stdout, code) = run_rdiff(&["--brief", "handout-a.txt", "handout-b.txt"]);
+    assert_eq!(stdout, "Files handout-a.txt and handout-b.txt differ\n");
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn test_exit_code_identical() {
+    let (_, code) = run_rdiff(&["handout-a.txt", "handout-a.txt"]);
+    assert_eq!(code, 0);
+}
+
+#[test]
+fn test_exit_code_different() {
+    let (stdout, code) = run_rdiff(&["handout-a.txt", "handout-b.txt"]);
+    assert!(stdout
+        .lines()
+        .any(|line| line.starts_with("< ") || line.starts_with("> ")));
+    assert_eq!(code, 1);
+}
+
+#[test]
+fn test_exit_code_missing_file() {
+    let (_, code) = run_rdiff(&["handout-a.txt", "does-not-exist.txt"]);
+    assert_eq!(code, 2);
+}
+
+#[test]
+fn test_identical_copies() {
+    let copy = std::env::temp_dir().join(format!("rdiff-copy-{}.txt", std::process::id()));
+    std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/handout-a.txt"), &copy).unwrap();
+    let copy = copy.to_str().unwrap();
+
+    let (stdout, code) = run_rdiff(&["handout-a.txt", copy]);
+    assert_eq!(stdout, "");
+    assert_eq!(code, 0);
+
+    let (stdout, code) = run_rdiff(&["--report-identical", "handout-a.txt", copy]);
+    assert_eq!(
+        stdout,
+        format!("Files handout-a.txt and {} are identical\n", copy)
+    );
+    assert_eq!(code, 0);
+
+    std::fs::remove_file(copy).unwrap();
+}
+
+#[test]
+fn test_word_diff() {
+    let dir = std::env::temp_dir();
+    let old = dir.join(format!("rdiff-word-old-{}.txt", std::process::id()));
+    let new = dir.join(format!("rdiff-word-new-{}.txt", std::process::id()));
+    std::fs::write(&old, "first line\nthe quick brown fox\n").unwrap();
+    std::fs::write(&new, "first line\nthe quick red fox\n").unwrap();
+
+    let (stdout, code) = run_rdiff(&["--word-diff", old.to_str().unwrap(), new.to_str().unwrap()]);
+    assert_eq!(stdout, " first line\n~ the quick [-brown-]{+red+} fox\n");
+    assert_eq!(code, 1);
+
+    std::fs::remove_file(old).unwrap();
+    std::fs::remove_file(new).unwrap();
+}
+
+#[test]
+fn test_pairwise() {
+    let dir = std::env::temp_dir();
+    let paths: Vec<String> = ["one", "two", "three"]
+        .iter()
+        .map(|name| {
+            dir.join(format!(
+                "rdiff-pairwise-{}-{}.txt",
+                name,
+                std::process::id()
+            ))
+            .to_str()
+            .unwrap()
+            .to_string()
+        })
+        .collect();
+    std::fs::write(&paths[0], "a\nb\n").unwrap();
+    std::fs::write(&paths[1], "a\nc\n").unwrap();
+    std::fs::write(&paths[2], "a\nd\n").unwrap();
+
+    let (stdout, code) = run_rdiff(&["--pairwise", &paths[0], &paths[1], &paths[2]]);
+    assert!(stdout.contains(&format!("diff {} {}\n", paths[0], paths[1])));
+    assert!(stdout.contains(&format!("diff {} {}\n", paths[1], paths[2])));
+    assert_eq!(stdout.matches("< ").count(), 2);
+    assert_eq!(stdout.matches("> ").count(), 2);
+    assert_eq!(code, 1);
+
+    // Only the first pair differs: still a nonzero exit
+    std::fs::write(&paths[2], "a\nc\n").unwrap();
+    let (_, code) = run_rdiff(&["--pairwise", "--brief", &paths[0], &paths[1], &paths[2]]);
+    assert_eq!(code, 1);
+
+    for path in paths {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn test_ignore_case() {
+    let path1 = std::env::temp_dir().join(format!("rdiff-case-1-{}.txt", std::process::id()));
+    let path2 = std::env::temp_dir().join(format!("rdiff-case-2-{}.txt", std::process::id()));
+    std::fs::write(&path1, "Hello World\nsame\n").unwrap();
+    std::fs::write(&path2, "hello WORLD\nsame\n").unwrap();
+    let (path1, path2) = (path1.to_str().unwrap(), path2.to_str().unwrap());
+
+    let (_, code) = run_rdiff(&[path1, path2]);
+    assert_eq!(code, 1);
+    let (stdout, code) = run_rdiff(&["-i", path1, path2]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "");
+    let (stdout, code) = run_rdiff(&["--ignore-case", "--report-identical", path1, path2]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("are identical"));
+
+    std::fs::remove_file(path1).unwrap();
+    std::fs::remove_file(path2).unwrap();
+}
+
+#[test]
+fn test_not_utf8() {
+    let path1 = std::env::temp_dir().join(format!("rdiff-latin1-1-{}.txt", std::process::id()));
+    let path2 = std::env::temp_dir().join(format!("rdiff-latin1-2-{}.txt", std::process::id()));
+    std::fs::write(&path1, b"caf\xe9\nsame\n").unwrap();
+    std::fs::write(&path2, b"caf\xe8\nsame\n").unwrap();
+    let (path1, path2) = (path1.to_str().unwrap(), path2.to_str().unwrap());
+
+    let (stdout, code) = run_rdiff(&[path1, path2]);
+    assert_eq!(code, 1);
+    assert!(stdout.ends_with("< caf\u{fffd}\n> caf\u{fffd}\n same\n"));
+    let (_, code) = run_rdiff(&[path1, path1]);
+    assert_eq!(code, 0);
+
+    std::fs::remove_file(path1).unwrap();
+    std::fs::remove_file(path2).unwrap();
+}
+
+#[test]
+fn test_binary_files() {
+    let path1 = std::env::temp_dir().join(format!("rdiff-binary-1-{}", std::process::id()));
+    let path2 = std::env::temp_dir().join(format!("rdiff-binary-2-{}", std::process::id()));
+    std::fs::write(&path1, b"\x7fELF\x00\x01\nsame\n").unwrap();
+    std::fs::write(&path2, b"\x7fELF\x00\x02\nsame\n").unwrap();
+    let (path1, path2) = (path1.to_str().unwrap(), path2.to_str().unwrap());
+
+    let (stdout, code) = run_rdiff(&[path1, path2]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        stdout,
+        format!("Binary files {} and {} differ\n", path1, path2)
+    );
+    // --text diffs them line by line anyway
+    let (stdout, code) = run_rdiff(&["--text", path1, path2]);
+    assert_eq!(code, 1);
+    assert!(stdout.ends_with(" same\n"));
+
+    std::fs::remove_file(path1).unwrap();
+    std::fs::remove_file(path2).unwrap();
+}
+
+#[test]
+fn test_side_by_side() {
+    let path1 = std::env::temp_dir().join(format!("rdiff-columns-1-{}.txt", std::process::id()));
+    let path2 = std::env::temp_dir().join(format!("rdiff-columns-2-{}.txt", std::process::id()));
+    std::fs::write(&path1, "one\ntwo\nthree\ngone\n").unwrap();
+    std::fs::write(&path2, "one\n2\nthree\nfour and a much longer line\n").unwrap();
+    let (path1, path2) = (path1.to_str().unwrap(), path2.to_str().unwrap());
+
+    let (stdout, code) = run_rdiff(&["-y", "--width", "23", path1, path2]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        stdout,
+        "one          one\n\
+         two        | 2\n\
+         three        three\n\
+         gone       | four and a\n"
+    );
+    let (stdout, _) = run_rdiff(&["--side-by-side", "--width=23", path1, path1]);
+    assert_eq!(stdout, "");
+
+    std::fs::remove_file(path1).unwrap();
+    std::fs::remove_file(path2).unwrap();
+}
@@ -1,40 +1,268 @@
+use serde::Serialize;
 use std::env;
 use std::error::Error;
 use std::fs::File;
 use std::io;
-use std::io::BufRead;
+use std::io::{BufRead, Read, Write};
+use std::ops::AddAssign;
 use std::process;
 
-fn read_files(filename: &String, line_count: &mut usize) -> Result<Vec<String>, io::Error> {
-    let file = File::open(filename)?;
-    let mut file_vec: Vec<String> = vec![];
-    for line in io::BufReader::new(file).lines() {
-        let content = line?;
-        *line_count += 1;
-        file_vec.push(content);
+/// Totals for one input. "characters" counts non-whitespace bytes, "bytes" counts every byte.
+#[derive(Debug, Default, PartialEq)]
+struct Counts {
+    lines: usize,
+    words: usize,
+    characters: usize,
+    bytes: usize,
+}
+
+impl AddAssign<&Counts> for Counts {
+    fn add_assign(&mut self, other: &Counts) {
+        self.lines += other.lines;
+        self.words += other.words;
+        self.characters += other.characters;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Which counts were asked for on the command line.
+#[derive(Debug, Default)]
+struct Options {
+    lines: bool,
+    words: bool,
+    characters: bool,
+    bytes: bool,
+    /// With --fields-delimiter, "words" are the fields of each line split on this byte instead
+    fields_delimiter: Option<u8>,
+}
+
+impl Options {
+    /// Without any flags we print words, characters and lines, like we always have.
+    fn is_default(&self) -> bool {
+        !(self.lines || self.words || self.characters || self.bytes)
+    }
+
+    fn only_bytes(&self) -> bool {
+        self.bytes && !(self.lines || self.words || self.characters)
+    }
+}
+
+/// When to print the totals line, chosen with --total=WHEN as in GNU wc.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum TotalMode {
+    /// Only when there is more than one file
+    #[default]
+    Auto,
+    Always,
+    /// Print the totals and nothing else
+    Only,
+    Never,
+}
+
+impl TotalMode {
+    fn from_arg(when: &str) -> Option<TotalMode> {
+        match when {
+            "auto" => Some(TotalMode::Auto),
+            "always" => Some(TotalMode::Always),
+            "only" => Some(TotalMode::Only),
+            "never" => Some(TotalMode::Never),
+            _ => None,
+        }
     }
 
-    Ok(file_vec)
+    fn shows_files(self) -> bool {
+        self != TotalMode::Only
+    }
+
+    fn shows_total(self, num_files: usize) -> bool {
+        match self {
+            TotalMode::Auto => num_files > 1,
+            TotalMode::Always | TotalMode::Only => true,
+            TotalMode::Never => false,
+        }
+    }
 }
 
-fn count_words_characters(file_vec: &Vec<String>) -> (usize, usize) {
-    let mut word_count: usize = 0;
-    let mut character_count: usize = 0;
+/// Counts a stream one line at a time, reusing a single buffer, so memory use doesn't depend on
+/// the size of the input. Words are split on whitespace, or on `fields_delimiter` if one is given.
+fn count_reader<R: BufRead>(
+    mut reader: R,
+    fields_delimiter: Option<u8>,
+) -> Result<Counts, io::Error> {
+    let mut counts = Counts::default();
+    let mut line: Vec<u8> = Vec::new();
 
-    let get_character_count = |content: &String| -> usize {
-        content.bytes().filter(|&x| !x.is_ascii_whitespace()).count()
+    let get_character_count =
+        |content: &[u8]| -> usize { content.iter().filter(|x| !x.is_ascii_whitespace()).count() };
+
+    let get_word_count = |content: &[u8]| -> usize {
+        content
+            .split(|x| x.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .count()
     };
 
-    let get_word_count = |content: &String| -> usize {
-        content.split_ascii_whitespace().count()
+    // Unlike words, empty fields count: "a\t\tb" has three. Only an empty line has none.
+    let get_field_count = |content: &[u8], delimiter: u8| -> usize {
+        let content = content.strip_suffix(b"\n").unwrap_or(content);
+        if content.is_empty() {
+            return 0;
+        }
+        content.iter().filter(|x| **x == delimiter).count() + 1
     };
 
-    for content in file_vec {
-        character_count += get_character_count(&content);
-        word_count += get_word_count(&content);
+    loop {
+        line.clear();
+        let bytes_read = reader.read_until(b'\n', &mut line)?;
+        if bytes_read == 0 {
+            break;
+        }
+        // Like GNU wc, count newlines: a final line without one still has words and bytes, but
+        // doesn't add to the line count
+        if line.ends_with(b"\n") {
+            counts.lines += 1;
+        }
+        counts.words += match fields_delimiter {
+            Some(delimiter) => get_field_count(&line, delimiter),
+            None => get_word_count(&line),
+        };
+        counts.characters += get_character_count(&line);
+        counts.bytes += bytes_read;
+    }
+
+    Ok(counts)
+}
+
+fn count_file(filename: &str, options: &Options) -> Result<Counts, io::Error> {
+    let file = File::open(filename)?;
+    let metadata = file.metadata()?;
+    if options.only_bytes() && metadata.is_file() && metadata.len() > 0 {
+        // No need to read anything when the file system already knows the answer. Pipes, devices
+        // and /proc files report a size of 0 whatever they hold, so a 0 is checked by reading,
+        // which costs nothing for a file that really is empty.
+        let bytes = metadata.len() as usize;
+        return Ok(Counts {
+            bytes,
+            ..Counts::default()
+        });
+    }
+    count_reader(io::BufReader::new(file), options.fields_delimiter)
+}
+
+/// Parses the argument to --fields-delimiter, which must be a single ASCII character. "\t" is
+/// accepted too, since a literal tab is awkward to type in a shell.
+fn parse_delimiter(arg: &str) -> Option<u8> {
+    match arg {
+        "\\t" => Some(b'\t'),
+        _ if arg.len() == 1 && arg.is_ascii() => Some(arg.as_bytes()[0]),
+        _ => None,
+    }
+}
+
+/// Reads a NUL-separated list of filenames, as written by `find -print0`. Empty names (e.g. after
+/// a trailing NUL) are skipped.
+fn read_files0<R: Read>(mut reader: R) -> Result<Vec<String>, io::Error> {
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+    Ok(contents
+        .split(|byte| *byte == b'\0')
+        .filter(|name| !name.is_empty())
+        .map(|name| String::from_utf8_lossy(name).to_string())
+        .collect())
+}
+
+/// Reads the filenames for --files0-from from a file, or from stdin if the file is "-".
+fn read_files0_from(source: &str) -> Result<Vec<String>, io::Error> {
+    if source == "-" {
+        read_files0(io::stdin())
+    } else {
+        read_files0(File::open(source)?)
+    }
+}
+
+fn write_counts<W: Write>(out: &mut W, counts: &Counts, options: &Options) -> io::Result<()> {
+    if options.is_default() || options.words {
+        writeln!(out, "word count = {}", counts.words)?;
+    }
+    if options.is_default() || options.characters {
+        writeln!(out, "character count = {}", counts.characters)?;
+    }
+    if options.is_default() || options.lines {
+        writeln!(out, "line count = {}", counts.lines)?;
+    }
+    if options.bytes {
+        writeln!(out, "byte count = {}", counts.bytes)?;
+    }
+    Ok(())
+}
+
+/// Counts each file and writes the report to `out`, with the per-file counts and the totals that
+/// `total_mode` calls for. Files that can't be read are reported on stderr and left out of the
+/// totals. Returns whether any file couldn't be read.
+fn write_report<W: Write>(
+    out: &mut W,
+    filenames: &[String],
+    options: &Options,
+    total_mode: TotalMode,
+) -> io::Result<bool> {
+    let mut total = Counts::default();
+    let mut failed = false;
+    for filename in filenames.iter() {
+        let counts = match count_file(filename, options) {
+            Ok(counts) => counts,
+            Err(err) => {
+                eprintln!("rwc: {}: {}", filename, err);
+                failed = true;
+                continue;
+            }
+        };
+        if total_mode.shows_files() {
+            if filenames.len() > 1 {
+                writeln!(out, "{}:", filename)?;
+            }
+            write_counts(out, &counts, options)?;
+        }
+        total += &counts;
+    }
+    if total_mode.shows_total(filenames.len()) {
+        // With --total=only the totals are all there is, so they need no heading
+        if total_mode.shows_files() {
+            writeln!(out, "total:")?;
+        }
+        write_counts(out, &total, options)?;
+    }
+    Ok(failed)
+}
+
+/// One file's counts as reported by --json. Every count is included regardless of which were
+/// asked for, so tools don't have to guess which keys are present.
+#[derive(Serialize)]
+struct JsonCounts<'a> {
+    file: &'a str,
+    lines: usize,
+    words: usize,
+    bytes: usize,
+    chars: usize,
+}
+
+impl<'a> JsonCounts<'a> {
+    fn new(file: &'a str, counts: &Counts) -> JsonCounts<'a> {
+        JsonCounts {
+            file,
+            lines: counts.lines,
+            words: counts.words,
+            bytes: counts.bytes,
+            chars: counts.characters,
+        }
     }
+}
 
-    (word_count, character_count)
+fn usage() -> ! {
+    println!("Usage: rwc [-l] [-w] [-m] [-c] [--json] [--total=WHEN] <file>...");
+    println!("       rwc [-l] [-w] [-m] [-c] [--json] [--total=WHEN] --files0-from=<list>");
+    println!("WHEN is auto (the default), always, only or never");
+    println!("--fields-delimiter=CHAR (or --tab) counts fields split on CHAR as words");
+    process::exit(1);
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -43,16 +271,367 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("Too few arguments.");
         process::exit(1);
     }
-    let filename = &args[1];
-    // Your code here :)
-    let mut line_count: usize = 0;
-    let file_vec = read_files(filename, &mut line_count)?;
 
-    let (word_count, character_count) = count_words_characters(&file_vec);
+    let mut options = Options::default();
+    let mut filenames: Vec<String> = Vec::new();
+    let mut files0_from = None;
+    let mut json = false;
+    let mut total_mode = TotalMode::default();
+    let mut arg_iter = args[1..].iter();
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "-l" => options.lines = true,
+            "-w" => options.words = true,
+            "-m" => options.characters = true,
+            "-c" => options.bytes = true,
+            "--json" => json = true,
+            "--tab" => options.fields_delimiter = Some(b'\t'),
+            "--fields-delimiter" => match arg_iter.next().and_then(|c| parse_delimiter(c)) {
+                Some(delimiter) => options.fields_delimiter = Some(delimiter),
+                None => usage(),
+            },
+            _ if arg.starts_with("--fields-delimiter=") => {
+                match parse_delimiter(&arg["--fields-delimiter=".len()..]) {
+                    Some(delimiter) => options.fields_delimiter = Some(delimiter),
+                    None => usage(),
+                }
+            }
+            "--files0-from" => match arg_iter.next() {
+                Some(source) => files0_from = Some(source.clone()),
+                None => usage(),
+            },
+            _ if arg.starts_with("--files0-from=") => {
+                files0_from = Some(arg["--files0-from=".len()..].to_string())
+            }
+            "--total" => match arg_iter.next().and_then(|when| TotalMode::from_arg(when)) {
+                Some(mode) => total_mode = mode,
+                None => usage(),
+            },
+            _ if arg.starts_with("--total=") => match TotalMode::from_arg(&arg["--total=".len()..])
+            {
+                Some(mode) => total_mode = mode,
+                None => usage(),
+            },
+            // A lone "-" is a filename (stdin for --files0-from), not a flag
+            _ if arg.starts_with('-') && arg != "-" => usage(),
+            _ => filenames.push(arg.clone()),
+        }
+    }
+    if let Some(source) = files0_from {
+        // Like wc, the list replaces filenames on the command line rather than adding to them
+        if !filenames.is_empty() {
+            eprintln!("rwc: file operands cannot be combined with --files0-from");
+            usage();
+        }
+        filenames = read_files0_from(&source)
+            .map_err(|err| format!("cannot read file names from {}: {}", source, err))?;
+    }
+    if filenames.is_empty() {
+        usage();
+    }
 
-    println!("word count = {}", word_count);
-    println!("character count = {}", character_count);
-    println!("line count = {}", line_count);
+    if json {
+        // JSON reports every count, so the whole file has to be read
+        let json_options = Options {
+            fields_delimiter: options.fields_delimiter,
+            ..Options::default()
+        };
+        let mut objects = Vec::new();
+        let mut failed = false;
+        for filename in filenames.iter() {
+            match count_file(filename, &json_options) {
+                Ok(counts) => objects.push(JsonCounts::new(filename, &counts)),
+                Err(err) => {
+                    eprintln!("rwc: {}: {}", filename, err);
+                    failed = true;
+                }
+            }
+        }
+        // Serializing plain strings and numbers can't fail
+        if filenames.len() > 1 {
+            println!("{}", serde_json::to_string(&objects).unwrap());
+        } else if let Some(object) = objects.first() {
+            println!("{}", serde_json::to_string(object).unwrap());
+        }
+        if failed {
+            process::exit(1);
+        }
+        return Ok(());
+    }
 
+    let failed = write_report(&mut io::stdout().lock(), &filenames, &options, total_mode)?;
+    if failed {
+        process::exit(1);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// Produces `remaining` copies of a line without ever holding more than one in memory.
+    struct RepeatedLines {
+        line: &'static [u8],
+        remaining: usize,
+        offset: usize,
+    }
+
+    impl Read for RepeatedLines {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut written = 0;
+            while written < buf.len() && self.remaining > 0 {
+                let n = (self.line.len() - self.offset).min(buf.len() - written);
+                buf[written..written + n].copy_from_slice(&self.line[self.offset..self.offset + n]);
+                written += n;
+                self.offset += n;
+                if self.offset == self.line.len() {
+                    self.offset = 0;
+                    self.remaining -= 1;
+                }
+            }
+            Ok(written)
+        }
+    }
+
+    fn count_contents(name: &str, contents: &[u8]) -> Counts {
+        let path = env::temp_dir().join(format!("rwc-test-{}-{}.txt", name, process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let counts = count_file(path.to_str().unwrap(), &Options::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        counts
+    }
+
+    #[test]
+    fn test_trailing_newline() {
+        let counts = count_contents("trailing-newline", b"hello world\nsecond line\n");
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 2,
+                words: 4,
+                characters: 20,
+                bytes: 24,
+            }
+        );
+    }
+
+    #[test]
+    fn test_no_trailing_newline() {
+        let counts = count_contents("no-trailing-newline", b"hello world\nsecond line");
+        assert_eq!(
+            counts,
+            Counts {
+                lines: 1,
+                words: 4,
+                characters: 20,
+                bytes: 23,
+            }
+        );
+        assert_eq!(count_contents("single-line", b"no newline").lines, 0);
+    }
+
+    #[test]
+    fn test_count_handout() {
+        let counts = count_file("handout-a.txt", &Options::default()).unwrap();
+        assert_eq!(counts.lines, 8);
+        assert_eq!(
+            counts.bytes as u64,
+            std::fs::metadata("handout-a.txt").unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_read_files0() {
+        let names = read_files0(&b"handout-a.txt\0handout-b.txt\0"[..]).unwrap();
+        assert_eq!(names, vec!["handout-a.txt", "handout-b.txt"]);
+        // No trailing NUL, and names may contain spaces or newlines
+        let names = read_files0(&b"a file\0with\nnewline"[..]).unwrap();
+        assert_eq!(names, vec!["a file", "with\nnewline"]);
+        assert!(read_files0(&b""[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_files0_from_list_file() {
+        let list = env::temp_dir().join(format!("rwc-files0-{}", process::id()));
+        std::fs::write(&list, b"handout-a.txt\0handout-b.txt\0").unwrap();
+        let names = read_files0_from(list.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&list).unwrap();
+
+        let mut total = Counts::default();
+        for name in names.iter() {
+            total += &count_file(name, &Options::default()).unwrap();
+        }
+        let a = count_file("handout-a.txt", &Options::default()).unwrap();
+        let b = count_file("handout-b.txt", &Options::default()).unwrap();
+        assert_eq!(total.lines, a.lines + b.lines);
+        assert_eq!(total.words, a.words + b.words);
+        assert_eq!(total.bytes, a.bytes + b.bytes);
+    }
+
+    #[test]
+    fn test_json_output() {
+        let counts = count_file("simple-a.txt", &Options::default()).unwrap();
+        let json = serde_json::to_string(&JsonCounts::new("simple-a.txt", &counts)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["file"], "simple-a.txt");
+        assert_eq!(value["lines"], 5);
+        assert_eq!(value["words"], 5);
+        assert_eq!(value["bytes"], 10);
+        assert_eq!(value["chars"], 5);
+        assert_eq!(value.as_object().unwrap().len(), 5);
+
+        // File names are escaped, and several files make an array
+        let odd_name = "a \"b\"\\c\n\u{1}";
+        let objects = vec![
+            JsonCounts::new("simple-a.txt", &counts),
+            JsonCounts::new(odd_name, &Counts::default()),
+        ];
+        let value: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&objects).unwrap()).unwrap();
+        assert_eq!(value[0]["file"], "simple-a.txt");
+        assert_eq!(value[1]["file"], odd_name);
+        assert_eq!(value[1]["lines"], 0);
+    }
+
+    #[test]
+    fn test_total_modes() {
+        assert_eq!(TotalMode::from_arg("only"), Some(TotalMode::Only));
+        assert_eq!(TotalMode::from_arg("sometimes"), None);
+        assert_eq!(TotalMode::default(), TotalMode::Auto);
+
+        // (mode, shows files, shows total for one file, shows total for several files)
+        let expected = [
+            (TotalMode::Auto, true, false, true),
+            (TotalMode::Always, true, true, true),
+            (TotalMode::Only, false, true, true),
+            (TotalMode::Never, true, false, false),
+        ];
+        for (mode, files, total_one, total_many) in expected {
+            assert_eq!(mode.shows_files(), files, "{:?}", mode);
+            assert_eq!(mode.shows_total(1), total_one, "{:?}", mode);
+            assert_eq!(mode.shows_total(3), total_many, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_total_output() {
+        let filenames: Vec<String> = [("a", "one two\n"), ("b", "three\n")]
+            .iter()
+            .map(|(name, contents)| {
+                let path =
+                    env::temp_dir().join(format!("rwc-total-{}-{}.txt", name, process::id()));
+                std::fs::write(&path, contents).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+        let options = Options {
+            words: true,
+            lines: true,
+            ..Options::default()
+        };
+        let render = |mode| {
+            let mut out = Vec::new();
+            assert!(!write_report(&mut out, &filenames, &options, mode).unwrap());
+            String::from_utf8(out).unwrap()
+        };
+        let outputs: Vec<String> = [
+            TotalMode::Auto,
+            TotalMode::Always,
+            TotalMode::Never,
+            TotalMode::Only,
+        ]
+        .iter()
+        .map(|mode| render(*mode))
+        .collect();
+        for filename in filenames.iter() {
+            std::fs::remove_file(filename).unwrap();
+        }
+
+        let files = format!(
+            "{}:\nword count = 2\nline count = 1\n{}:\nword count = 1\nline count = 1\n",
+            filenames[0], filenames[1]
+        );
+        let total = "word count = 3\nline count = 2\n";
+        assert_eq!(outputs[0], format!("{}total:\n{}", files, total));
+        assert_eq!(outputs[1], outputs[0]);
+        assert_eq!(outputs[2], files);
+        assert_eq!(outputs[3], total);
+    }
+
+    #[test]
+    fn test_tab_separated_fields() {
+        let contents: &[u8] = b"name\tage\tcity\nalice smith\t30\t\t\n\nbob\t25\tparis";
+        let counts = count_reader(contents, Some(b'\t')).unwrap();
+        // Empty columns still count as fields, but the blank line has none
+        assert_eq!(counts.words, 10);
+        assert_eq!(counts.lines, 3);
+        // Without a delimiter the same input is split on whitespace as before
+        assert_eq!(count_reader(contents, None).unwrap().words, 9);
+
+        assert_eq!(parse_delimiter(","), Some(b','));
+        assert_eq!(parse_delimiter("\\t"), Some(b'\t'));
+        assert_eq!(parse_delimiter("ab"), None);
+        assert_eq!(parse_delimiter(""), None);
+    }
+
+    #[test]
+    fn test_count_large_stream() {
+        // ~20MB of input: more than we would want to hold if lines were being kept around
+        let num_lines = 1_000_000;
+        let reader = RepeatedLines {
+            line: b"the quick brown fox\n",
+            remaining: num_lines,
+            offset: 0,
+        };
+        let counts = count_reader(io::BufReader::new(reader), None).unwrap();
+        assert_eq!(
+            counts,
+            Counts {
+                lines: num_lines,
+                words: 4 * num_lines,
+                characters: 16 * num_lines,
+                bytes: 20 * num_lines,
+            }
+        );
+    }
+
+    #[test]
+    fn test_bytes_fast_path_matches_streaming() {
+        let path = env::temp_dir().join(format!("rwc-test-{}.txt", process::id()));
+        let mut file = File::create(&path).unwrap();
+        for i in 0..100_000 {
+            writeln!(file, "line number {}", i).unwrap();
+        }
+        drop(file);
+        let path = path.to_str().unwrap();
+
+        let only_bytes = Options {
+            bytes: true,
+            ..Options::default()
+        };
+        let fast = count_file(path, &only_bytes).unwrap();
+        let all = Options {
+            lines: true,
+            bytes: true,
+            ..Options::default()
+        };
+        let streamed = count_file(path, &all).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(fast.bytes, streamed.bytes);
+        assert_eq!(fast.lines, 0);
+        assert_eq!(streamed.lines, 100_000);
+    }
+
+    #[test]
+    fn test_bytes_of_unsized_file() {
+        let only_bytes = Options {
+            bytes: true,
+            ..Options::default()
+        };
+        let counts = count_file("/proc/self/status", &only_bytes).unwrap();
+        assert!(counts.bytes > 0);
+    }
+}
@@ -1,39 +1,305 @@
-use crossbeam_channel::{self, unbounded, Receiver, Sender};
-use std::{process::id, thread, time};
+use crossbeam_channel::{bounded, unbounded, Receiver};
+use std::collections::BTreeMap;
+use std::{thread, time};
 
-fn parallel_map<T, U, F>(mut input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
 where
     F: FnOnce(T) -> U + Send + Copy + 'static,
     T: Send + 'static,
     U: Send + 'static + Default,
 {
-    let mut output_vec: Vec<U> = Vec::with_capacity(input_vec.len());
-    // TODO: implement parallel map!
-    let (tx1, rx1): (Sender<U>, Receiver<U>) = unbounded();
-    let (tx2, rx2): (Sender<U>, Receiver<U>) = unbounded();
+    parallel_map_with_progress(input_vec, num_threads, f, |_, _| {})
+}
 
-    for val in input_vec.into_iter() {
-        tx1.send(f(val))
-            .expect("tx1 send message failed!");
+/// Like parallel_map, but picks the number of worker threads itself based on how many CPUs are
+/// available.
+fn parallel_map_auto<T, U, F>(input_vec: Vec<T>, f: F) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let num_threads = auto_thread_count(input_vec.len());
+    parallel_map(input_vec, num_threads, f)
+}
+
+/// One thread per available CPU, but never more threads than items (and never fewer than one).
+fn auto_thread_count(num_items: usize) -> usize {
+    let available = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+    available.min(num_items).max(1)
+}
+
+/// Like parallel_map, but calls `progress(completed, total)` each time a result comes back. The
+/// callback runs on the calling thread, so it doesn't need to be Send or synchronized. With zero
+/// or one threads the map simply runs on the calling thread.
+fn parallel_map_with_progress<T, U, F, P>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+    progress: P,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+    P: Fn(usize, usize),
+{
+    let total = input_vec.len();
+    if num_threads <= 1 {
+        // Not worth the channels, and with no workers at all nothing would ever get mapped
+        return input_vec
+            .into_iter()
+            .enumerate()
+            .map(|(idx, val)| {
+                let result = f(val);
+                progress(idx + 1, total);
+                result
+            })
+            .collect();
+    }
+    let mut output_vec: Vec<U> = Vec::with_capacity(total);
+    output_vec.resize_with(total, U::default);
+    // Values travel with their index so results can be put back in input order
+    let (tx1, rx1) = unbounded::<(usize, T)>();
+    let (tx2, rx2) = unbounded::<(usize, U)>();
+
+    let mut threads = Vec::new();
+    for _ in 0..num_threads {
+        let recv = rx1.clone();
+        let sender = tx2.clone();
+        threads.push(thread::spawn(move || {
+            while let Ok((idx, val)) = recv.recv() {
+                sender.send((idx, f(val))).expect("tx2 send message failed");
+            }
+        }));
+    }
+
+    drop(tx2);
+
+    for (idx, val) in input_vec.into_iter().enumerate() {
+        tx1.send((idx, val)).expect("tx1 send message failed!");
     }
 
     drop(tx1);
 
+    let mut completed = 0;
+    while let Ok((idx, result)) = rx2.recv() {
+        output_vec[idx] = result;
+        completed += 1;
+        progress(completed, total);
+    }
+
+    for t in threads {
+        t.join().expect("panic in thread");
+    }
+
+    output_vec
+}
+
+/// Like parallel_map, but maps `input[i]` into `output[i]` in place, so callers can reuse the same
+/// buffers instead of allocating a new Vec each time. Each thread gets one contiguous chunk of the
+/// slices; scoped threads let the workers borrow them directly.
+fn parallel_map_into<T, U, F>(input: &[T], output: &mut [U], num_threads: usize, f: F)
+where
+    F: Fn(&T) -> U + Sync,
+    T: Sync,
+    U: Send,
+{
+    assert_eq!(
+        input.len(),
+        output.len(),
+        "input and output slices must have the same length"
+    );
+    if input.is_empty() {
+        return;
+    }
+    let chunk_size = input.len().div_ceil(num_threads.max(1));
+    let f = &f;
+    thread::scope(|scope| {
+        for (input_chunk, output_chunk) in
+            input.chunks(chunk_size).zip(output.chunks_mut(chunk_size))
+        {
+            scope.spawn(move || {
+                for (val, out) in input_chunk.iter().zip(output_chunk.iter_mut()) {
+                    *out = f(val);
+                }
+            });
+        }
+    });
+}
+
+/// Like parallel_map, but hands results back as soon as each one is ready, in whatever order the
+/// workers finish them. Asking for zero threads still gets one worker.
+fn parallel_map_unordered<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> impl Iterator<Item = U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let (tx1, rx1) = unbounded::<T>();
+    let (tx2, rx2) = unbounded::<U>();
+
     let mut threads = Vec::new();
-    for _ in 0..num_threads {
+    for _ in 0..num_threads.max(1) {
+        let recv = rx1.clone();
+        let sender = tx2.clone();
+        threads.push(thread::spawn(move || {
+            while let Ok(val) = recv.recv() {
+                // The iterator may have been dropped early; the rest of the results are unwanted
+                if sender.send(f(val)).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+
+    drop(tx2);
+
+    for val in input_vec {
+        tx1.send(val).expect("tx1 send message failed!");
+    }
+
+    UnorderedResults {
+        results: Some(rx2),
+        threads,
+    }
+}
+
+/// Iterator returned by parallel_map_unordered. Results are pulled off the channel lazily;
+/// dropping the iterator stops the workers and waits for them to exit.
+struct UnorderedResults<U> {
+    // Only None while being dropped
+    results: Option<Receiver<U>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl<U> Iterator for UnorderedResults<U> {
+    type Item = U;
+
+    fn next(&mut self) -> Option<U> {
+        self.results.as_ref()?.recv().ok()
+    }
+}
+
+impl<U> Drop for UnorderedResults<U> {
+    fn drop(&mut self) {
+        // Hang up first, so that each worker gives up after the item it's on rather than working
+        // through the rest of the input
+        drop(self.results.take());
+        for t in self.threads.drain(..) {
+            t.join().expect("panic in thread");
+        }
+    }
+}
+
+/// Like parallel_map_unordered, but hands results back in input order: each one as soon as it and
+/// every result before it are ready. Results that finish early wait in a reorder buffer until the
+/// gap in front of them is filled.
+fn parallel_map_ordered_stream<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> impl Iterator<Item = U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let indexed: Vec<(usize, T)> = input_vec.into_iter().enumerate().collect();
+    OrderedResults {
+        completed: parallel_map_unordered(indexed, num_threads, move |(idx, val)| (idx, f(val))),
+        pending: BTreeMap::new(),
+        next_idx: 0,
+    }
+}
+
+/// Iterator returned by parallel_map_ordered_stream. Pulls (index, result) pairs off `completed`
+/// in completion order and holds on to them until `next_idx` comes up.
+struct OrderedResults<I, U> {
+    completed: I,
+    pending: BTreeMap<usize, U>,
+    next_idx: usize,
+}
+
+impl<I, U> Iterator for OrderedResults<I, U>
+where
+    I: Iterator<Item = (usize, U)>,
+{
+    type Item = U;
+
+    fn next(&mut self) -> Option<U> {
+        loop {
+            if let Some(val) = self.pending.remove(&self.next_idx) {
+                self.next_idx += 1;
+                return Some(val);
+            }
+            let (idx, val) = self.completed.next()?;
+            self.pending.insert(idx, val);
+        }
+    }
+}
+
+/// Result of an element that parallel_map_timeout gave up on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Timeout;
+
+/// Like parallel_map, but gives up on any element whose call to `f` takes longer than `per_item`,
+/// putting `Err(Timeout)` in its place instead of stalling the whole map.
+///
+/// Rust can't kill a thread, so each call runs on a helper thread of its own, which the worker
+/// waits on for at most `per_item`. A call that times out is abandoned rather than stopped: it
+/// keeps running in the background until `f` returns, and its result is thrown away. The map
+/// itself doesn't wait for it. If `f` can hang forever, make it check for cancellation itself or
+/// run the work in a separate process.
+fn parallel_map_timeout<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+    per_item: time::Duration,
+) -> Vec<Result<U, Timeout>>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let total = input_vec.len();
+    let mut output_vec: Vec<Result<U, Timeout>> = (0..total).map(|_| Err(Timeout)).collect();
+    let (tx1, rx1) = unbounded::<(usize, T)>();
+    let (tx2, rx2) = unbounded::<(usize, Result<U, Timeout>)>();
+
+    let mut threads = Vec::new();
+    for _ in 0..num_threads.max(1) {
         let recv = rx1.clone();
         let sender = tx2.clone();
         threads.push(thread::spawn(move || {
-            while let Ok(num) = recv.recv() {
-                sender.send(num).expect("tx2 send message failed");
+            while let Ok((idx, val)) = recv.recv() {
+                let (result_tx, result_rx) = bounded(1);
+                thread::spawn(move || {
+                    // Nobody is listening any more if we took too long
+                    let _ = result_tx.send(f(val));
+                });
+                let result = result_rx.recv_timeout(per_item).map_err(|_| Timeout);
+                sender.send((idx, result)).expect("tx2 send message failed");
             }
         }));
     }
 
     drop(tx2);
 
-    while let Ok(num) = rx2.recv() {
-        output_vec.push(num);
+    for (idx, val) in input_vec.into_iter().enumerate() {
+        tx1.send((idx, val)).expect("tx1 send message failed!");
+    }
+
+    drop(tx1);
+
+    while let Ok((idx, result)) = rx2.recv() {
+        output_vec[idx] = result;
     }
 
     for t in threads {
@@ -51,4 +317,176 @@ fn main() {
         num * num
     });
     println!("squares: {:?}", squares);
+
+    let cubes = parallel_map_auto(vec![1, 2, 3, 4, 5], |num| num * num * num);
+    println!("cubes: {:?}", cubes);
+
+    let input: Vec<u32> = (1..=8).collect();
+    let mut halves = vec![0.0; input.len()];
+    parallel_map_into(&input, &mut halves, 3, |num| *num as f64 / 2.0);
+    println!("halves: {:?}", halves);
+
+    for double in parallel_map_unordered(vec![3, 1, 2], 3, |num| num * 2) {
+        println!("got {}", double);
+    }
+
+    for triple in parallel_map_ordered_stream(vec![3, 1, 2], 3, |num| num * 3) {
+        println!("in order: {}", triple);
+    }
+
+    let naps = parallel_map_timeout(
+        vec![10, 1000, 20],
+        2,
+        |millis| {
+            thread::sleep(time::Duration::from_millis(millis));
+            millis
+        },
+        time::Duration::from_millis(100),
+    );
+    println!("naps: {:?}", naps);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_parallel_map_keeps_order() {
+        let input: Vec<u64> = (0..50).collect();
+        let output = parallel_map(input, 4, |num| num * num);
+        let expected: Vec<u64> = (0..50).map(|num| num * num).collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_parallel_map_zero_and_one_threads() {
+        let expected: Vec<u64> = (0..20).map(|num| num * num).collect();
+        assert_eq!(
+            parallel_map((0..20).collect(), 0, |num: u64| num * num),
+            expected
+        );
+        assert_eq!(
+            parallel_map((0..20).collect(), 1, |num: u64| num * num),
+            expected
+        );
+
+        let mut unordered: Vec<u64> =
+            parallel_map_unordered((0..20).collect(), 0, |num: u64| num * num).collect();
+        unordered.sort_unstable();
+        assert_eq!(unordered, expected);
+    }
+
+    #[test]
+    fn test_parallel_map_into() {
+        let input: Vec<u64> = (0..103).collect();
+        let mut output = vec![0; input.len()];
+        for num_threads in [0, 1, 4, 200] {
+            output.iter_mut().for_each(|out| *out = 0);
+            parallel_map_into(&input, &mut output, num_threads, |num| num * 3);
+            assert_eq!(output, (0..103).map(|num| num * 3).collect::<Vec<u64>>());
+        }
+
+        // Output of a different type, reusing a buffer
+        let mut lengths = vec![0; 3];
+        parallel_map_into(&["a", "bb", "ccc"], &mut lengths, 2, |s| s.len());
+        assert_eq!(lengths, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_parallel_map_auto() {
+        let output = parallel_map_auto((0..100).collect::<Vec<i32>>(), |num| num * 2);
+        assert_eq!(output, (0..100).map(|num| num * 2).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn test_auto_thread_count_clamped_to_input() {
+        assert_eq!(auto_thread_count(1), 1);
+        assert!(auto_thread_count(2) <= 2);
+        assert!(auto_thread_count(3) >= 1);
+        // Nothing to do still gets a (harmless) worker
+        assert_eq!(auto_thread_count(0), 1);
+    }
+
+    #[test]
+    fn test_parallel_map_unordered() {
+        let mut output: Vec<u32> = parallel_map_unordered((0..30).collect(), 4, |num: u32| {
+            thread::sleep(time::Duration::from_millis(((30 - num) % 7).into()));
+            num * 3
+        })
+        .collect();
+        output.sort_unstable();
+        assert_eq!(output, (0..30).map(|num| num * 3).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_parallel_map_unordered_dropped_early() {
+        static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+        let mut results = parallel_map_unordered((0..100).collect::<Vec<u32>>(), 4, |num| {
+            thread::sleep(time::Duration::from_millis(5));
+            PROCESSED.fetch_add(1, Ordering::SeqCst);
+            num
+        });
+        assert!(results.next().is_some());
+        // Dropping the iterator must not hang or panic, and must stop the workers
+        drop(results);
+        assert!(PROCESSED.load(Ordering::SeqCst) < 100);
+    }
+
+    #[test]
+    fn test_parallel_map_ordered_stream() {
+        // Later elements finish first, so nearly everything has to wait in the reorder buffer
+        let output: Vec<u32> = parallel_map_ordered_stream((0..30).collect(), 4, |num: u32| {
+            thread::sleep(time::Duration::from_millis(((30 - num) % 7 * 3).into()));
+            num * 3
+        })
+        .collect();
+        assert_eq!(output, (0..30).map(|num| num * 3).collect::<Vec<u32>>());
+
+        let mut results = parallel_map_ordered_stream(vec![30u64, 0, 10], 3, |millis| {
+            thread::sleep(time::Duration::from_millis(millis));
+            millis
+        });
+        assert_eq!(results.next(), Some(30));
+        assert_eq!(results.next(), Some(0));
+        assert_eq!(results.next(), Some(10));
+        assert_eq!(results.next(), None);
+    }
+
+    #[test]
+    fn test_parallel_map_timeout() {
+        let start = time::Instant::now();
+        let output = parallel_map_timeout(
+            vec![10, 5000, 20, 30],
+            2,
+            |millis: u64| {
+                thread::sleep(time::Duration::from_millis(millis));
+                millis * 2
+            },
+            time::Duration::from_millis(500),
+        );
+        assert_eq!(output, vec![Ok(20), Err(Timeout), Ok(40), Ok(60)]);
+        // The slow element was abandoned rather than waited for
+        assert!(start.elapsed() < time::Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_progress_callback() {
+        let calls = RefCell::new(Vec::new());
+        let output = parallel_map_with_progress(
+            (0..20).collect::<Vec<u32>>(),
+            3,
+            |num| num + 1,
+            |completed, total| calls.borrow_mut().push((completed, total)),
+        );
+        assert_eq!(output, (1..21).collect::<Vec<u32>>());
+
+        let calls = calls.into_inner();
+        assert_eq!(calls.len(), 20);
+        for (i, (completed, total)) in calls.iter().enumerate() {
+            assert_eq!(*completed, i + 1);
+            assert_eq!(*total, 20);
+        }
+    }
 }